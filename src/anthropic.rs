@@ -0,0 +1,864 @@
+#![cfg(feature = "anthropic")]
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use agent_stream_kit::tool::ToolInfo;
+use agent_stream_kit::{
+    ASKit, Agent, AgentContext, AgentData, AgentError, AgentOutput, AgentSpec, AgentValue, AsAgent,
+    Message, ToolCall, ToolCallFunction, askit_agent, async_trait,
+};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+
+use crate::common::{
+    RetryPolicy, StreamResponses, content_with_thinking, first_token_before, first_token_deadline,
+    output_message_parts, prompt_messages, reply_message_id, response_value, retry_transient,
+    tool_call_id, tool_result_ids,
+};
+use crate::image::{IMAGE_FORMAT_PNG, ImageFormat};
+use crate::tool::{
+    check_provider_tool_names, configured_tool_infos, provider_tool_name, registry_tool_name,
+};
+
+const CATEGORY: &str = "LLM/Anthropic";
+
+const PIN_CONTENT: &str = "content";
+const PIN_MESSAGE: &str = "message";
+const PIN_PROMPT: &str = "prompt";
+const PIN_RESPONSE: &str = "response";
+const PIN_TOOL_CALLS: &str = "tool_calls";
+
+const CONFIG_ANTHROPIC_API_BASE: &str = "anthropic_api_base";
+const CONFIG_ANTHROPIC_API_KEY: &str = "anthropic_api_key";
+const CONFIG_EMIT_PROMPT: &str = "emit_prompt";
+const CONFIG_ERROR_ON_EMPTY: &str = "error_on_empty";
+const CONFIG_FIRST_TOKEN_TIMEOUT_SECS: &str = "first_token_timeout_secs";
+const CONFIG_IMAGE_FORMAT: &str = "image_format";
+const CONFIG_MAX_IMAGES: &str = "max_images";
+const CONFIG_MAX_PROMPT_TOKENS: &str = "max_prompt_tokens";
+const CONFIG_MAX_RETRIES: &str = "max_retries";
+const CONFIG_MAX_THINKING_TOKENS: &str = "max_thinking_tokens";
+const CONFIG_MAX_TOKENS: &str = "max_tokens";
+const CONFIG_MERGE_SYSTEM: &str = "merge_system";
+const CONFIG_MODEL: &str = "model";
+const CONFIG_OPTIONS: &str = "options";
+const CONFIG_PRETTY_RESPONSE: &str = "pretty_response";
+const CONFIG_REDACT_RESPONSE: &str = "redact_response";
+const CONFIG_REPAIR_MESSAGES: &str = "repair_messages";
+const CONFIG_RESEND_THINKING: &str = "resend_thinking";
+const CONFIG_RETRY_BASE_MS: &str = "retry_base_ms";
+const CONFIG_STABLE_TURN_ID: &str = "stable_turn_id";
+const CONFIG_STREAM: &str = "stream";
+const CONFIG_STREAM_RESPONSE: &str = "stream_response";
+const CONFIG_TOKENIZER: &str = "tokenizer";
+const CONFIG_TOOLS: &str = "tools";
+const CONFIG_TRIM_PROMPT: &str = "trim_prompt";
+const CONFIG_USER_AGENT: &str = "user_agent";
+const CONFIG_VALIDATE_MESSAGES: &str = "validate_messages";
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const DEFAULT_API_BASE: &str = "https://api.anthropic.com/v1";
+pub(crate) const DEFAULT_CONFIG_MODEL: &str = "claude-sonnet-4-5";
+const DEFAULT_MAX_TOKENS: i64 = 4096;
+
+// The API rejects thinking budgets below this
+const MIN_THINKING_TOKENS: i64 = 1024;
+
+/// Error from a request to the Messages API.
+#[derive(Debug)]
+enum AnthropicError {
+    Http(reqwest::Error),
+    Api { status: StatusCode, message: String },
+}
+
+impl fmt::Display for AnthropicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnthropicError::Http(e) => write!(f, "{}", e),
+            AnthropicError::Api { status, message } => write!(f, "{}: {}", status, message),
+        }
+    }
+}
+
+/// Returns true for errors worth retrying: timeouts, connection failures,
+/// rate limits and server errors, including 529 overloaded.
+fn is_transient_error(e: &AnthropicError) -> bool {
+    match e {
+        AnthropicError::Http(e) => e.is_timeout() || e.is_connect(),
+        AnthropicError::Api { status, .. } => {
+            *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+        }
+    }
+}
+
+// Shared client management for Anthropic agents
+struct AnthropicManager {
+    client: reqwest::Client,
+}
+
+impl AnthropicManager {
+    fn new() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// The API key from the `anthropic_api_key` global config, or the ANTHROPIC_API_KEY variable.
+    fn api_key(&self, askit: &ASKit) -> Result<String, AgentError> {
+        askit
+            .get_global_configs(AnthropicChatAgent::DEF_NAME)
+            .and_then(|cfg| cfg.get_string(CONFIG_ANTHROPIC_API_KEY).ok())
+            .filter(|key| !key.is_empty())
+            .or_else(|| std::env::var("ANTHROPIC_API_KEY").ok())
+            .ok_or_else(|| AgentError::InvalidConfig("Anthropic API key is not set".to_string()))
+    }
+
+    fn user_agent(&self, askit: &ASKit) -> Option<String> {
+        askit
+            .get_global_configs(AnthropicChatAgent::DEF_NAME)
+            .and_then(|cfg| cfg.get_string(CONFIG_USER_AGENT).ok())
+            .map(|ua| ua.trim().to_string())
+            .filter(|ua| !ua.is_empty())
+    }
+
+    fn api_base(&self, askit: &ASKit) -> String {
+        askit
+            .get_global_configs(AnthropicChatAgent::DEF_NAME)
+            .and_then(|cfg| cfg.get_string(CONFIG_ANTHROPIC_API_BASE).ok())
+            .filter(|base| !base.is_empty())
+            .unwrap_or_else(|| DEFAULT_API_BASE.to_string())
+    }
+
+    /// Post the request to the messages endpoint, turning error statuses into errors.
+    async fn send(
+        &self,
+        api_base: &str,
+        api_key: &str,
+        user_agent: Option<&str>,
+        request: &Value,
+    ) -> Result<reqwest::Response, AnthropicError> {
+        let mut req = self
+            .client
+            .post(format!("{}/messages", api_base.trim_end_matches('/')))
+            .header("x-api-key", api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(user_agent) = user_agent {
+            req = req.header(reqwest::header::USER_AGENT, user_agent);
+        }
+        let res = req
+            .body(request.to_string())
+            .send()
+            .await
+            .map_err(AnthropicError::Http)?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(res);
+        }
+        let body = res.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|v| v["error"]["message"].as_str().map(str::to_string))
+            .unwrap_or(body);
+        Err(AnthropicError::Api { status, message })
+    }
+}
+
+// Anthropic Chat Agent
+#[askit_agent(
+    title="Chat",
+    category=CATEGORY,
+    inputs=[PIN_MESSAGE],
+    outputs=[PIN_MESSAGE, PIN_RESPONSE, PIN_CONTENT, PIN_TOOL_CALLS, PIN_PROMPT],
+    boolean_config(name=CONFIG_STREAM, title="Stream"),
+    boolean_config(name=CONFIG_STREAM_RESPONSE, default=true, title="Stream Response"),
+    integer_config(name=CONFIG_FIRST_TOKEN_TIMEOUT_SECS, title="First Token Timeout (secs)"),
+    string_config(name=CONFIG_MODEL, default=DEFAULT_CONFIG_MODEL),
+    integer_config(name=CONFIG_MAX_TOKENS, default=DEFAULT_MAX_TOKENS, title="Max Tokens"),
+    text_config(name=CONFIG_TOOLS),
+    object_config(name=CONFIG_OPTIONS),
+    string_config(name=CONFIG_IMAGE_FORMAT, default=IMAGE_FORMAT_PNG, title="Image Format"),
+    integer_config(name=CONFIG_MAX_IMAGES, title="Max Images"),
+    integer_config(name=CONFIG_MAX_THINKING_TOKENS, title="Max Thinking Tokens"),
+    boolean_config(name=CONFIG_RESEND_THINKING, title="Resend Thinking"),
+    boolean_config(name=CONFIG_PRETTY_RESPONSE, title="Pretty Response"),
+    boolean_config(name=CONFIG_REDACT_RESPONSE, title="Redact Response"),
+    boolean_config(name=CONFIG_ERROR_ON_EMPTY, title="Error on Empty"),
+    boolean_config(name=CONFIG_MERGE_SYSTEM, title="Merge System Messages"),
+    integer_config(name=CONFIG_MAX_PROMPT_TOKENS, title="Max Prompt Tokens"),
+    boolean_config(name=CONFIG_TRIM_PROMPT, title="Trim Prompt"),
+    string_config(name=CONFIG_TOKENIZER, title="Tokenizer"),
+    boolean_config(name=CONFIG_VALIDATE_MESSAGES, title="Validate Messages"),
+    boolean_config(name=CONFIG_REPAIR_MESSAGES, title="Repair Messages"),
+    boolean_config(name=CONFIG_EMIT_PROMPT, title="Emit Prompt"),
+    boolean_config(name=CONFIG_STABLE_TURN_ID, title="Stable Turn Id"),
+    integer_config(name=CONFIG_MAX_RETRIES, default=3, title="Max Retries"),
+    integer_config(name=CONFIG_RETRY_BASE_MS, default=500, title="Retry Base Delay (ms)"),
+    string_global_config(name=CONFIG_ANTHROPIC_API_KEY, title="Anthropic API Key"),
+    string_global_config(name=CONFIG_ANTHROPIC_API_BASE, title="Anthropic API Base URL", default=DEFAULT_API_BASE),
+    string_global_config(name=CONFIG_USER_AGENT, title="User-Agent"),
+)]
+pub struct AnthropicChatAgent {
+    data: AgentData,
+    manager: AnthropicManager,
+}
+
+#[async_trait]
+impl AsAgent for AnthropicChatAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        Ok(Self {
+            data: AgentData::new(askit, id, spec),
+            manager: AnthropicManager::new(),
+        })
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let mut config_model = self.configs()?.get_string_or_default(CONFIG_MODEL);
+        if config_model.is_empty() {
+            config_model = DEFAULT_CONFIG_MODEL.to_string();
+        }
+
+        let Some(messages) = prompt_messages(self.configs()?, value)? else {
+            return Ok(());
+        };
+        if self.configs()?.get_bool_or_default(CONFIG_EMIT_PROMPT) {
+            self.output(ctx.clone(), PIN_PROMPT, AgentValue::array(messages.clone()))
+                .await?;
+        }
+        let stable_turn_id = self.configs()?.get_bool_or_default(CONFIG_STABLE_TURN_ID);
+        let id = reply_message_id(&messages, stable_turn_id);
+        let messages = messages
+            .iter()
+            .filter_map(|m| m.as_message())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let config_tools = self.configs()?.get_string_or_default(CONFIG_TOOLS);
+        let tool_infos = configured_tool_infos(&config_tools)?;
+        check_provider_tool_names(&tool_infos)?;
+
+        let image_format: ImageFormat = self
+            .configs()?
+            .get_string_or_default(CONFIG_IMAGE_FORMAT)
+            .parse()?;
+        let use_stream = self.configs()?.get_bool_or_default(CONFIG_STREAM);
+
+        let mut request = chat_request(
+            &config_model,
+            &messages,
+            &tool_infos,
+            self.configs()?.get_integer_or_default(CONFIG_MAX_TOKENS),
+            self.configs()?
+                .get_integer_or_default(CONFIG_MAX_THINKING_TOKENS),
+            image_format,
+        );
+        if use_stream {
+            request["stream"] = json!(true);
+        }
+
+        let config_options = self.configs()?.get_object_or_default(CONFIG_OPTIONS);
+        if !config_options.is_empty() {
+            // Merge options into request
+            let options_json = serde_json::to_value(&config_options)
+                .map_err(|e| AgentError::InvalidValue(format!("Invalid JSON in options: {}", e)))?;
+            if let (Some(request_obj), Some(options_obj)) =
+                (request.as_object_mut(), options_json.as_object())
+            {
+                for (key, value) in options_obj {
+                    request_obj.insert(key.clone(), value.clone());
+                }
+            }
+        }
+
+        let message = if use_stream {
+            self.send_chat_stream(&ctx, &request, &id).await?
+        } else {
+            self.send_chat(&ctx, &request, &id).await?
+        };
+        output_message_parts(self, &ctx, &message).await?;
+
+        Ok(())
+    }
+}
+
+impl AnthropicChatAgent {
+    fn retry_policy(&self) -> Result<RetryPolicy, AgentError> {
+        Ok(RetryPolicy::new(
+            self.configs()?.get_integer_or_default(CONFIG_MAX_RETRIES),
+            self.configs()?.get_integer_or_default(CONFIG_RETRY_BASE_MS),
+        ))
+    }
+
+    async fn start_request(&self, request: &Value) -> Result<reqwest::Response, AgentError> {
+        let api_key = self.manager.api_key(self.askit())?;
+        let api_base = self.manager.api_base(self.askit());
+        let user_agent = self.manager.user_agent(self.askit());
+        retry_transient(
+            self.retry_policy()?,
+            "Anthropic Error",
+            || {
+                self.manager
+                    .send(&api_base, &api_key, user_agent.as_deref(), request)
+            },
+            is_transient_error,
+        )
+        .await
+    }
+
+    async fn send_chat(
+        &self,
+        ctx: &AgentContext,
+        request: &Value,
+        id: &str,
+    ) -> Result<Message, AgentError> {
+        let res = self
+            .start_request(request)
+            .await?
+            .text()
+            .await
+            .map_err(|e| AgentError::IoError(format!("Anthropic Error: {}", e)))?;
+        let res: Value = serde_json::from_str(&res)
+            .map_err(|e| AgentError::InvalidValue(format!("Invalid response JSON: {}", e)))?;
+
+        let mut message = message_from_anthropic(&res)?;
+        message.id = Some(id.to_string());
+        self.output(ctx.clone(), PIN_MESSAGE, message.clone().into())
+            .await?;
+
+        let pretty_response = self.configs()?.get_bool_or_default(CONFIG_PRETTY_RESPONSE);
+        let redact_response = self.configs()?.get_bool_or_default(CONFIG_REDACT_RESPONSE);
+        let out_response = response_value(&res, pretty_response, redact_response)?;
+        self.output(ctx.clone(), PIN_RESPONSE, out_response).await?;
+
+        Ok(message)
+    }
+
+    async fn send_chat_stream(
+        &self,
+        ctx: &AgentContext,
+        request: &Value,
+        id: &str,
+    ) -> Result<Message, AgentError> {
+        let pretty_response = self.configs()?.get_bool_or_default(CONFIG_PRETTY_RESPONSE);
+        let redact_response = self.configs()?.get_bool_or_default(CONFIG_REDACT_RESPONSE);
+        let mut responses =
+            StreamResponses::new(self.configs()?.get_bool_or_default(CONFIG_STREAM_RESPONSE));
+        let mut deadline = first_token_deadline(
+            self.configs()?
+                .get_integer_or_default(CONFIG_FIRST_TOKEN_TIMEOUT_SECS),
+        );
+
+        let mut response = first_token_before(self.start_request(request), deadline).await??;
+
+        let mut message = Message::assistant("".to_string());
+        message.id = Some(id.to_string());
+        let mut events = SseEvents::default();
+        let mut state = StreamState::default();
+        loop {
+            let chunk = first_token_before(response.chunk(), deadline)
+                .await?
+                .map_err(|e| AgentError::IoError(format!("Anthropic Stream Error: {}", e)))?;
+            let Some(chunk) = chunk else {
+                break;
+            };
+            deadline = None;
+
+            for data in events.push(&chunk) {
+                let event: Value = serde_json::from_str(&data).map_err(|e| {
+                    AgentError::InvalidValue(format!("Invalid stream event JSON: {}", e))
+                })?;
+                if state.push(&event)? {
+                    message.content = state.content.clone();
+                    if !state.thinking.is_empty() {
+                        message.thinking = Some(state.thinking.clone());
+                    }
+                    // Tool calls are attached only once their input is complete
+                    if let Some(calls) = state.tool_calls()? {
+                        message.tool_calls = Some(calls.into());
+                    }
+                    self.output(ctx.clone(), PIN_MESSAGE, message.clone().into())
+                        .await?;
+                }
+                if let Some(event) = responses.push(event) {
+                    let out_response = response_value(&event, pretty_response, redact_response)?;
+                    self.output(ctx.clone(), PIN_RESPONSE, out_response).await?;
+                }
+            }
+        }
+
+        if let Some(event) = responses.finish() {
+            let out_response = response_value(&event, pretty_response, redact_response)?;
+            self.output(ctx.clone(), PIN_RESPONSE, out_response).await?;
+        }
+
+        Ok(message)
+    }
+}
+
+/// Send the messages in a single non-streaming request and return the reply text.
+pub(crate) async fn chat_once(
+    askit: &ASKit,
+    model: &str,
+    messages: &[Message],
+) -> Result<String, AgentError> {
+    let manager = AnthropicManager::new();
+    let api_key = manager.api_key(askit)?;
+    let api_base = manager.api_base(askit);
+    let user_agent = manager.user_agent(askit);
+    let request = chat_request(model, messages, &[], 0, 0, ImageFormat::default());
+    let res = manager
+        .send(&api_base, &api_key, user_agent.as_deref(), &request)
+        .await
+        .map_err(|e| AgentError::IoError(format!("Anthropic Error: {}", e)))?
+        .text()
+        .await
+        .map_err(|e| AgentError::IoError(format!("Anthropic Error: {}", e)))?;
+    let res: Value = serde_json::from_str(&res)
+        .map_err(|e| AgentError::InvalidValue(format!("Invalid response JSON: {}", e)))?;
+    Ok(message_from_anthropic(&res)?.content)
+}
+
+/// Build the Messages API request body.
+///
+/// System messages go into the top-level system field. A positive
+/// max_thinking_tokens enables extended thinking with that budget, raising
+/// max_tokens above the budget when needed.
+fn chat_request(
+    model: &str,
+    messages: &[Message],
+    tools: &[ToolInfo],
+    max_tokens: i64,
+    max_thinking_tokens: i64,
+    image_format: ImageFormat,
+) -> Value {
+    let (system, messages) = messages_to_anthropic(messages, image_format);
+    let mut max_tokens = if max_tokens > 0 {
+        max_tokens
+    } else {
+        DEFAULT_MAX_TOKENS
+    };
+    let mut request = json!({
+        "model": model,
+        "messages": messages,
+    });
+    if let Some(system) = system {
+        request["system"] = json!(system);
+    }
+    if !tools.is_empty() {
+        request["tools"] = tools.iter().map(tool_info_to_anthropic).collect();
+    }
+    if max_thinking_tokens > 0 {
+        let budget = max_thinking_tokens.max(MIN_THINKING_TOKENS);
+        if max_tokens <= budget {
+            max_tokens += budget;
+        }
+        request["thinking"] = json!({ "type": "enabled", "budget_tokens": budget });
+    }
+    request["max_tokens"] = json!(max_tokens);
+    request
+}
+
+fn tool_info_to_anthropic(info: &ToolInfo) -> Value {
+    json!({
+        "name": provider_tool_name(&info.name),
+        "description": info.description,
+        "input_schema": info
+            .parameters
+            .clone()
+            .unwrap_or_else(|| json!({ "type": "object", "properties": {} })),
+    })
+}
+
+/// Convert messages into the system prompt and the turns of the Messages API.
+///
+/// Tool results are sent as tool_result blocks in user turns, matched to the
+/// tool_use blocks of the previous assistant turn with `tool_result_ids`, and
+/// consecutive turns of the same role are merged.
+fn messages_to_anthropic(
+    messages: &[Message],
+    image_format: ImageFormat,
+) -> (Option<String>, Vec<Value>) {
+    let mut system = Vec::new();
+    let mut turns: Vec<Value> = Vec::new();
+
+    for (msg, result_id) in messages.iter().zip(tool_result_ids(messages)) {
+        let (role, blocks) = match msg.role.as_str() {
+            "system" => {
+                system.push(msg.content.clone());
+                continue;
+            }
+            "assistant" => {
+                let mut blocks = Vec::new();
+                // Past thinking has no signature, so it is resent as text
+                let content = content_with_thinking(msg);
+                if !content.is_empty() {
+                    blocks.push(json!({ "type": "text", "text": content }));
+                }
+                for (i, call) in msg.tool_calls.iter().flatten().enumerate() {
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": tool_call_id(call, i),
+                        "name": provider_tool_name(&call.function.name),
+                        "input": call.function.parameters,
+                    }));
+                }
+                ("assistant", blocks)
+            }
+            "tool" | "function" => match result_id {
+                Some(id) => {
+                    let block = json!({
+                        "type": "tool_result",
+                        "tool_use_id": id,
+                        "content": msg.content,
+                    });
+                    ("user", vec![block])
+                }
+                // Without a matching tool_use the result is passed as plain text
+                None => ("user", vec![json!({ "type": "text", "text": msg.content })]),
+            },
+            _ => ("user", user_blocks(msg, image_format)),
+        };
+        if !blocks.is_empty() {
+            push_turn(&mut turns, role, blocks);
+        }
+    }
+
+    let system = (!system.is_empty()).then(|| system.join("\n\n"));
+    (system, turns)
+}
+
+fn push_turn(turns: &mut Vec<Value>, role: &str, blocks: Vec<Value>) {
+    if let Some(last) = turns.last_mut()
+        && last["role"] == role
+        && let Some(content) = last["content"].as_array_mut()
+    {
+        content.extend(blocks);
+        return;
+    }
+    turns.push(json!({ "role": role, "content": blocks }));
+}
+
+#[cfg_attr(not(feature = "image"), allow(unused_variables))]
+fn user_blocks(msg: &Message, image_format: ImageFormat) -> Vec<Value> {
+    let mut blocks = Vec::new();
+    #[cfg(feature = "image")]
+    if let Some(image) = &msg.image {
+        blocks.push(json!({
+            "type": "image",
+            "source": {
+                "type": "base64",
+                "media_type": image_format.mime_type(),
+                "data": crate::image::encode_base64(image, image_format),
+            },
+        }));
+    }
+    if !msg.content.is_empty() || blocks.is_empty() {
+        blocks.push(json!({ "type": "text", "text": msg.content }));
+    }
+    blocks
+}
+
+/// Convert a Messages API response into an assistant message.
+fn message_from_anthropic(res: &Value) -> Result<Message, AgentError> {
+    let blocks = res["content"].as_array().ok_or_else(|| {
+        AgentError::InvalidValue("Anthropic response has no content".to_string())
+    })?;
+    let mut content = String::new();
+    let mut thinking = String::new();
+    let mut tool_calls = Vec::new();
+    for block in blocks {
+        match block["type"].as_str() {
+            Some("text") => content.push_str(block["text"].as_str().unwrap_or_default()),
+            Some("thinking") => thinking.push_str(block["thinking"].as_str().unwrap_or_default()),
+            Some("tool_use") => tool_calls.push(tool_call(
+                block["id"].as_str(),
+                block["name"].as_str().unwrap_or_default(),
+                block["input"].clone(),
+            )),
+            _ => {}
+        }
+    }
+
+    let mut message = Message::assistant(content);
+    if !thinking.is_empty() {
+        message.thinking = Some(thinking);
+    }
+    if !tool_calls.is_empty() {
+        message.tool_calls = Some(tool_calls.into());
+    }
+    Ok(message)
+}
+
+fn tool_call(id: Option<&str>, name: &str, parameters: Value) -> ToolCall {
+    ToolCall {
+        function: ToolCallFunction {
+            id: id.map(str::to_string),
+            name: registry_tool_name(name),
+            parameters,
+        },
+    }
+}
+
+/// Splits a server-sent event stream into the payloads of its data lines.
+#[derive(Default)]
+struct SseEvents {
+    buf: Vec<u8>,
+}
+
+impl SseEvents {
+    /// Add a chunk of the stream, returning the data of the lines completed by it.
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(chunk);
+        let mut data = Vec::new();
+        while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+            let line = self.buf.drain(..=end).collect::<Vec<_>>();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(payload) = line.trim_end().strip_prefix("data:") {
+                data.push(payload.trim_start().to_string());
+            }
+        }
+        data
+    }
+}
+
+/// A tool_use block being streamed, whose input arrives as partial JSON.
+#[derive(Default)]
+struct ToolUseBlock {
+    id: String,
+    name: String,
+    input: String,
+    done: bool,
+}
+
+/// Accumulates the content blocks of a streamed message.
+#[derive(Default)]
+struct StreamState {
+    content: String,
+    thinking: String,
+    tool_uses: BTreeMap<u64, ToolUseBlock>,
+}
+
+impl StreamState {
+    /// Apply a stream event, returning true if the message changed.
+    fn push(&mut self, event: &Value) -> Result<bool, AgentError> {
+        let index = event["index"].as_u64().unwrap_or_default();
+        match event["type"].as_str() {
+            Some("content_block_start") => {
+                let block = &event["content_block"];
+                if block["type"] == "tool_use" {
+                    self.tool_uses.insert(
+                        index,
+                        ToolUseBlock {
+                            id: block["id"].as_str().unwrap_or_default().to_string(),
+                            name: block["name"].as_str().unwrap_or_default().to_string(),
+                            ..Default::default()
+                        },
+                    );
+                }
+                Ok(false)
+            }
+            Some("content_block_delta") => {
+                let delta = &event["delta"];
+                match delta["type"].as_str() {
+                    Some("text_delta") => {
+                        self.content
+                            .push_str(delta["text"].as_str().unwrap_or_default());
+                    }
+                    Some("thinking_delta") => {
+                        self.thinking
+                            .push_str(delta["thinking"].as_str().unwrap_or_default());
+                    }
+                    Some("input_json_delta") => {
+                        if let Some(tool_use) = self.tool_uses.get_mut(&index) {
+                            tool_use
+                                .input
+                                .push_str(delta["partial_json"].as_str().unwrap_or_default());
+                        }
+                        return Ok(false);
+                    }
+                    _ => return Ok(false),
+                }
+                Ok(true)
+            }
+            Some("content_block_stop") => match self.tool_uses.get_mut(&index) {
+                Some(tool_use) => {
+                    tool_use.done = true;
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            Some("error") => Err(AgentError::IoError(format!(
+                "Anthropic Stream Error: {}",
+                event["error"]["message"].as_str().unwrap_or_default()
+            ))),
+            _ => Ok(false),
+        }
+    }
+
+    /// The tool calls, once every tool_use block is complete.
+    fn tool_calls(&self) -> Result<Option<Vec<ToolCall>>, AgentError> {
+        if self.tool_uses.is_empty() || self.tool_uses.values().any(|t| !t.done) {
+            return Ok(None);
+        }
+        self.tool_uses
+            .values()
+            .map(|t| {
+                // A tool without parameters streams no input at all
+                let input = if t.input.trim().is_empty() {
+                    json!({})
+                } else {
+                    serde_json::from_str(&t.input).map_err(|e| {
+                        AgentError::InvalidValue(format!(
+                            "Failed to parse tool call arguments JSON: {}",
+                            e
+                        ))
+                    })?
+                };
+                Ok(tool_call(Some(&t.id), &t.name, input))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_to_anthropic() {
+        let mut assistant = Message::assistant("Let me check.".to_string());
+        assistant.tool_calls = Some(
+            vec![
+                tool_call(Some("toolu_1"), "weather", json!({ "city": "Tokyo" })),
+                tool_call(Some("toolu_2"), "time", json!({})),
+            ]
+            .into(),
+        );
+        let messages = vec![
+            Message::system("Be brief.".to_string()),
+            Message::user("Weather and time in Tokyo?".to_string()),
+            assistant,
+            Message::tool("time".to_string(), "10:00".to_string()),
+            Message::tool("weather".to_string(), "Sunny".to_string()),
+        ];
+
+        let (system, turns) = messages_to_anthropic(&messages, ImageFormat::Png);
+        assert_eq!(system.as_deref(), Some("Be brief."));
+        assert_eq!(
+            turns,
+            vec![
+                json!({ "role": "user", "content": [
+                    { "type": "text", "text": "Weather and time in Tokyo?" },
+                ]}),
+                json!({ "role": "assistant", "content": [
+                    { "type": "text", "text": "Let me check." },
+                    { "type": "tool_use", "id": "toolu_1", "name": "weather", "input": { "city": "Tokyo" } },
+                    { "type": "tool_use", "id": "toolu_2", "name": "time", "input": {} },
+                ]}),
+                // both results in one user turn, matched by name
+                json!({ "role": "user", "content": [
+                    { "type": "tool_result", "tool_use_id": "toolu_2", "content": "10:00" },
+                    { "type": "tool_result", "tool_use_id": "toolu_1", "content": "Sunny" },
+                ]}),
+            ]
+        );
+
+        let tools = vec![ToolInfo {
+            name: "time".to_string(),
+            description: "Current time".to_string(),
+            parameters: None,
+        }];
+        let request = chat_request("claude", &messages[..2], &tools, 100, 2000, ImageFormat::Png);
+        assert_eq!(request["max_tokens"], 2100);
+        assert_eq!(request["thinking"]["budget_tokens"], 2000);
+        assert_eq!(request["tools"][0]["input_schema"]["type"], "object");
+    }
+
+    #[test]
+    fn test_message_from_anthropic() {
+        let res = json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "content": [
+                { "type": "thinking", "thinking": "The user wants weather.", "signature": "sig" },
+                { "type": "text", "text": "Checking." },
+                { "type": "tool_use", "id": "toolu_1", "name": "weather", "input": { "city": "Tokyo" } },
+            ],
+            "stop_reason": "tool_use",
+        });
+        let message = message_from_anthropic(&res).unwrap();
+        assert_eq!(message.role, "assistant");
+        assert_eq!(message.content, "Checking.");
+        assert_eq!(message.thinking.as_deref(), Some("The user wants weather."));
+        let calls = message.tool_calls.unwrap();
+        assert_eq!(calls[0].function.id.as_deref(), Some("toolu_1"));
+        assert_eq!(calls[0].function.parameters, json!({ "city": "Tokyo" }));
+    }
+
+    #[test]
+    fn test_stream_state() {
+        let stream = concat!(
+            "event: message_start\n",
+            "data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\"}}\n\n",
+            "event: content_block_start\n",
+            "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hel\"}}\n\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"lo\"}}\n\n",
+            "data: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+            "data: {\"type\":\"content_block_start\",\"index\":1,\"content_block\":{\"type\":\"tool_use\",\"id\":\"toolu_1\",\"name\":\"weather\",\"input\":{}}}\n\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{\\\"city\\\": \"}}\n\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"\\\"Tokyo\\\"}\"}}\n\n",
+            "data: {\"type\":\"content_block_stop\",\"index\":1}\n\n",
+            "event: message_stop\n",
+            "data: {\"type\":\"message_stop\"}\n\n",
+        );
+
+        let mut events = SseEvents::default();
+        let mut state = StreamState::default();
+        // feed the stream in small pieces that split lines
+        for chunk in stream.as_bytes().chunks(7) {
+            for data in events.push(chunk) {
+                let event: Value = serde_json::from_str(&data).unwrap();
+                state.push(&event).unwrap();
+                if event["type"] == "content_block_delta" && event["index"] == 1 {
+                    assert!(state.tool_calls().unwrap().is_none());
+                }
+            }
+        }
+        assert_eq!(state.content, "Hello");
+        let calls = state.tool_calls().unwrap().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "weather");
+        assert_eq!(calls[0].function.parameters, json!({ "city": "Tokyo" }));
+
+        let error = json!({ "type": "error", "error": { "type": "overloaded_error", "message": "Overloaded" } });
+        assert!(state.push(&error).is_err());
+    }
+
+    #[test]
+    fn test_mcp_tool_names() {
+        crate::tool::tests::register_test_tool("test_anthropic_server::search");
+
+        // the tool list sent to Anthropic uses the provider-safe name
+        let tools = configured_tool_infos("^test_anthropic_server::").unwrap();
+        assert_eq!(tools.len(), 1);
+        let tool = tool_info_to_anthropic(&tools[0]);
+        assert_eq!(tool["name"], "test_anthropic_server__search");
+        let valid = regex::Regex::new("^[a-zA-Z0-9_-]{1,64}$").unwrap();
+        assert!(valid.is_match(tool["name"].as_str().unwrap()));
+
+        // a returned tool_use is mapped back to the registry name
+        let call = tool_call(Some("toolu_1"), "test_anthropic_server__search", json!({}));
+        assert_eq!(call.function.name, "test_anthropic_server::search");
+
+        agent_stream_kit::tool::unregister_tool("test_anthropic_server::search");
+    }
+}
@@ -0,0 +1,1986 @@
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use agent_stream_kit::{
+    ASKit, Agent, AgentConfigs, AgentContext, AgentData, AgentError, AgentOutput, AgentSpec,
+    AgentValue, AsAgent, Message, ToolCall, askit_agent, async_trait,
+};
+use im::Vector;
+use serde::Serialize;
+use tokio::time::Instant;
+use tokio_stream::{Stream, StreamExt};
+use tokenizers::Tokenizer;
+
+use crate::image::IMAGE_FORMAT_PNG;
+
+const CATEGORY: &str = "LLM/Common";
+
+const PIN_BATCH: &str = "batch";
+const PIN_CONTENT: &str = "content";
+const PIN_END: &str = "end";
+const PIN_MESSAGE: &str = "message";
+const PIN_MESSAGES: &str = "messages";
+const PIN_PROMPT: &str = "prompt";
+const PIN_REFUSAL: &str = "refusal";
+const PIN_RESPONSE: &str = "response";
+const PIN_TITLE: &str = "title";
+const PIN_TOOL_CALLS: &str = "tool_calls";
+const PIN_VALUE: &str = "value";
+
+const CONFIG_BATCH_SIZE: &str = "batch_size";
+const CONFIG_EMIT_PROMPT: &str = "emit_prompt";
+const CONFIG_ERROR_ON_EMPTY: &str = "error_on_empty";
+const CONFIG_EXTRA_HEADERS: &str = "extra_headers";
+const CONFIG_FIRST_TOKEN_TIMEOUT_SECS: &str = "first_token_timeout_secs";
+const CONFIG_FLUSH_MS: &str = "flush_ms";
+const CONFIG_IMAGE_FORMAT: &str = "image_format";
+const CONFIG_MAX_EMPTY_RETRIES: &str = "max_empty_retries";
+const CONFIG_MAX_MESSAGES: &str = "max_messages";
+const CONFIG_MAX_IMAGES: &str = "max_images";
+const CONFIG_MAX_PROMPT_TOKENS: &str = "max_prompt_tokens";
+const CONFIG_MAX_RETRIES: &str = "max_retries";
+const CONFIG_MAX_THINKING_TOKENS: &str = "max_thinking_tokens";
+const CONFIG_MAX_TOKENS: &str = "max_tokens";
+const CONFIG_MERGE_SYSTEM: &str = "merge_system";
+const CONFIG_MODEL: &str = "model";
+const CONFIG_OPTIONS: &str = "options";
+const CONFIG_PREFIX_TOOL_NAME: &str = "prefix_tool_name";
+const CONFIG_PRETTY_RESPONSE: &str = "pretty_response";
+const CONFIG_PROMPT: &str = "prompt";
+const CONFIG_PROVIDER: &str = "provider";
+const CONFIG_REDACT_RESPONSE: &str = "redact_response";
+const CONFIG_REPAIR_MESSAGES: &str = "repair_messages";
+const CONFIG_RESEND_THINKING: &str = "resend_thinking";
+const CONFIG_RETRY_BASE_MS: &str = "retry_base_ms";
+const CONFIG_RETRY_ON_EMPTY: &str = "retry_on_empty";
+const CONFIG_STABLE_TURN_ID: &str = "stable_turn_id";
+const CONFIG_STREAM: &str = "stream";
+const CONFIG_STREAM_RESPONSE: &str = "stream_response";
+const CONFIG_TOKENIZER: &str = "tokenizer";
+const CONFIG_TOOLS: &str = "tools";
+const CONFIG_TRIM_PROMPT: &str = "trim_prompt";
+const CONFIG_VALIDATE_MESSAGES: &str = "validate_messages";
+
+const PROVIDER_ANTHROPIC: &str = "anthropic";
+const PROVIDER_OLLAMA: &str = "ollama";
+const PROVIDER_OPENAI: &str = "openai";
+
+pub const TRUNCATE_STRATEGY_HEAD: &str = "head";
+pub const TRUNCATE_STRATEGY_MIDDLE: &str = "middle";
+pub const TRUNCATE_STRATEGY_TAIL: &str = "tail";
+
+pub const DEFAULT_TRUNCATION_MARKER: &str = "\n[truncated]";
+
+const DEFAULT_TITLE_PROMPT: &str = "Write a short title (at most six words) for the following conversation. Reply with the title only.";
+
+// Tokens added per message for the role and separators
+const MESSAGE_TOKEN_OVERHEAD: usize = 4;
+
+/// Returns true if the message has neither content nor tool calls.
+pub fn is_empty_message(message: &Message) -> bool {
+    message.content.trim().is_empty()
+        && message
+            .tool_calls
+            .as_ref()
+            .map(|calls| calls.is_empty())
+            .unwrap_or(true)
+}
+
+/// Split an assistant message into its text and its tool calls, each None when empty.
+pub fn message_parts(
+    message: &Message,
+) -> Result<(Option<AgentValue>, Option<AgentValue>), AgentError> {
+    let content = (!message.content.is_empty()).then(|| AgentValue::string(&message.content));
+    let tool_calls = match &message.tool_calls {
+        Some(calls) if !calls.is_empty() => Some(AgentValue::from_serialize(calls)?),
+        _ => None,
+    };
+    Ok((content, tool_calls))
+}
+
+/// Emit the text of the message on the content pin and its tool calls on the tool_calls pin.
+pub async fn output_message_parts<A: AgentOutput>(
+    agent: &A,
+    ctx: &AgentContext,
+    message: &Message,
+) -> Result<(), AgentError> {
+    let (content, tool_calls) = message_parts(message)?;
+    if let Some(content) = content {
+        agent.output(ctx.clone(), PIN_CONTENT, content).await?;
+    }
+    if let Some(tool_calls) = tool_calls {
+        agent.output(ctx.clone(), PIN_TOOL_CALLS, tool_calls).await?;
+    }
+    Ok(())
+}
+
+/// Handle empty or unsupported input: a silent no-op unless `error_on_empty` is set.
+pub fn empty_input(error_on_empty: bool, reason: &str) -> Result<(), AgentError> {
+    if error_on_empty {
+        return Err(AgentError::InvalidValue(reason.to_string()));
+    }
+    Ok(())
+}
+
+/// Which part of a long text is cut off by `truncate`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TruncateStrategy {
+    /// Cut the beginning, keeping the end.
+    Head,
+    /// Cut the middle, keeping both ends.
+    Middle,
+    /// Cut the end, keeping the beginning.
+    #[default]
+    Tail,
+}
+
+impl FromStr for TruncateStrategy {
+    type Err = AgentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            TRUNCATE_STRATEGY_HEAD => Ok(TruncateStrategy::Head),
+            TRUNCATE_STRATEGY_MIDDLE => Ok(TruncateStrategy::Middle),
+            "" | TRUNCATE_STRATEGY_TAIL => Ok(TruncateStrategy::Tail),
+            other => Err(AgentError::InvalidConfig(format!(
+                "Unknown truncate strategy: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Truncate the text to at most `max_chars` characters, putting `marker` where it was cut.
+///
+/// The marker is not counted in `max_chars`. Text that already fits is returned as is.
+pub fn truncate(text: &str, max_chars: usize, strategy: TruncateStrategy, marker: &str) -> String {
+    let len = text.chars().count();
+    if len <= max_chars {
+        return text.to_string();
+    }
+    // byte offset of the n-th character
+    let offset = |n: usize| text.char_indices().nth(n).map_or(text.len(), |(i, _)| i);
+    match strategy {
+        TruncateStrategy::Head => format!("{}{}", marker, &text[offset(len - max_chars)..]),
+        TruncateStrategy::Middle => {
+            let head = max_chars.div_ceil(2);
+            let tail = max_chars - head;
+            format!(
+                "{}{}{}",
+                &text[..offset(head)],
+                marker,
+                &text[offset(len - tail)..]
+            )
+        }
+        TruncateStrategy::Tail => format!("{}{}", &text[..offset(max_chars)], marker),
+    }
+}
+
+/// Concatenate all system messages into a single leading system message.
+///
+/// Other messages keep their order. Returns the messages unchanged if there is at most one
+/// system message already at the front.
+pub fn merge_system_messages(messages: Vector<AgentValue>) -> Vector<AgentValue> {
+    let is_system = |v: &AgentValue| v.as_message().is_some_and(|m| m.role == "system");
+    let count = messages.iter().filter(|v| is_system(v)).count();
+    if count == 0 || (count == 1 && messages.front().is_some_and(is_system)) {
+        return messages;
+    }
+
+    let (system, mut rest): (Vector<AgentValue>, Vector<AgentValue>) =
+        messages.into_iter().partition(is_system);
+    let content = system
+        .iter()
+        .filter_map(|v| v.as_message())
+        .map(|m| m.content.as_str())
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    rest.push_front(AgentValue::message(Message::system(content)));
+    rest
+}
+
+/// Estimate the number of tokens in the messages.
+///
+/// The content is counted with the tokenizer if one is given, and otherwise
+/// estimated at about 4 characters per token. A few tokens of overhead are
+/// added per message.
+pub fn estimate_prompt_tokens(
+    messages: &Vector<AgentValue>,
+    tokenizer: Option<&Tokenizer>,
+) -> usize {
+    messages
+        .iter()
+        .filter_map(|v| v.as_message())
+        .map(|m| count_message_tokens(m, tokenizer))
+        .sum()
+}
+
+/// Count the tokens of a single message with the tokenizer, as in `estimate_prompt_tokens`.
+///
+/// Falls back to `estimate_message_tokens` without a tokenizer or if encoding fails.
+pub fn count_message_tokens(message: &Message, tokenizer: Option<&Tokenizer>) -> usize {
+    let Some(tokenizer) = tokenizer else {
+        return estimate_message_tokens(message);
+    };
+    match tokenizer.encode(message.content.as_str(), false) {
+        Ok(encoding) => encoding.len() + MESSAGE_TOKEN_OVERHEAD,
+        Err(_) => estimate_message_tokens(message),
+    }
+}
+
+/// Roughly estimate the number of tokens in a single message at about 4 characters per token.
+pub fn estimate_message_tokens(message: &Message) -> usize {
+    message.content.chars().count().div_ceil(4) + MESSAGE_TOKEN_OVERHEAD
+}
+
+/// Check that the messages fit in `max_tokens`, counted as in `estimate_prompt_tokens`.
+///
+/// If `trim` is set, the oldest non-system messages are dropped until they fit,
+/// keeping the last message and starting the conversation with a user message.
+/// If they still don't fit, the middle of the last message is cut with `truncate`.
+/// Otherwise an error describing the overflow is returned.
+pub fn fit_prompt(
+    mut messages: Vector<AgentValue>,
+    max_tokens: usize,
+    trim: bool,
+    tokenizer: Option<&Tokenizer>,
+) -> Result<Vector<AgentValue>, AgentError> {
+    let tokens = estimate_prompt_tokens(&messages, tokenizer);
+    if tokens <= max_tokens {
+        return Ok(messages);
+    }
+    if !trim {
+        return Err(AgentError::InvalidValue(format!(
+            "Prompt is too long: about {} tokens, but max_prompt_tokens is {}. Shorten the history or enable trim_prompt.",
+            tokens, max_tokens
+        )));
+    }
+
+    let is_system = |v: &AgentValue| v.as_message().is_some_and(|m| m.role == "system");
+    let is_user = |v: &AgentValue| v.as_message().is_some_and(|m| m.role == "user");
+    loop {
+        let Some(pos) = messages.iter().position(|v| !is_system(v)) else {
+            break;
+        };
+        if pos + 1 >= messages.len() {
+            // only the last message is left
+            break;
+        }
+        messages.remove(pos);
+        // drop replies orphaned by the removal
+        while let Some(pos) = messages.iter().position(|v| !is_system(v))
+            && pos + 1 < messages.len()
+            && !is_user(&messages[pos])
+        {
+            messages.remove(pos);
+        }
+        if estimate_prompt_tokens(&messages, tokenizer) <= max_tokens {
+            return Ok(messages);
+        }
+    }
+
+    if let Some(last) = messages.last().and_then(|v| v.as_message())
+        && last.role != "system"
+    {
+        // find the longest cut of the last message that fits
+        let last = last.clone();
+        let others = estimate_prompt_tokens(&messages, tokenizer)
+            - count_message_tokens(&last, tokenizer);
+        let fits = |max_chars: usize| {
+            let mut message = last.clone();
+            message.content = truncate(
+                &last.content,
+                max_chars,
+                TruncateStrategy::Middle,
+                DEFAULT_TRUNCATION_MARKER,
+            );
+            (others + count_message_tokens(&message, tokenizer) <= max_tokens).then_some(message)
+        };
+        let (mut lo, mut hi) = (1, last.content.chars().count());
+        let mut best = None;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match fits(mid) {
+                Some(message) => {
+                    best = Some(message);
+                    lo = mid + 1;
+                }
+                None => hi = mid,
+            }
+        }
+        if let Some(message) = best {
+            let index = messages.len() - 1;
+            messages.set(index, AgentValue::message(message));
+            return Ok(messages);
+        }
+    }
+
+    Err(AgentError::InvalidValue(format!(
+        "Prompt is too long even after trimming: about {} tokens, but max_prompt_tokens is {}.",
+        estimate_prompt_tokens(&messages, tokenizer),
+        max_tokens
+    )))
+}
+
+/// The id of a tool call, or a generated one based on its position in the
+/// assistant message for calls without an id, such as those from Ollama.
+pub fn tool_call_id(call: &ToolCall, index: usize) -> String {
+    call.function
+        .id
+        .clone()
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| format!("call_{}", index))
+}
+
+/// The id of the tool call answered by each tool message.
+///
+/// A tool message whose id is that of a call in the preceding assistant message,
+/// as set by `call_tools_concurrently`, answers that call. Otherwise it is matched
+/// by tool name to the first unanswered call with that name.
+/// Other messages, and tool messages without a matching call, get None.
+pub fn tool_result_ids(messages: &[Message]) -> Vec<Option<String>> {
+    let mut pending: Vec<(String, String)> = Vec::new();
+    messages
+        .iter()
+        .map(|msg| match msg.role.as_str() {
+            "assistant" => {
+                pending = msg
+                    .tool_calls
+                    .iter()
+                    .flatten()
+                    .enumerate()
+                    .map(|(i, call)| (tool_call_id(call, i), call.function.name.clone()))
+                    .collect();
+                None
+            }
+            "tool" | "function" => {
+                let pos = pending
+                    .iter()
+                    .position(|(id, _)| msg.id.as_ref() == Some(id))
+                    .or_else(|| {
+                        pending.iter().position(|(_, name)| {
+                            msg.tool_name.as_ref().is_none_or(|n| n == name)
+                        })
+                    })?;
+                Some(pending.remove(pos).0)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The turn an assistant message id belongs to, without its `#<round>` suffix.
+pub fn turn_id(id: &str) -> &str {
+    id.split_once('#').map_or(id, |(turn, _)| turn)
+}
+
+/// The id for the assistant message replying to the prompt.
+///
+/// With `stable`, a reply to tool results continues the turn of the assistant
+/// message that made the tool calls: it gets the same turn id with the next
+/// round, as in `<turn>#1`, `<turn>#2`. Any other reply starts a new turn.
+pub fn reply_message_id(messages: &Vector<AgentValue>, stable: bool) -> String {
+    if stable {
+        let previous = messages
+            .iter()
+            .rev()
+            .filter_map(|value| value.as_message())
+            .find(|msg| msg.role != "tool" && msg.role != "function");
+        if let Some(msg) = previous
+            && msg.role == "assistant"
+            && msg.tool_calls.as_ref().is_some_and(|calls| !calls.is_empty())
+            && let Some(id) = &msg.id
+        {
+            let round = id
+                .split_once('#')
+                .and_then(|(_, round)| round.parse::<usize>().ok())
+                .unwrap_or(0);
+            return format!("{}#{}", turn_id(id), round + 1);
+        }
+    }
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Remove the thinking from the messages.
+pub fn without_thinking(messages: Vector<AgentValue>) -> Vector<AgentValue> {
+    messages
+        .into_iter()
+        .map(|value| match value.as_message() {
+            Some(msg) if msg.thinking.is_some() => {
+                let mut msg = msg.clone();
+                msg.thinking = None;
+                AgentValue::message(msg)
+            }
+            _ => value,
+        })
+        .collect()
+}
+
+/// Keep the images of only the last max_images messages with an image.
+///
+/// The older images are removed from their messages, and the number removed is logged.
+#[cfg(feature = "image")]
+pub fn limit_images(messages: Vector<AgentValue>, max_images: usize) -> Vector<AgentValue> {
+    let mut kept = 0;
+    let mut dropped = 0;
+    let messages = messages
+        .into_iter()
+        .rev()
+        .map(|value| match value.as_message() {
+            Some(msg) if msg.image.is_some() => {
+                if kept < max_images {
+                    kept += 1;
+                    return value;
+                }
+                dropped += 1;
+                let mut msg = msg.clone();
+                msg.image = None;
+                AgentValue::message(msg)
+            }
+            _ => value,
+        })
+        .collect::<Vector<_>>();
+    if dropped > 0 {
+        log::info!("Dropped {} images beyond max_images {}", dropped, max_images);
+    }
+    messages.into_iter().rev().collect()
+}
+
+/// The content of the message with its thinking prepended in a `<think>` block,
+/// for providers that have no field for past reasoning.
+pub fn content_with_thinking(message: &Message) -> String {
+    match &message.thinking {
+        Some(thinking) if !thinking.is_empty() => {
+            format!("<think>\n{}\n</think>\n\n{}", thinking, message.content)
+        }
+        _ => message.content.clone(),
+    }
+}
+
+/// Check the order of the messages before they are sent to a provider.
+///
+/// The messages must not be empty, must end with a user or tool message, must not
+/// have two user or two assistant messages in a row, and each tool message must
+/// answer a call of the preceding assistant message.
+/// With `repair`, unanswered tool messages and trailing assistant messages are
+/// dropped and consecutive messages with the same role are merged instead of
+/// failing. Returns InvalidValue for what can't be repaired.
+pub fn validate_messages(
+    messages: Vector<AgentValue>,
+    repair: bool,
+) -> Result<Vector<AgentValue>, AgentError> {
+    let msgs: Vec<Message> = messages
+        .iter()
+        .filter_map(|v| v.as_message())
+        .cloned()
+        .collect();
+
+    let ids = tool_result_ids(&msgs);
+    let mut result: Vec<Message> = Vec::with_capacity(msgs.len());
+    for (i, (msg, id)) in msgs.into_iter().zip(ids).enumerate() {
+        if (msg.role == "tool" || msg.role == "function") && id.is_none() {
+            if repair {
+                continue;
+            }
+            return Err(AgentError::InvalidValue(format!(
+                "Tool message {} does not answer a tool call of the preceding assistant message",
+                i
+            )));
+        }
+        if let Some(last) = result.last_mut()
+            && last.role == msg.role
+            && (msg.role == "user" || msg.role == "assistant")
+        {
+            if !repair {
+                return Err(AgentError::InvalidValue(format!(
+                    "Messages {} and {} are both {} messages",
+                    i - 1,
+                    i,
+                    msg.role
+                )));
+            }
+            merge_message(last, msg)?;
+            continue;
+        }
+        result.push(msg);
+    }
+
+    if repair {
+        while result.last().is_some_and(|m| m.role == "assistant") {
+            result.pop();
+        }
+    }
+    let Some(last) = result.last() else {
+        return Err(AgentError::InvalidValue("No messages to send".to_string()));
+    };
+    if last.role != "user" && last.role != "tool" && last.role != "function" {
+        return Err(AgentError::InvalidValue(format!(
+            "Last message must be a user or tool message: {}",
+            last.role
+        )));
+    }
+    Ok(result.into_iter().map(AgentValue::message).collect())
+}
+
+/// Append the content, thinking and tool calls of `msg` to `into`.
+fn merge_message(into: &mut Message, msg: Message) -> Result<(), AgentError> {
+    if into.image.is_some() && msg.image.is_some() {
+        return Err(AgentError::InvalidValue(format!(
+            "Cannot merge two {} messages with images",
+            msg.role
+        )));
+    }
+    let join = |a: &str, b: &str| match (a.is_empty(), b.is_empty()) {
+        (_, true) => a.to_string(),
+        (true, false) => b.to_string(),
+        (false, false) => format!("{}\n\n{}", a, b),
+    };
+    into.content = join(&into.content, &msg.content);
+    if let Some(thinking) = msg.thinking {
+        into.thinking = Some(join(into.thinking.as_deref().unwrap_or(""), &thinking));
+    }
+    if let Some(calls) = msg.tool_calls {
+        into.tool_calls.get_or_insert_with(Vector::new).extend(calls);
+    }
+    if msg.image.is_some() {
+        into.image = msg.image;
+    }
+    Ok(())
+}
+
+/// Build the prompt messages sent to a chat provider from the chat input.
+///
+/// Thinking is removed unless resend_thinking is set, and the message order is
+/// checked with validate_messages, repairing it with repair_messages.
+/// Only the images of the latest max_images messages are sent.
+/// System messages are merged with merge_system, and the prompt is fitted to
+/// max_prompt_tokens, trimming it with trim_prompt. Returns `None` when there is
+/// nothing to send, which is an error only with error_on_empty.
+pub fn prompt_messages(
+    configs: &AgentConfigs,
+    value: AgentValue,
+) -> Result<Option<Vector<AgentValue>>, AgentError> {
+    let value = crate::message::normalize_openai_messages(value)?;
+    #[cfg(feature = "image")]
+    let value = crate::image::decode_message_images(value)?;
+    let Some(value) = value.to_message_value() else {
+        return Err(AgentError::InvalidValue(
+            "Input value is not a valid message".to_string(),
+        ));
+    };
+    let mut messages = if value.is_array() {
+        value.into_array().unwrap()
+    } else {
+        Vector::unit(value)
+    };
+    if configs.get_bool_or_default(CONFIG_MERGE_SYSTEM) {
+        messages = merge_system_messages(messages);
+    }
+    if !configs.get_bool_or_default(CONFIG_RESEND_THINKING) {
+        messages = without_thinking(messages);
+    }
+    #[cfg(feature = "image")]
+    {
+        let max_images = configs.get_integer_or_default(CONFIG_MAX_IMAGES);
+        if max_images > 0 {
+            messages = limit_images(messages, max_images as usize);
+        }
+    }
+    if configs.get_bool_or_default(CONFIG_VALIDATE_MESSAGES) {
+        let repair = configs.get_bool_or_default(CONFIG_REPAIR_MESSAGES);
+        messages = validate_messages(messages, repair)?;
+    }
+    let error_on_empty = configs.get_bool_or_default(CONFIG_ERROR_ON_EMPTY);
+    if messages.is_empty() {
+        return empty_input(error_on_empty, "No messages to send").map(|_| None);
+    }
+
+    // If the last message isn’t a user/tool/function message, there is nothing to answer
+    let role = &messages.last().unwrap().as_message().unwrap().role;
+    if role != "user" && role != "tool" && role != "function" {
+        return empty_input(
+            error_on_empty,
+            &format!("Last message must be a user, tool or function message: {}", role),
+        )
+        .map(|_| None);
+    }
+
+    let max_prompt_tokens = configs.get_integer_or_default(CONFIG_MAX_PROMPT_TOKENS);
+    if max_prompt_tokens > 0 {
+        let trim_prompt = configs.get_bool_or_default(CONFIG_TRIM_PROMPT);
+        // Count with the tokenizer of the model if one is set
+        let tokenizer = configs.get_string_or_default(CONFIG_TOKENIZER);
+        let tokenizer = match tokenizer.trim() {
+            "" => None,
+            tokenizer => Some(crate::doc::load_tokenizer(tokenizer)?),
+        };
+        messages = fit_prompt(
+            messages,
+            max_prompt_tokens as usize,
+            trim_prompt,
+            tokenizer.as_deref(),
+        )?;
+    }
+    Ok(Some(messages))
+}
+
+/// Call `f` again while its result is empty, at most `max_retries` more times.
+///
+/// The last result is returned even if it is still empty.
+pub async fn retry_on_empty<T, F, Fut>(
+    max_retries: usize,
+    mut f: F,
+    is_empty: impl Fn(&T) -> bool,
+) -> Result<T, AgentError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, AgentError>>,
+{
+    let mut result = f().await?;
+    let mut retries = 0;
+    while retries < max_retries && is_empty(&result) {
+        retries += 1;
+        result = f().await?;
+    }
+    Ok(result)
+}
+
+/// Exponential backoff for retrying transient provider errors, such as rate limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: i64, base_ms: i64) -> Self {
+        Self {
+            max_retries: max_retries.max(0) as usize,
+            base_delay: Duration::from_millis(base_ms.max(0) as u64),
+        }
+    }
+
+    /// Delay before the given retry (starting at 1): base * 2^(retry - 1),
+    /// plus a random jitter of up to base.
+    pub fn delay(&self, retry: usize) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << retry.saturating_sub(1).min(16));
+        let base_nanos = self.base_delay.as_nanos() as u64;
+        if base_nanos == 0 {
+            return backoff;
+        }
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64)
+            .unwrap_or_default();
+        backoff + Duration::from_nanos(seed % base_nanos)
+    }
+}
+
+/// Call `f` again after a backoff while it fails with a transient error,
+/// at most `policy.max_retries` more times.
+///
+/// Other errors fail immediately. The final error is reported as an IoError
+/// prefixed with `label`, including the number of attempts when retried.
+pub async fn retry_transient<T, E, F, Fut>(
+    policy: RetryPolicy,
+    label: &str,
+    mut f: F,
+    is_transient: impl Fn(&E) -> bool,
+) -> Result<T, AgentError>
+where
+    E: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match f().await {
+            Ok(res) => return Ok(res),
+            Err(e) if attempts <= policy.max_retries && is_transient(&e) => {
+                log::warn!("{}: {} (retrying, attempt {})", label, e, attempts);
+                tokio::time::sleep(policy.delay(attempts)).await;
+            }
+            Err(e) if attempts > 1 => {
+                return Err(AgentError::IoError(format!(
+                    "{} after {} attempts: {}",
+                    label, attempts, e
+                )));
+            }
+            Err(e) => return Err(AgentError::IoError(format!("{}: {}", label, e))),
+        }
+    }
+}
+
+/// Response fields that may echo the prompt back.
+const REDACTED_RESPONSE_KEYS: [&str; 6] =
+    ["context", "input", "messages", "prompt", "system", "template"];
+
+/// Convert a provider response into the value emitted on the `response` pin.
+///
+/// With `pretty` the response is emitted as an indented JSON string instead of an object.
+/// With `redact` the fields echoing the input are removed, leaving output, usage and metadata.
+pub fn response_value<T: Serialize>(
+    res: &T,
+    pretty: bool,
+    redact: bool,
+) -> Result<AgentValue, AgentError> {
+    let mut json = serde_json::to_value(res)
+        .map_err(|e| AgentError::InvalidValue(format!("Failed to serialize: {}", e)))?;
+    if redact && let Some(obj) = json.as_object_mut() {
+        for key in REDACTED_RESPONSE_KEYS {
+            obj.remove(key);
+        }
+    }
+    if pretty {
+        let json = serde_json::to_string_pretty(&json)
+            .map_err(|e| AgentError::InvalidValue(format!("Failed to serialize: {}", e)))?;
+        return Ok(AgentValue::string(json));
+    }
+    AgentValue::from_json(json)
+}
+
+/// Deadline for the first streamed chunk, or None when the timeout is 0.
+pub fn first_token_deadline(timeout_secs: i64) -> Option<Instant> {
+    (timeout_secs > 0).then(|| Instant::now() + Duration::from_secs(timeout_secs as u64))
+}
+
+/// Wait for the next stream item, failing if it does not arrive before the deadline.
+pub async fn next_before<S: Stream + Unpin>(
+    stream: &mut S,
+    deadline: Option<Instant>,
+) -> Result<Option<S::Item>, AgentError> {
+    first_token_before(stream.next(), deadline).await
+}
+
+/// Await the future that yields the first token, failing if it does not complete before the deadline.
+pub async fn first_token_before<F: Future>(
+    fut: F,
+    deadline: Option<Instant>,
+) -> Result<F::Output, AgentError> {
+    let Some(deadline) = deadline else {
+        return Ok(fut.await);
+    };
+    tokio::time::timeout_at(deadline, fut)
+        .await
+        .map_err(|_| AgentError::IoError("Timed out waiting for the first token".to_string()))
+}
+
+/// Selects which streamed response chunks go out on the response pin.
+///
+/// When `stream` is false, only the last chunk of a turn is emitted.
+pub struct StreamResponses<T> {
+    stream: bool,
+    last: Option<T>,
+}
+
+impl<T> StreamResponses<T> {
+    pub fn new(stream: bool) -> Self {
+        Self { stream, last: None }
+    }
+
+    /// Returns the chunk if it should be emitted right away.
+    pub fn push(&mut self, res: T) -> Option<T> {
+        if self.stream {
+            return Some(res);
+        }
+        self.last = Some(res);
+        None
+    }
+
+    /// Returns the held back final chunk, if any.
+    pub fn finish(self) -> Option<T> {
+        self.last
+    }
+}
+
+/// Accumulates values into batches of up to `batch_size`.
+#[derive(Default)]
+struct Batcher {
+    items: Vec<AgentValue>,
+    // Bumped on every flush so that stale timers can tell their batch is gone
+    generation: u64,
+}
+
+impl Batcher {
+    /// Add a value, returning the batch if it is now full.
+    fn push(&mut self, value: AgentValue, batch_size: usize) -> Option<Vec<AgentValue>> {
+        self.items.push(value);
+        if self.items.len() >= batch_size {
+            return self.take();
+        }
+        None
+    }
+
+    /// Take the pending values, if any.
+    fn take(&mut self) -> Option<Vec<AgentValue>> {
+        if self.items.is_empty() {
+            return None;
+        }
+        self.generation += 1;
+        Some(std::mem::take(&mut self.items))
+    }
+}
+
+/// Flush the batch after `delay` unless it was already flushed in the meantime.
+fn spawn_flush<F, Fut>(batcher: Arc<Mutex<Batcher>>, delay: Duration, flush: F)
+where
+    F: FnOnce(Vec<AgentValue>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let generation = batcher.lock().unwrap().generation;
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let items = {
+            let mut batcher = batcher.lock().unwrap();
+            if batcher.generation != generation {
+                return;
+            }
+            batcher.take()
+        };
+        if let Some(items) = items {
+            flush(items).await;
+        }
+    });
+}
+
+// Batch Agent
+#[askit_agent(
+    title="Batch",
+    category=CATEGORY,
+    inputs=[PIN_VALUE, PIN_END],
+    outputs=[PIN_BATCH],
+    integer_config(name=CONFIG_BATCH_SIZE, default=16, title="Batch Size"),
+    integer_config(name=CONFIG_FLUSH_MS, title="Flush (ms)"),
+)]
+pub struct BatchAgent {
+    data: AgentData,
+    batcher: Arc<Mutex<Batcher>>,
+}
+
+#[async_trait]
+impl AsAgent for BatchAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        Ok(Self {
+            data: AgentData::new(askit, id, spec),
+            batcher: Arc::new(Mutex::new(Batcher::default())),
+        })
+    }
+
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        self.batcher.lock().unwrap().take();
+        Ok(())
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        if pin == PIN_END {
+            let items = self.batcher.lock().unwrap().take();
+            if let Some(items) = items {
+                self.output(ctx, PIN_BATCH, AgentValue::array(items.into()))
+                    .await?;
+            }
+            return Ok(());
+        }
+
+        if pin != PIN_VALUE {
+            return Err(AgentError::InvalidPin(pin));
+        }
+
+        let batch_size = self.configs()?.get_integer_or_default(CONFIG_BATCH_SIZE);
+        if batch_size <= 0 {
+            return Err(AgentError::InvalidConfig(
+                "batch_size must be greater than 0".to_string(),
+            ));
+        }
+        let flush_ms = self.configs()?.get_integer_or_default(CONFIG_FLUSH_MS);
+
+        let (full, first) = {
+            let mut batcher = self.batcher.lock().unwrap();
+            let full = batcher.push(value, batch_size as usize);
+            (full, batcher.items.len() == 1)
+        };
+        if let Some(items) = full {
+            return self
+                .output(ctx, PIN_BATCH, AgentValue::array(items.into()))
+                .await;
+        }
+
+        // Start the timer when a new batch begins
+        if first && flush_ms > 0 {
+            let askit = self.askit().clone();
+            let agent_id = self.id().to_string();
+            spawn_flush(
+                self.batcher.clone(),
+                Duration::from_millis(flush_ms as u64),
+                move |items| async move {
+                    let _ = askit
+                        .send_agent_out(
+                            agent_id,
+                            ctx,
+                            PIN_BATCH.to_string(),
+                            AgentValue::array(items.into()),
+                        )
+                        .await;
+                },
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Chat providers selectable in the unified chat agent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provider {
+    Anthropic,
+    Ollama,
+    OpenAI,
+}
+
+impl Provider {
+    #[cfg_attr(
+        not(all(feature = "anthropic", feature = "ollama", feature = "openai")),
+        allow(unused_variables)
+    )]
+    fn default_model(&self, askit: &ASKit) -> String {
+        match self {
+            #[cfg(feature = "anthropic")]
+            Provider::Anthropic => crate::anthropic::DEFAULT_CONFIG_MODEL.to_string(),
+            #[cfg(feature = "ollama")]
+            Provider::Ollama => crate::ollama::default_chat_model(askit),
+            #[cfg(feature = "openai")]
+            Provider::OpenAI => crate::openai::default_chat_model(askit),
+            #[allow(unreachable_patterns)]
+            _ => String::new(),
+        }
+    }
+
+    #[cfg_attr(
+        not(all(feature = "anthropic", feature = "ollama", feature = "openai")),
+        allow(unused_variables)
+    )]
+    async fn chat_once(
+        &self,
+        askit: &ASKit,
+        model: &str,
+        messages: &[Message],
+    ) -> Result<String, AgentError> {
+        match self {
+            #[cfg(feature = "anthropic")]
+            Provider::Anthropic => crate::anthropic::chat_once(askit, model, messages).await,
+            #[cfg(feature = "ollama")]
+            Provider::Ollama => crate::ollama::chat_once(askit, model, messages).await,
+            #[cfg(feature = "openai")]
+            Provider::OpenAI => crate::openai::chat_once(askit, model, messages).await,
+            #[allow(unreachable_patterns)]
+            _ => Err(AgentError::InvalidConfig(format!(
+                "Provider {:?} is not enabled",
+                self
+            ))),
+        }
+    }
+}
+
+impl FromStr for Provider {
+    type Err = AgentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            PROVIDER_ANTHROPIC => Ok(Provider::Anthropic),
+            PROVIDER_OLLAMA => Ok(Provider::Ollama),
+            PROVIDER_OPENAI => Ok(Provider::OpenAI),
+            other => Err(AgentError::InvalidConfig(format!(
+                "Unsupported provider: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Create the provider specific chat agent that does the actual work.
+///
+/// It shares the id and spec of the unified agent, so its outputs are
+/// emitted from the unified agent.
+#[cfg_attr(
+    not(all(feature = "anthropic", feature = "ollama", feature = "openai")),
+    allow(unused_variables)
+)]
+fn new_provider_agent(
+    provider: Provider,
+    askit: ASKit,
+    id: String,
+    spec: AgentSpec,
+) -> Result<Box<dyn Agent>, AgentError> {
+    match provider {
+        #[cfg(feature = "anthropic")]
+        Provider::Anthropic => Ok(Box::new(
+            <crate::anthropic::AnthropicChatAgent as Agent>::new(askit, id, spec)?,
+        )),
+        #[cfg(feature = "ollama")]
+        Provider::Ollama => Ok(Box::new(
+            <crate::ollama::OllamaChatAgent as Agent>::new(askit, id, spec)?,
+        )),
+        #[cfg(feature = "openai")]
+        Provider::OpenAI => Ok(Box::new(
+            <crate::openai::OpenAIChatAgent as Agent>::new(askit, id, spec)?,
+        )),
+        #[allow(unreachable_patterns)]
+        _ => Err(AgentError::InvalidConfig(format!(
+            "Provider {:?} is not enabled",
+            provider
+        ))),
+    }
+}
+
+// Unified Chat Agent
+/// Chat through the selected provider's chat agent.
+///
+/// All configs are forwarded to the provider agent, which ignores the ones
+/// it does not have: max_tokens applies only to Anthropic, prefix_tool_name
+/// only to Ollama, extra_headers only to OpenAI, retry_on_empty and
+/// max_empty_retries to OpenAI and Ollama, and max_retries and retry_base_ms
+/// to OpenAI and Anthropic. The rest apply to every provider.
+#[askit_agent(
+    title="Chat",
+    category=CATEGORY,
+    inputs=[PIN_MESSAGE],
+    outputs=[PIN_MESSAGE, PIN_RESPONSE, PIN_REFUSAL, PIN_CONTENT, PIN_TOOL_CALLS, PIN_PROMPT],
+    string_config(name=CONFIG_PROVIDER, default=PROVIDER_OPENAI, title="Provider"),
+    boolean_config(name=CONFIG_STREAM, title="Stream"),
+    boolean_config(name=CONFIG_STREAM_RESPONSE, default=true, title="Stream Response"),
+    integer_config(name=CONFIG_FIRST_TOKEN_TIMEOUT_SECS, title="First Token Timeout (secs)"),
+    string_config(name=CONFIG_MODEL),
+    integer_config(name=CONFIG_MAX_TOKENS, title="Max Tokens"),
+    text_config(name=CONFIG_TOOLS),
+    object_config(name=CONFIG_OPTIONS),
+    string_config(name=CONFIG_IMAGE_FORMAT, default=IMAGE_FORMAT_PNG, title="Image Format"),
+    integer_config(name=CONFIG_MAX_IMAGES, title="Max Images"),
+    boolean_config(name=CONFIG_RETRY_ON_EMPTY, title="Retry on Empty"),
+    integer_config(name=CONFIG_MAX_EMPTY_RETRIES, default=2, title="Max Empty Retries"),
+    integer_config(name=CONFIG_MAX_THINKING_TOKENS, title="Max Thinking Tokens"),
+    boolean_config(name=CONFIG_RESEND_THINKING, title="Resend Thinking"),
+    boolean_config(name=CONFIG_PRETTY_RESPONSE, title="Pretty Response"),
+    boolean_config(name=CONFIG_REDACT_RESPONSE, title="Redact Response"),
+    boolean_config(name=CONFIG_ERROR_ON_EMPTY, title="Error on Empty"),
+    boolean_config(name=CONFIG_MERGE_SYSTEM, title="Merge System Messages"),
+    integer_config(name=CONFIG_MAX_PROMPT_TOKENS, title="Max Prompt Tokens"),
+    boolean_config(name=CONFIG_TRIM_PROMPT, title="Trim Prompt"),
+    string_config(name=CONFIG_TOKENIZER, title="Tokenizer"),
+    boolean_config(name=CONFIG_VALIDATE_MESSAGES, title="Validate Messages"),
+    boolean_config(name=CONFIG_REPAIR_MESSAGES, title="Repair Messages"),
+    boolean_config(name=CONFIG_EMIT_PROMPT, title="Emit Prompt"),
+    boolean_config(name=CONFIG_STABLE_TURN_ID, title="Stable Turn Id"),
+    text_config(name=CONFIG_EXTRA_HEADERS, title="Extra Headers"),
+    integer_config(name=CONFIG_MAX_RETRIES, default=3, title="Max Retries"),
+    integer_config(name=CONFIG_RETRY_BASE_MS, default=500, title="Retry Base Delay (ms)"),
+    boolean_config(name=CONFIG_PREFIX_TOOL_NAME, title="Prefix Tool Name"),
+)]
+pub struct UnifiedChatAgent {
+    data: AgentData,
+    inner: Option<(Provider, Box<dyn Agent>)>,
+}
+
+#[async_trait]
+impl AsAgent for UnifiedChatAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        Ok(Self {
+            data: AgentData::new(askit, id, spec),
+            inner: None,
+        })
+    }
+
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        self.inner = None;
+        Ok(())
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let provider: Provider = self
+            .configs()?
+            .get_string_or_default(CONFIG_PROVIDER)
+            .parse()?;
+
+        // Recreate the provider agent only when the provider changes, to keep its client
+        if self.inner.as_ref().is_none_or(|(p, _)| *p != provider) {
+            let agent = new_provider_agent(
+                provider,
+                self.askit().clone(),
+                self.id().to_string(),
+                self.spec().clone(),
+            )?;
+            self.inner = Some((provider, agent));
+        }
+
+        let mut configs = self.configs()?.clone();
+        if configs.get_string_or_default(CONFIG_MODEL).is_empty() {
+            configs.set(
+                CONFIG_MODEL.to_string(),
+                AgentValue::string(provider.default_model(self.askit())),
+            );
+        }
+
+        let (_, agent) = self.inner.as_mut().unwrap();
+        agent.set_configs(configs)?;
+        agent.process(ctx, pin, value).await
+    }
+}
+
+/// Render the first `max_messages` non-system messages as a plain transcript.
+///
+/// Returns an empty string when there is nothing to summarize.
+fn title_transcript(messages: &Vector<AgentValue>, max_messages: usize) -> String {
+    messages
+        .iter()
+        .filter_map(|m| m.as_message())
+        .filter(|m| m.role != "system" && !m.content.trim().is_empty())
+        .take(max_messages)
+        .map(|m| format!("{}: {}", m.role, m.content.trim()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Clean up the model reply into a single line title.
+fn clean_title(reply: &str) -> String {
+    let line = reply.trim().lines().next().unwrap_or_default();
+    line.trim()
+        .trim_matches(|c| c == '"' || c == '\'' || c == '*')
+        .trim()
+        .to_string()
+}
+
+/// Generate a conversation title from the first messages of a history.
+///
+/// An empty history outputs an empty title without calling the model.
+#[askit_agent(
+    title="Title",
+    category=CATEGORY,
+    inputs=[PIN_MESSAGES],
+    outputs=[PIN_TITLE],
+    string_config(name=CONFIG_PROVIDER, default=PROVIDER_OPENAI, title="Provider"),
+    string_config(name=CONFIG_MODEL),
+    integer_config(name=CONFIG_MAX_MESSAGES, default=4, title="Max Messages"),
+    text_config(name=CONFIG_PROMPT, default=DEFAULT_TITLE_PROMPT),
+)]
+pub struct TitleAgent {
+    data: AgentData,
+}
+
+#[async_trait]
+impl AsAgent for TitleAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        Ok(Self {
+            data: AgentData::new(askit, id, spec),
+        })
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let title = self.generate_title(value).await?;
+        self.output(ctx, PIN_TITLE, AgentValue::string(title)).await
+    }
+}
+
+impl TitleAgent {
+    async fn generate_title(&self, value: AgentValue) -> Result<String, AgentError> {
+        let messages = match value.to_message_value() {
+            Some(v) if v.is_array() => v.into_array().unwrap_or_default(),
+            Some(v) => Vector::unit(v),
+            None => Vector::new(),
+        };
+        let max_messages = self.configs()?.get_integer_or_default(CONFIG_MAX_MESSAGES);
+        let max_messages = if max_messages > 0 {
+            max_messages as usize
+        } else {
+            usize::MAX
+        };
+        let transcript = title_transcript(&messages, max_messages);
+        if transcript.is_empty() {
+            return Ok(String::new());
+        }
+
+        let provider: Provider = self
+            .configs()?
+            .get_string_or_default(CONFIG_PROVIDER)
+            .parse()?;
+        let mut model = self.configs()?.get_string_or_default(CONFIG_MODEL);
+        if model.is_empty() {
+            model = provider.default_model(self.askit());
+        }
+        let prompt = self.configs()?.get_string_or_default(CONFIG_PROMPT);
+        let prompt = vec![Message::system(prompt), Message::user(transcript)];
+        let reply = provider.chat_once(self.askit(), &model, &prompt).await?;
+        Ok(clean_title(&reply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retry_on_empty() {
+        // The first response is empty and the retry yields content
+        let mut responses = vec![
+            Message::assistant("".to_string()),
+            Message::assistant("Hello".to_string()),
+        ]
+        .into_iter();
+        let mut calls = 0;
+        let message = retry_on_empty(
+            2,
+            || {
+                calls += 1;
+                let message = responses.next().unwrap();
+                async move { Ok(message) }
+            },
+            is_empty_message,
+        )
+        .await
+        .unwrap();
+        assert_eq!(message.content, "Hello");
+        assert_eq!(calls, 2);
+
+        // Give up after max_retries and return the empty result
+        let mut calls = 0;
+        let message = retry_on_empty(
+            2,
+            || {
+                calls += 1;
+                async { Ok(Message::assistant(" ".to_string())) }
+            },
+            is_empty_message,
+        )
+        .await
+        .unwrap();
+        assert!(is_empty_message(&message));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_response_value() {
+        let res = serde_json::json!({"model": "m", "message": {"content": "Hi"}});
+
+        let compact = response_value(&res, false, false).unwrap();
+        assert!(compact.is_object());
+        assert_eq!(compact.get("model").unwrap().as_str(), Some("m"));
+
+        let pretty = response_value(&res, true, false).unwrap();
+        let s = pretty.as_str().unwrap();
+        assert!(s.contains('\n'));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(s).unwrap(), res);
+    }
+
+    #[test]
+    fn test_response_value_redact() {
+        let res = serde_json::json!({
+            "model": "m",
+            "prompt": "my secret is 1234",
+            "context": [1, 2, 3],
+            "response": "Hi",
+            "eval_count": 2,
+        });
+
+        let value = response_value(&res, false, false).unwrap();
+        assert!(value.get("prompt").is_some());
+
+        let value = response_value(&res, false, true).unwrap();
+        assert!(value.get("prompt").is_none());
+        assert!(value.get("context").is_none());
+        assert_eq!(value.get_str("response"), Some("Hi"));
+        assert_eq!(value.get("eval_count").unwrap().as_i64(), Some(2));
+
+        let pretty = response_value(&res, true, true).unwrap();
+        assert!(!pretty.as_str().unwrap().contains("secret"));
+    }
+
+    #[test]
+    fn test_batcher_size() {
+        let mut batcher = Batcher::default();
+        assert!(batcher.push(AgentValue::integer(1), 3).is_none());
+        assert!(batcher.push(AgentValue::integer(2), 3).is_none());
+        let batch = batcher.push(AgentValue::integer(3), 3).unwrap();
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch[2].as_i64(), Some(3));
+
+        // partial batch on end
+        assert!(batcher.push(AgentValue::integer(4), 3).is_none());
+        let batch = batcher.take().unwrap();
+        assert_eq!(batch.len(), 1);
+        assert!(batcher.take().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_batcher_flush_timeout() {
+        let batcher = Arc::new(Mutex::new(Batcher::default()));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        batcher.lock().unwrap().push(AgentValue::integer(1), 10);
+        batcher.lock().unwrap().push(AgentValue::integer(2), 10);
+        let tx1 = tx.clone();
+        spawn_flush(batcher.clone(), Duration::from_millis(20), move |items| {
+            async move {
+                tx1.send(items).unwrap();
+            }
+        });
+        let batch = rx.recv().await.unwrap();
+        assert_eq!(batch.len(), 2);
+
+        // A timer for a batch that was already flushed does nothing
+        batcher.lock().unwrap().push(AgentValue::integer(3), 10);
+        spawn_flush(batcher.clone(), Duration::from_millis(20), move |items| {
+            async move {
+                tx.send(items).unwrap();
+            }
+        });
+        batcher.lock().unwrap().take();
+        let res = tokio::time::timeout(Duration::from_millis(100), rx.recv()).await;
+        assert!(matches!(res, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn test_first_token_timeout() {
+        // Stalls before the first chunk
+        let mut stalled = tokio_stream::pending::<i32>();
+        let deadline = first_token_deadline(1);
+        let res = next_before(&mut stalled, deadline).await;
+        assert!(matches!(res, Err(AgentError::IoError(_))));
+
+        // The first chunk arrives in time; later chunks are not bound by the deadline
+        let mut stream = Box::pin(
+            tokio_stream::iter(vec![1, 2]).then(|i| async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                i
+            }),
+        );
+        let mut deadline = first_token_deadline(1);
+        assert_eq!(next_before(&mut stream, deadline.take()).await.unwrap(), Some(1));
+        assert_eq!(next_before(&mut stream, deadline.take()).await.unwrap(), Some(2));
+        assert_eq!(next_before(&mut stream, deadline.take()).await.unwrap(), None);
+
+        assert!(first_token_deadline(0).is_none());
+    }
+
+    #[test]
+    fn test_message_parts() {
+        use agent_stream_kit::{ToolCall, ToolCallFunction};
+
+        let mut message = Message::assistant("Let me check the weather.".to_string());
+        message.tool_calls = Some(im::vector![ToolCall {
+            function: ToolCallFunction {
+                id: Some("call_1".to_string()),
+                name: "get_weather".to_string(),
+                parameters: serde_json::json!({"city": "Tokyo"}),
+            },
+        }]);
+        let (content, tool_calls) = message_parts(&message).unwrap();
+        assert_eq!(content.unwrap().as_str(), Some("Let me check the weather."));
+        let tool_calls = tool_calls.unwrap();
+        let calls = tool_calls.as_array().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(
+            calls[0].get("function").unwrap().get_str("name"),
+            Some("get_weather")
+        );
+
+        let (content, tool_calls) =
+            message_parts(&Message::assistant("Hello".to_string())).unwrap();
+        assert!(content.is_some());
+        assert!(tool_calls.is_none());
+    }
+
+    #[test]
+    fn test_stream_responses() {
+        fn count_emitted(stream: bool) -> usize {
+            let mut responses = StreamResponses::new(stream);
+            let mut emitted = Vec::new();
+            for chunk in 0..5 {
+                emitted.extend(responses.push(chunk));
+            }
+            emitted.extend(responses.finish());
+            if !stream {
+                // Only the final chunk survives
+                assert_eq!(emitted, vec![4]);
+            }
+            emitted.len()
+        }
+
+        assert_eq!(count_emitted(true), 5);
+        assert_eq!(count_emitted(false), 1);
+    }
+
+    #[tokio::test]
+    async fn test_title_empty_history() {
+        let askit = ASKit::init().unwrap();
+        let spec = askit.new_agent_spec(TitleAgent::DEF_NAME).unwrap();
+        let mut agent = <TitleAgent as Agent>::new(askit, "title".into(), spec).unwrap();
+        // An unknown provider would fail if the model were called
+        agent
+            .set_config(CONFIG_PROVIDER.into(), AgentValue::string("sakura"))
+            .unwrap();
+
+        let history = AgentValue::array(im::vector![AgentValue::message(Message::system(
+            "You are helpful.".to_string()
+        ))]);
+        for value in [AgentValue::array_default(), history] {
+            assert_eq!(agent.generate_title(value).await.unwrap(), "");
+        }
+
+        let messages = im::vector![
+            AgentValue::message(Message::system("sys".to_string())),
+            AgentValue::message(Message::user("How do I bake bread?".to_string())),
+            AgentValue::message(Message::assistant("Mix flour and water.".to_string())),
+            AgentValue::message(Message::user("And then?".to_string())),
+        ];
+        assert_eq!(
+            title_transcript(&messages, 2),
+            "user: How do I bake bread?\nassistant: Mix flour and water."
+        );
+        assert_eq!(clean_title("\"Baking Bread\"\nextra"), "Baking Bread");
+    }
+
+    #[test]
+    fn test_provider_routing() {
+        assert_eq!("openai".parse::<Provider>().unwrap(), Provider::OpenAI);
+        assert_eq!(" Ollama ".parse::<Provider>().unwrap(), Provider::Ollama);
+        assert_eq!("anthropic".parse::<Provider>().unwrap(), Provider::Anthropic);
+        assert!("sakura".parse::<Provider>().is_err());
+
+        let askit = ASKit::init().unwrap();
+        let spec = askit.new_agent_spec(UnifiedChatAgent::DEF_NAME).unwrap();
+
+        #[cfg(feature = "openai")]
+        {
+            let agent =
+                new_provider_agent(Provider::OpenAI, askit.clone(), "a".into(), spec.clone())
+                    .unwrap();
+            assert!(agent.as_agent::<crate::openai::OpenAIChatAgent>().is_some());
+            assert_eq!(agent.id(), "a");
+        }
+
+        #[cfg(feature = "ollama")]
+        {
+            let agent =
+                new_provider_agent(Provider::Ollama, askit.clone(), "a".into(), spec.clone())
+                    .unwrap();
+            assert!(agent.as_agent::<crate::ollama::OllamaChatAgent>().is_some());
+        }
+
+        #[cfg(feature = "anthropic")]
+        {
+            let agent =
+                new_provider_agent(Provider::Anthropic, askit.clone(), "a".into(), spec.clone())
+                    .unwrap();
+            assert!(agent.as_agent::<crate::anthropic::AnthropicChatAgent>().is_some());
+        }
+
+        #[cfg(not(feature = "anthropic"))]
+        {
+            let res = new_provider_agent(Provider::Anthropic, askit, "a".into(), spec);
+            assert!(matches!(res, Err(AgentError::InvalidConfig(_))));
+        }
+    }
+
+    #[test]
+    fn test_unified_chat_configs() {
+        let askit = ASKit::init().unwrap();
+        let config_keys = |def_name: &str| -> Vec<String> {
+            let spec = askit.new_agent_spec(def_name).unwrap();
+            spec.config_specs.unwrap().keys().cloned().collect()
+        };
+        let unified = config_keys(UnifiedChatAgent::DEF_NAME);
+
+        // Every config of a provider chat agent can be set on the unified agent
+        let provider_defs: Vec<&str> = vec![
+            #[cfg(feature = "anthropic")]
+            crate::anthropic::AnthropicChatAgent::DEF_NAME,
+            #[cfg(feature = "ollama")]
+            crate::ollama::OllamaChatAgent::DEF_NAME,
+            #[cfg(feature = "openai")]
+            crate::openai::OpenAIChatAgent::DEF_NAME,
+        ];
+        let mut provider_keys = vec![CONFIG_PROVIDER.to_string()];
+        for def_name in provider_defs {
+            provider_keys.extend(config_keys(def_name));
+        }
+        for key in &provider_keys {
+            assert!(unified.contains(key), "missing config {}", key);
+        }
+
+        // and with every provider enabled, each unified config is used by one
+        #[cfg(all(feature = "anthropic", feature = "ollama", feature = "openai"))]
+        for key in &unified {
+            assert!(provider_keys.contains(key), "unused config {}", key);
+        }
+    }
+
+    #[test]
+    fn test_provider_default_model() {
+        #[cfg(any(feature = "anthropic", feature = "ollama", feature = "openai"))]
+        let askit = ASKit::init().unwrap();
+
+        #[cfg(feature = "openai")]
+        {
+            let def_name = crate::openai::OpenAICompletionAgent::DEF_NAME;
+            assert_eq!(
+                Provider::OpenAI.default_model(&askit),
+                crate::openai::DEFAULT_CONFIG_MODEL
+            );
+            let mut configs = askit.get_global_configs(def_name).unwrap_or_default();
+            configs.set(
+                "openai_default_model".to_string(),
+                AgentValue::string("gpt-5-mini"),
+            );
+            askit.set_global_configs(def_name.to_string(), configs);
+            assert_eq!(Provider::OpenAI.default_model(&askit), "gpt-5-mini");
+        }
+
+        #[cfg(feature = "ollama")]
+        {
+            let def_name = crate::ollama::OllamaCompletionAgent::DEF_NAME;
+            assert_eq!(
+                Provider::Ollama.default_model(&askit),
+                crate::ollama::DEFAULT_CONFIG_MODEL
+            );
+            let mut configs = askit.get_global_configs(def_name).unwrap_or_default();
+            configs.set(
+                "ollama_default_model".to_string(),
+                AgentValue::string("llama3.2"),
+            );
+            askit.set_global_configs(def_name.to_string(), configs);
+            assert_eq!(Provider::Ollama.default_model(&askit), "llama3.2");
+        }
+
+        #[cfg(feature = "anthropic")]
+        assert_eq!(
+            Provider::Anthropic.default_model(&askit),
+            crate::anthropic::DEFAULT_CONFIG_MODEL
+        );
+    }
+
+    #[test]
+    fn test_merge_system_messages() {
+        let messages = im::vector![
+            AgentValue::message(Message::system("You are helpful.".to_string())),
+            AgentValue::message(Message::user("Hi".to_string())),
+            AgentValue::message(Message::system("Answer in Japanese.".to_string())),
+            AgentValue::message(Message::user("Hello".to_string())),
+        ];
+        let merged = merge_system_messages(messages);
+        assert_eq!(merged.len(), 3);
+        let system = merged[0].as_message().unwrap();
+        assert_eq!(system.role, "system");
+        assert_eq!(system.content, "You are helpful.\n\nAnswer in Japanese.");
+        assert_eq!(merged[1].as_message().unwrap().content, "Hi");
+        assert_eq!(merged[2].as_message().unwrap().content, "Hello");
+
+        // a single leading system message is left as is
+        let messages = im::vector![
+            AgentValue::message(Message::system("You are helpful.".to_string())),
+            AgentValue::message(Message::user("Hi".to_string())),
+        ];
+        assert_eq!(merge_system_messages(messages.clone()), messages);
+    }
+
+    #[test]
+    fn test_fit_prompt() {
+        let messages = im::vector![
+            AgentValue::message(Message::system("Be brief.".to_string())),
+            AgentValue::message(Message::user("a".repeat(400))),
+            AgentValue::message(Message::assistant("b".repeat(400))),
+            AgentValue::message(Message::user("What now?".to_string())),
+        ];
+        let tokens = estimate_prompt_tokens(&messages, None);
+        assert!(tokens > 200);
+
+        assert_eq!(fit_prompt(messages.clone(), 1000, false, None).unwrap().len(), 4);
+
+        let err = fit_prompt(messages.clone(), 100, false, None).unwrap_err();
+        let AgentError::InvalidValue(msg) = err else {
+            panic!("unexpected error");
+        };
+        assert!(msg.contains("max_prompt_tokens is 100"));
+        assert!(msg.contains(&format!("about {} tokens", tokens)));
+
+        // trimming keeps the system message and the last user message
+        let trimmed = fit_prompt(messages.clone(), 100, true, None).unwrap();
+        assert_eq!(trimmed.len(), 2);
+        assert_eq!(trimmed[0].as_message().unwrap().role, "system");
+        assert_eq!(trimmed[1].as_message().unwrap().content, "What now?");
+
+        assert!(fit_prompt(messages, 5, true, None).is_err());
+    }
+
+    #[test]
+    fn test_fit_prompt_truncate() {
+        let content = format!("{}{}", "a".repeat(400), "b".repeat(400));
+        let messages = im::vector![
+            AgentValue::message(Message::system("Be brief.".to_string())),
+            AgentValue::message(Message::user(content.clone())),
+        ];
+
+        // the last message is cut in the middle to fit
+        let trimmed = fit_prompt(messages.clone(), 100, true, None).unwrap();
+        assert!(estimate_prompt_tokens(&trimmed, None) <= 100);
+        let last = trimmed[1].as_message().unwrap();
+        assert!(last.content.len() < content.len());
+        assert!(last.content.starts_with('a'));
+        assert!(last.content.ends_with('b'));
+        assert!(last.content.contains(DEFAULT_TRUNCATION_MARKER));
+
+        // not without trim
+        assert!(fit_prompt(messages, 100, false, None).is_err());
+    }
+
+    #[test]
+    fn test_fit_prompt_tokenizer() {
+        let path = std::env::temp_dir().join(format!("tokenizer-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, crate::doc::tests::word_level_tokenizer()).unwrap();
+        let name = path.to_str().unwrap();
+        let tokenizer = crate::doc::load_tokenizer(name).unwrap();
+
+        // 100 words of 20 characters are 100 tokens, not 500
+        let message = Message::user(vec!["a".repeat(20); 100].join(" "));
+        assert_eq!(estimate_message_tokens(&message), 525 + MESSAGE_TOKEN_OVERHEAD);
+        assert_eq!(
+            count_message_tokens(&message, Some(&tokenizer)),
+            100 + MESSAGE_TOKEN_OVERHEAD
+        );
+        let messages = im::vector![AgentValue::message(message)];
+        assert!(fit_prompt(messages.clone(), 200, false, None).is_err());
+        assert!(fit_prompt(messages.clone(), 200, false, Some(&tokenizer)).is_ok());
+
+        // the chat agents pick the tokenizer with the tokenizer config
+        let mut configs = AgentConfigs::new();
+        configs.set(CONFIG_MAX_PROMPT_TOKENS.to_string(), AgentValue::integer(200));
+        assert!(prompt_messages(&configs, AgentValue::array(messages.clone())).is_err());
+        configs.set(CONFIG_TOKENIZER.to_string(), AgentValue::string(name));
+        assert!(prompt_messages(&configs, AgentValue::array(messages.clone())).is_ok());
+
+        // a tokenizer that fails to load is an error
+        let bad = std::env::temp_dir().join(format!("tokenizer-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&bad, "not a tokenizer").unwrap();
+        configs.set(
+            CONFIG_TOKENIZER.to_string(),
+            AgentValue::string(bad.to_str().unwrap()),
+        );
+        assert!(prompt_messages(&configs, AgentValue::array(messages)).is_err());
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(bad).unwrap();
+    }
+
+    #[test]
+    fn test_retry_policy_delay() {
+        let policy = RetryPolicy::new(3, 100);
+        for (retry, base) in [(1, 100), (2, 200), (3, 400)] {
+            let delay = policy.delay(retry).as_millis();
+            assert!(delay >= base && delay < base + 100, "{}: {}", retry, delay);
+        }
+        assert_eq!(RetryPolicy::new(-1, -1), RetryPolicy::new(0, 0));
+        assert_eq!(RetryPolicy::new(3, 0).delay(2), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient() {
+        let policy = RetryPolicy::new(2, 1);
+
+        // transient errors are retried until success
+        let calls = Mutex::new(0);
+        let res = retry_transient(
+            policy,
+            "Test Error",
+            || async {
+                let mut calls = calls.lock().unwrap();
+                *calls += 1;
+                if *calls < 3 { Err("busy") } else { Ok(*calls) }
+            },
+            |e: &&str| *e == "busy",
+        )
+        .await;
+        assert_eq!(res.unwrap(), 3);
+
+        // retries are bounded and counted in the error
+        let calls = Mutex::new(0);
+        let err = retry_transient(
+            policy,
+            "Test Error",
+            || async {
+                *calls.lock().unwrap() += 1;
+                Err::<(), _>("busy")
+            },
+            |e: &&str| *e == "busy",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(*calls.lock().unwrap(), 3);
+        let AgentError::IoError(msg) = err else {
+            panic!("unexpected error");
+        };
+        assert_eq!(msg, "Test Error after 3 attempts: busy");
+
+        // other errors fail fast
+        let calls = Mutex::new(0);
+        let err = retry_transient(
+            policy,
+            "Test Error",
+            || async {
+                *calls.lock().unwrap() += 1;
+                Err::<(), _>("bad request")
+            },
+            |e: &&str| *e == "busy",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(*calls.lock().unwrap(), 1);
+        let AgentError::IoError(msg) = err else {
+            panic!("unexpected error");
+        };
+        assert_eq!(msg, "Test Error: bad request");
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_limit_images() {
+        use agent_stream_kit::PhotonImage;
+        use std::sync::Arc;
+
+        let with_image = |content: &str| {
+            let mut msg = Message::user(content.to_string());
+            msg.image = Some(Arc::new(PhotonImage::new(vec![255u8; 4], 1, 1)));
+            AgentValue::message(msg)
+        };
+        let messages = im::vector![
+            with_image("1"),
+            AgentValue::message(Message::assistant("ok".to_string())),
+            with_image("2"),
+            with_image("3"),
+        ];
+        let has_image = |messages: &Vector<AgentValue>| {
+            messages
+                .iter()
+                .map(|v| v.as_message().unwrap().image.is_some())
+                .collect::<Vec<_>>()
+        };
+
+        // the most recent images are kept, and no message is removed
+        let limited = limit_images(messages.clone(), 2);
+        assert_eq!(has_image(&limited), [false, false, true, true]);
+        assert_eq!(limited[0].as_message().unwrap().content, "1");
+        assert_eq!(has_image(&limit_images(messages.clone(), 5)), has_image(&messages));
+
+        let mut configs = AgentConfigs::new();
+        configs.set(CONFIG_MAX_IMAGES.to_string(), AgentValue::integer(1));
+        let prompt = prompt_messages(&configs, AgentValue::array(messages)).unwrap().unwrap();
+        assert_eq!(has_image(&prompt), [false, false, false, true]);
+    }
+
+    #[test]
+    fn test_prompt_messages() {
+        let value = AgentValue::array(im::vector![
+            AgentValue::message(Message::system("Be brief.".to_string())),
+            AgentValue::message(Message::user("a".repeat(400))),
+            AgentValue::message(Message::assistant("b".repeat(400))),
+            AgentValue::message(Message::system("Answer in English.".to_string())),
+            AgentValue::message(Message::user("What now?".to_string())),
+        ]);
+
+        let mut configs = AgentConfigs::new();
+        configs.set(CONFIG_MERGE_SYSTEM.to_string(), AgentValue::boolean(true));
+        configs.set(CONFIG_MAX_PROMPT_TOKENS.to_string(), AgentValue::integer(100));
+        configs.set(CONFIG_TRIM_PROMPT.to_string(), AgentValue::boolean(true));
+
+        // the prompt is what is sent after merging and trimming
+        let prompt = prompt_messages(&configs, value.clone()).unwrap().unwrap();
+        assert_eq!(prompt.len(), 2);
+        let system = prompt[0].as_message().unwrap();
+        assert_eq!(system.role, "system");
+        assert_eq!(system.content, "Be brief.\n\nAnswer in English.");
+        assert_eq!(prompt[1].as_message().unwrap().content, "What now?");
+
+        configs.set(CONFIG_MAX_PROMPT_TOKENS.to_string(), AgentValue::integer(0));
+        configs.set(CONFIG_MERGE_SYSTEM.to_string(), AgentValue::boolean(false));
+        let prompt = prompt_messages(&configs, value).unwrap().unwrap();
+        assert_eq!(prompt.len(), 5);
+
+        // thinking is sent only with resend_thinking
+        let mut assistant = Message::assistant("Hi".to_string());
+        assistant.thinking = Some("Greet back".to_string());
+        let value = AgentValue::array(im::vector![
+            AgentValue::message(Message::user("Hello".to_string())),
+            AgentValue::message(assistant.clone()),
+            AgentValue::message(Message::user("How are you?".to_string())),
+        ]);
+        let prompt = prompt_messages(&configs, value.clone()).unwrap().unwrap();
+        assert_eq!(prompt[1].as_message().unwrap().thinking, None);
+        configs.set(CONFIG_RESEND_THINKING.to_string(), AgentValue::boolean(true));
+        let prompt = prompt_messages(&configs, value).unwrap().unwrap();
+        assert_eq!(
+            prompt[1].as_message().unwrap().thinking.as_deref(),
+            Some("Greet back")
+        );
+        assert_eq!(
+            content_with_thinking(prompt[1].as_message().unwrap()),
+            "<think>\nGreet back\n</think>\n\nHi"
+        );
+
+        // a legacy function result is answered like a tool result
+        let value = AgentValue::array(im::vector![
+            AgentValue::message(Message::user("What is the temperature?".to_string())),
+            AgentValue::message(Message::assistant(String::new())),
+            AgentValue::message(Message::new(
+                "function".to_string(),
+                "{\"temperature\": 21}".to_string()
+            )),
+        ]);
+        let prompt = prompt_messages(&configs, value).unwrap().unwrap();
+        assert_eq!(prompt.len(), 3);
+        assert_eq!(prompt[2].as_message().unwrap().role, "function");
+
+        // nothing to answer
+        let assistant = AgentValue::message(Message::assistant("Hi".to_string()));
+        assert!(prompt_messages(&configs, assistant.clone()).unwrap().is_none());
+        configs.set(CONFIG_ERROR_ON_EMPTY.to_string(), AgentValue::boolean(true));
+        assert!(prompt_messages(&configs, assistant).is_err());
+    }
+
+    #[test]
+    fn test_validate_messages() {
+        let user = |c: &str| AgentValue::message(Message::user(c.to_string()));
+        let assistant = |c: &str| AgentValue::message(Message::assistant(c.to_string()));
+
+        // empty array
+        let err = validate_messages(Vector::new(), false).unwrap_err();
+        assert!(matches!(err, AgentError::InvalidValue(msg) if msg == "No messages to send"));
+        assert!(validate_messages(Vector::new(), true).is_err());
+
+        // trailing assistant message
+        let messages = im::vector![user("Hi"), assistant("Hello"), user("Bye"), assistant("See you")];
+        let err = validate_messages(messages.clone(), false).unwrap_err();
+        assert!(
+            matches!(err, AgentError::InvalidValue(msg) if msg.contains("must be a user or tool"))
+        );
+        let repaired = validate_messages(messages, true).unwrap();
+        assert_eq!(repaired.len(), 3);
+        assert_eq!(repaired[2].as_message().unwrap().content, "Bye");
+
+        // consecutive user messages are merged, an unanswered tool result dropped
+        let messages = im::vector![
+            user("Hi"),
+            AgentValue::message(Message::tool("search".to_string(), "[]".to_string())),
+            user("Anyone?"),
+        ];
+        assert!(validate_messages(messages.clone(), false).is_err());
+        let repaired = validate_messages(messages, true).unwrap();
+        assert_eq!(repaired.len(), 1);
+        assert_eq!(repaired[0].as_message().unwrap().content, "Hi\n\nAnyone?");
+
+        // a valid conversation is kept as is
+        let messages = im::vector![user("Hi"), assistant("Hello"), user("Bye")];
+        assert_eq!(validate_messages(messages.clone(), false).unwrap(), messages);
+
+        // prompt_messages validates only with validate_messages
+        let mut configs = AgentConfigs::new();
+        let value = AgentValue::array(im::vector![user("Hi"), user("Anyone?")]);
+        assert_eq!(prompt_messages(&configs, value.clone()).unwrap().unwrap().len(), 2);
+        configs.set(CONFIG_VALIDATE_MESSAGES.to_string(), AgentValue::boolean(true));
+        assert!(prompt_messages(&configs, value.clone()).is_err());
+        configs.set(CONFIG_REPAIR_MESSAGES.to_string(), AgentValue::boolean(true));
+        assert_eq!(prompt_messages(&configs, value).unwrap().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let marker = "[…]";
+        assert_eq!(truncate("short", 10, TruncateStrategy::Tail, marker), "short");
+
+        let text = "日本語のテキストです";
+        assert_eq!(
+            truncate(text, 4, TruncateStrategy::Tail, marker),
+            "日本語の[…]"
+        );
+        assert_eq!(
+            truncate(text, 4, TruncateStrategy::Head, marker),
+            "[…]ストです"
+        );
+        assert_eq!(
+            truncate(text, 5, TruncateStrategy::Middle, marker),
+            "日本語[…]です"
+        );
+        assert_eq!(truncate(text, 0, TruncateStrategy::Middle, marker), "[…]");
+
+        assert_eq!(
+            "MIDDLE".parse::<TruncateStrategy>().unwrap(),
+            TruncateStrategy::Middle
+        );
+        assert_eq!(
+            "".parse::<TruncateStrategy>().unwrap(),
+            TruncateStrategy::Tail
+        );
+        assert!("both".parse::<TruncateStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_reply_message_id() {
+        use agent_stream_kit::ToolCallFunction;
+
+        let tool_turn = |id: &str| {
+            let mut message = Message::assistant("".to_string());
+            message.id = Some(id.to_string());
+            message.tool_calls = Some(im::vector![ToolCall {
+                function: ToolCallFunction {
+                    id: None,
+                    name: "get_weather".to_string(),
+                    parameters: serde_json::json!({"city": "Tokyo"}),
+                },
+            }]);
+            AgentValue::message(message)
+        };
+        let tool_result = AgentValue::message(Message::tool(
+            "get_weather".to_string(),
+            "sunny".to_string(),
+        ));
+
+        // A new question starts a new turn
+        let mut messages = im::vector![AgentValue::message(Message::user("Weather?".to_string()))];
+        let first = reply_message_id(&messages, true);
+        assert_eq!(turn_id(&first), first);
+
+        // Each tool round continues the turn with the next round
+        messages.push_back(tool_turn(&first));
+        messages.push_back(tool_result.clone());
+        let second = reply_message_id(&messages, true);
+        assert_eq!(second, format!("{}#1", first));
+        messages.push_back(tool_turn(&second));
+        messages.push_back(tool_result.clone());
+        let third = reply_message_id(&messages, true);
+        assert_eq!(third, format!("{}#2", first));
+        assert_eq!(turn_id(&third), turn_id(&second));
+
+        // Without stable_turn_id every reply gets its own id
+        assert_ne!(turn_id(&reply_message_id(&messages, false)), first);
+
+        // A user message after the tool round starts a new turn
+        messages.push_back(AgentValue::message(Message::assistant("Sunny.".to_string())));
+        messages.push_back(AgentValue::message(Message::user("Thanks".to_string())));
+        assert_ne!(turn_id(&reply_message_id(&messages, true)), first);
+    }
+}
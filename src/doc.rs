@@ -1,22 +1,35 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex};
 use std::vec;
 
 use agent_stream_kit::{
     ASKit, Agent, AgentContext, AgentData, AgentError, AgentOutput, AgentSpec, AgentValue, AsAgent,
     askit_agent, async_trait,
 };
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use encoding_rs::Encoding;
 use icu_normalizer::{ComposingNormalizer, ComposingNormalizerBorrowed};
 use im::vector;
-use text_splitter::{ChunkConfig, TextSplitter};
+use text_splitter::{Characters, ChunkConfig, TextSplitter};
 use tokenizers::Tokenizer;
 
 const CATEGORY: &str = "LLM/Doc";
 
+const PIN_BYTES: &str = "bytes";
 const PIN_CHUNKS: &str = "chunks";
 const PIN_DOC: &str = "doc";
 const PIN_STRING: &str = "string";
 
+const CONFIG_AUTO_TOKENIZER: &str = "auto_tokenizer";
+const CONFIG_ENCODING: &str = "encoding";
+const CONFIG_FALLBACK_TOKENIZER: &str = "fallback_tokenizer";
 const CONFIG_MAX_CHARACTERS: &str = "max_characters";
+const CONFIG_MAX_CHUNKS: &str = "max_chunks";
 const CONFIG_MAX_TOKENS: &str = "max_tokens";
+const CONFIG_MODEL: &str = "model";
+const CONFIG_OVERLAP: &str = "overlap";
+const CONFIG_STREAM_CHUNKS: &str = "stream_chunks";
 const CONFIG_TOKENIZER: &str = "tokenizer";
 
 #[askit_agent(
@@ -98,24 +111,310 @@ impl AsAgent for NFKCAgent {
     }
 }
 
+pub const TEXT_ENCODING_UTF8: &str = "utf-8";
+pub const TEXT_ENCODING_UTF16LE: &str = "utf-16le";
+pub const TEXT_ENCODING_UTF16BE: &str = "utf-16be";
+pub const TEXT_ENCODING_SHIFT_JIS: &str = "shift_jis";
+pub const TEXT_ENCODING_WINDOWS_1252: &str = "windows-1252";
+
+/// Text encodings the Decode Text agent can decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    ShiftJis,
+    /// Also used for Latin-1, as in web browsers.
+    Windows1252,
+}
+
+impl TextEncoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TextEncoding::Utf8 => TEXT_ENCODING_UTF8,
+            TextEncoding::Utf16Le => TEXT_ENCODING_UTF16LE,
+            TextEncoding::Utf16Be => TEXT_ENCODING_UTF16BE,
+            TextEncoding::ShiftJis => TEXT_ENCODING_SHIFT_JIS,
+            TextEncoding::Windows1252 => TEXT_ENCODING_WINDOWS_1252,
+        }
+    }
+
+    fn encoding(&self) -> &'static Encoding {
+        match self {
+            TextEncoding::Utf8 => encoding_rs::UTF_8,
+            TextEncoding::Utf16Le => encoding_rs::UTF_16LE,
+            TextEncoding::Utf16Be => encoding_rs::UTF_16BE,
+            TextEncoding::ShiftJis => encoding_rs::SHIFT_JIS,
+            TextEncoding::Windows1252 => encoding_rs::WINDOWS_1252,
+        }
+    }
+
+    /// Decode the bytes, skipping a byte order mark of this encoding.
+    ///
+    /// Malformed input is an error rather than being decoded with replacement characters.
+    pub fn decode(&self, bytes: &[u8]) -> Result<String, AgentError> {
+        let encoding = self.encoding();
+        let bytes = match Encoding::for_bom(bytes) {
+            Some((bom_encoding, bom_len)) if bom_encoding == encoding => &bytes[bom_len..],
+            _ => bytes,
+        };
+        encoding
+            .decode_without_bom_handling_and_without_replacement(bytes)
+            .map(|text| text.into_owned())
+            .ok_or_else(|| AgentError::InvalidValue(format!("Input is not valid {}", self.name())))
+    }
+}
+
+impl FromStr for TextEncoding {
+    type Err = AgentError;
+
+    /// Parse a WHATWG encoding label, such as "utf8", "sjis" or "latin1".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unsupported = || AgentError::InvalidConfig(format!("Unsupported encoding: {}", s));
+        let encoding = Encoding::for_label(s.trim().as_bytes()).ok_or_else(unsupported)?;
+        [
+            TextEncoding::Utf8,
+            TextEncoding::Utf16Le,
+            TextEncoding::Utf16Be,
+            TextEncoding::ShiftJis,
+            TextEncoding::Windows1252,
+        ]
+        .into_iter()
+        .find(|e| e.encoding() == encoding)
+        .ok_or_else(unsupported)
+    }
+}
+
+/// Detect the encoding of the bytes.
+///
+/// A byte order mark decides the encoding. Otherwise valid UTF-8 is UTF-8,
+/// text that looks like Shift_JIS is Shift_JIS, and anything else
+/// windows-1252, which accepts any bytes.
+pub fn detect_encoding(bytes: &[u8]) -> TextEncoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        if encoding == encoding_rs::UTF_16LE {
+            return TextEncoding::Utf16Le;
+        }
+        if encoding == encoding_rs::UTF_16BE {
+            return TextEncoding::Utf16Be;
+        }
+        return TextEncoding::Utf8;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return TextEncoding::Utf8;
+    }
+    if looks_like_shift_jis(bytes) {
+        return TextEncoding::ShiftJis;
+    }
+    TextEncoding::Windows1252
+}
+
+/// Whether the bytes are well-formed Shift_JIS with at least one double-byte
+/// character led by 0x81-0x9F, a range windows-1252 text rarely uses.
+fn looks_like_shift_jis(bytes: &[u8]) -> bool {
+    let mut low_lead = false;
+    let mut iter = bytes.iter();
+    while let Some(&b) = iter.next() {
+        match b {
+            0x00..=0x7F | 0xA1..=0xDF => {}
+            0x81..=0x9F | 0xE0..=0xFC => {
+                let Some(&trail) = iter.next() else {
+                    return false;
+                };
+                if !matches!(trail, 0x40..=0x7E | 0x80..=0xFC) {
+                    return false;
+                }
+                low_lead |= b <= 0x9F;
+            }
+            _ => return false,
+        }
+    }
+    low_lead
+}
+
+/// Decode the bytes with the given encoding, or the detected one if None.
+pub fn decode_text(
+    bytes: &[u8],
+    encoding: Option<TextEncoding>,
+) -> Result<(String, TextEncoding), AgentError> {
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => detect_encoding(bytes),
+    };
+    Ok((encoding.decode(bytes)?, encoding))
+}
+
+/// The bytes of a value: an array of integers from 0 to 255, or a base64 string,
+/// which may be a data URL.
+fn bytes_from_value(value: &AgentValue) -> Result<Vec<u8>, AgentError> {
+    if let Some(array) = value.as_array() {
+        return array
+            .iter()
+            .map(|v| {
+                v.as_i64()
+                    .and_then(|b| u8::try_from(b).ok())
+                    .ok_or_else(|| {
+                        AgentError::InvalidValue("Bytes must be integers from 0 to 255".to_string())
+                    })
+            })
+            .collect();
+    }
+    if let Some(s) = value.as_str() {
+        let data = s.split_once(";base64,").map_or(s, |(_, data)| data);
+        return STANDARD
+            .decode(data.trim())
+            .map_err(|e| AgentError::InvalidValue(format!("Invalid base64 bytes: {}", e)));
+    }
+    Err(AgentError::InvalidValue(
+        "Bytes must be an array of integers or a base64 string".to_string(),
+    ))
+}
+
+#[askit_agent(
+    title="Decode Text",
+    category=CATEGORY,
+    inputs=[PIN_BYTES, PIN_DOC],
+    outputs=[PIN_STRING, PIN_DOC],
+    string_config(name=CONFIG_ENCODING, title="Encoding"),
+)]
+pub struct DecodeTextAgent {
+    data: AgentData,
+}
+
+#[async_trait]
+impl AsAgent for DecodeTextAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        Ok(Self {
+            data: AgentData::new(askit, id, spec),
+        })
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        // empty = detect
+        let config_encoding = self.configs()?.get_string_or_default(CONFIG_ENCODING);
+        let encoding = if config_encoding.trim().is_empty() {
+            None
+        } else {
+            Some(config_encoding.parse::<TextEncoding>()?)
+        };
+
+        if pin == PIN_BYTES {
+            let (text, _) = decode_text(&bytes_from_value(&value)?, encoding)?;
+            return self
+                .output(ctx, PIN_STRING, AgentValue::string(text))
+                .await;
+        }
+
+        if pin == PIN_DOC {
+            let Some(bytes) = value.get("bytes") else {
+                return Err(AgentError::InvalidValue(
+                    "Input must be an object with a bytes field".to_string(),
+                ));
+            };
+            let (text, encoding) = decode_text(&bytes_from_value(bytes)?, encoding)?;
+            let mut output = value.clone();
+            if let Some(obj) = output.as_object_mut() {
+                obj.remove("bytes");
+            }
+            output.set("text".to_string(), AgentValue::string(text))?;
+            output.set(
+                "encoding".to_string(),
+                AgentValue::string(encoding.name()),
+            )?;
+            return self.output(ctx, PIN_DOC, output).await;
+        }
+
+        Err(AgentError::InvalidPin(pin))
+    }
+}
+
 #[askit_agent(
     title="Split Text",
     category=CATEGORY,
     inputs=[PIN_STRING, PIN_DOC],
     outputs=[PIN_CHUNKS, PIN_DOC],
     integer_config(name=CONFIG_MAX_CHARACTERS, default=512),
+    integer_config(name=CONFIG_OVERLAP, title="Overlap"),
+    boolean_config(name=CONFIG_STREAM_CHUNKS, title="Stream Chunks"),
+    integer_config(name=CONFIG_MAX_CHUNKS, title="Max Chunks"),
 )]
 pub struct SplitTextAgent {
     data: AgentData,
 }
 
-impl SplitTextAgent {
-    fn split_into_chunks(&self, text: &str, max_characters: usize) -> Vec<(usize, String)> {
-        TextSplitter::new(max_characters)
-            .chunk_indices(text)
-            .map(|(offset, chunk)| (offset, chunk.to_string()))
-            .collect()
+fn split_into_chunks<'a>(
+    splitter: &'a TextSplitter<Characters>,
+    text: &'a str,
+) -> impl Iterator<Item = (usize, String)> + 'a {
+    splitter
+        .chunk_indices(text)
+        .map(|(offset, chunk)| (offset, chunk.to_string()))
+}
+
+/// Chunks of up to `size`, each starting with the last `overlap` of the previous one.
+///
+/// The overlap must be less than the chunk size; `size_name` names the size config
+/// in the error.
+fn chunk_config(
+    size: usize,
+    overlap: usize,
+    size_name: &str,
+) -> Result<ChunkConfig<Characters>, AgentError> {
+    ChunkConfig::new(size).with_overlap(overlap).map_err(|_| {
+        AgentError::InvalidConfig(format!(
+            "overlap ({}) must be less than {} ({})",
+            overlap, size_name, size
+        ))
+    })
+}
+
+/// Collect at most `max_chunks` chunks (0 = unlimited).
+///
+/// Also returns whether any chunk was left out.
+fn take_chunks<T>(mut chunks: impl Iterator<Item = T>, max_chunks: usize) -> (Vec<T>, bool) {
+    if max_chunks == 0 {
+        return (chunks.collect(), false);
     }
+    let taken = chunks.by_ref().take(max_chunks).collect();
+    (taken, chunks.next().is_some())
+}
+
+/// Mark the last chunk doc when the chunks were capped by max_chunks.
+fn set_truncated(doc: &mut AgentValue) -> Result<(), AgentError> {
+    doc.set("truncated".to_string(), AgentValue::boolean(true))
+}
+
+/// The values emitted on chunks in stream mode, one per chunk.
+///
+/// Each is an array holding a single `[offset, text]` pair, the same shape as the
+/// batch output, so the chunks pin of the Embeddings agents accepts it.
+fn streamed_chunk_values<'a>(
+    splitter: &'a TextSplitter<Characters>,
+    text: &'a str,
+    limit: usize,
+) -> impl Iterator<Item = AgentValue> + 'a {
+    split_into_chunks(splitter, text)
+        .take(limit)
+        .map(|(offset, chunk)| AgentValue::array(vector![chunk_value(offset, chunk)]))
+}
+
+fn chunk_value(offset: usize, chunk: String) -> AgentValue {
+    AgentValue::array(vector![
+        AgentValue::integer(offset as i64),
+        AgentValue::string(chunk)
+    ])
+}
+
+fn chunk_doc(doc: &AgentValue, offset: usize, chunk: String) -> Result<AgentValue, AgentError> {
+    let mut output = doc.clone();
+    output.set("offset".to_string(), AgentValue::integer(offset as i64))?;
+    output.set("text".to_string(), AgentValue::string(chunk))?;
+    Ok(output)
 }
 
 #[async_trait]
@@ -140,27 +439,38 @@ impl AsAgent for SplitTextAgent {
                 "max_characters must be greater than 0".to_string(),
             ));
         }
+        let overlap = self.configs()?.get_integer_or_default(CONFIG_OVERLAP).max(0) as usize;
+        let config = chunk_config(max_characters, overlap, CONFIG_MAX_CHARACTERS)?;
+        let stream_chunks = self.configs()?.get_bool_or_default(CONFIG_STREAM_CHUNKS);
+        let max_chunks = self.configs()?.get_integer_or_default(CONFIG_MAX_CHUNKS).max(0) as usize;
 
         if pin == PIN_STRING {
             let text = value.as_str().unwrap_or("");
             if text.is_empty() {
+                if stream_chunks {
+                    return Ok(());
+                }
                 return self
                     .output(ctx.clone(), PIN_CHUNKS, AgentValue::array_default())
                     .await;
             }
-            let chunks = self.split_into_chunks(text, max_characters);
+            let splitter = TextSplitter::new(config);
+            let limit = if max_chunks > 0 { max_chunks } else { usize::MAX };
+            if stream_chunks {
+                // Emit each chunk as soon as it is produced instead of collecting them all
+                for chunks in streamed_chunk_values(&splitter, text, limit) {
+                    self.output(ctx.clone(), PIN_CHUNKS, chunks).await?;
+                }
+                return Ok(());
+            }
+            let (chunks, _) = take_chunks(split_into_chunks(&splitter, text), max_chunks);
             self.output(
                 ctx.clone(),
                 PIN_CHUNKS,
                 AgentValue::array(
                     chunks
                         .into_iter()
-                        .map(|(offset, chunk)| {
-                            AgentValue::array(vector![
-                                AgentValue::integer(offset as i64),
-                                AgentValue::string(chunk)
-                            ])
-                        })
+                        .map(|(offset, chunk)| chunk_value(offset, chunk))
                         .collect::<Vec<_>>()
                         .into(),
                 ),
@@ -173,31 +483,42 @@ impl AsAgent for SplitTextAgent {
             if value.is_object() {
                 let text = value.get_str("text").unwrap_or("");
                 if text.is_empty() {
+                    if stream_chunks {
+                        return Ok(());
+                    }
                     return self
                         .output(ctx.clone(), PIN_DOC, AgentValue::array_default())
                         .await;
                 }
-                let chunks = self.split_into_chunks(text, max_characters);
-                self.output(
-                    ctx,
-                    PIN_DOC,
-                    AgentValue::array(
-                        chunks
-                            .into_iter()
-                            .map(|(offset, chunk)| {
-                                let mut output = value.clone();
-                                output.set(
-                                    "offset".to_string(),
-                                    AgentValue::integer(offset as i64),
-                                )?;
-                                output.set("text".to_string(), AgentValue::string(chunk))?;
-                                Ok(output)
-                            })
-                            .collect::<Result<Vec<_>, AgentError>>()?
-                            .into(),
-                    ),
-                )
-                .await?;
+                let splitter = TextSplitter::new(config);
+                if stream_chunks {
+                    let mut chunks = split_into_chunks(&splitter, text).peekable();
+                    let mut count = 0;
+                    while let Some((offset, chunk)) = chunks.next() {
+                        count += 1;
+                        let mut output = chunk_doc(&value, offset, chunk)?;
+                        let capped = count == max_chunks;
+                        if capped && chunks.peek().is_some() {
+                            set_truncated(&mut output)?;
+                        }
+                        self.output(ctx.clone(), PIN_DOC, output).await?;
+                        if capped {
+                            break;
+                        }
+                    }
+                    return Ok(());
+                }
+                let (chunks, truncated) =
+                    take_chunks(split_into_chunks(&splitter, text), max_chunks);
+                let mut docs = chunks
+                    .into_iter()
+                    .map(|(offset, chunk)| chunk_doc(&value, offset, chunk))
+                    .collect::<Result<Vec<_>, AgentError>>()?;
+                if truncated && let Some(last) = docs.last_mut() {
+                    set_truncated(last)?;
+                }
+                self.output(ctx, PIN_DOC, AgentValue::array(docs.into()))
+                    .await?;
             }
             return Ok(());
         }
@@ -206,17 +527,45 @@ impl AsAgent for SplitTextAgent {
     }
 }
 
+// Tokenizers paired with embedding / LLM model names
+const MODEL_TOKENIZERS: [(&str, &str); 7] = [
+    ("all-minilm", "sentence-transformers/all-MiniLM-L6-v2"),
+    ("bge-m3", "BAAI/bge-m3"),
+    ("granite-embedding", "ibm-granite/granite-embedding-278m-multilingual"),
+    ("mxbai-embed-large", "mixedbread-ai/mxbai-embed-large-v1"),
+    ("nomic-embed-text", "nomic-ai/nomic-embed-text-v1.5"),
+    ("nomic-embed-text-v2-moe", "nomic-ai/nomic-embed-text-v2-moe"),
+    ("snowflake-arctic-embed2", "Snowflake/snowflake-arctic-embed-l-v2.0"),
+];
+
+/// Find the tokenizer paired with the given model name.
+///
+/// The tag (`:latest`) and namespace (`nomic-ai/`) of the model name are ignored.
+fn tokenizer_for_model(model: &str) -> Option<&'static str> {
+    let name = model.split(':').next().unwrap_or_default();
+    let name = name.rsplit('/').next().unwrap_or_default().to_lowercase();
+    MODEL_TOKENIZERS
+        .iter()
+        .find(|(m, _)| *m == name)
+        .map(|(_, tokenizer)| *tokenizer)
+}
+
 #[askit_agent(
     title="Split Text by Tokens",
     category=CATEGORY,
     inputs=[PIN_STRING, PIN_DOC],
     outputs=[PIN_CHUNKS, PIN_DOC],
     integer_config(name=CONFIG_MAX_TOKENS, default=500),
-    string_config(name=CONFIG_TOKENIZER, default="nomic-ai/nomic-embed-text-v2-moe")
+    integer_config(name=CONFIG_OVERLAP, title="Overlap"),
+    string_config(name=CONFIG_TOKENIZER, default="nomic-ai/nomic-embed-text-v2-moe"),
+    boolean_config(name=CONFIG_AUTO_TOKENIZER, title="Auto Tokenizer"),
+    string_config(name=CONFIG_FALLBACK_TOKENIZER, title="Fallback Tokenizer"),
+    string_config(name=CONFIG_MODEL),
+    integer_config(name=CONFIG_MAX_CHUNKS, title="Max Chunks"),
 )]
 pub struct SplitTextByTokensAgent {
     data: AgentData,
-    splitter: Option<TextSplitter<Tokenizer>>,
+    splitter: Option<TextSplitter<Arc<Tokenizer>>>,
 }
 
 impl SplitTextByTokensAgent {
@@ -224,22 +573,24 @@ impl SplitTextByTokensAgent {
         &mut self,
         text: &str,
         max_tokens: usize,
+        overlap: usize,
         tokenizer_model: &str,
-    ) -> Result<Vec<(usize, String)>, AgentError> {
+        fallback_tokenizer: &str,
+        max_chunks: usize,
+    ) -> Result<(Vec<(usize, String)>, bool), AgentError> {
         if self.splitter.is_none() {
-            let tokenizer = Tokenizer::from_pretrained(tokenizer_model, None).map_err(|e| {
-                AgentError::InvalidConfig(format!("Failed to load tokenizer: {}", e))
-            })?;
-            let splitter = TextSplitter::new(ChunkConfig::new(max_tokens).with_sizer(tokenizer));
+            let tokenizer = load_tokenizer_with_fallback(tokenizer_model, fallback_tokenizer)?;
+            let config = chunk_config(max_tokens, overlap, CONFIG_MAX_TOKENS)?;
+            let splitter = TextSplitter::new(config.with_sizer(tokenizer));
             self.splitter = Some(splitter);
         }
-        Ok(self
+        let chunks = self
             .splitter
             .as_ref()
             .unwrap()
             .chunk_indices(text)
-            .map(|(offset, chunk)| (offset, chunk.to_string()))
-            .collect())
+            .map(|(offset, chunk)| (offset, chunk.to_string()));
+        Ok(take_chunks(chunks, max_chunks))
     }
 }
 
@@ -274,13 +625,24 @@ impl AsAgent for SplitTextByTokensAgent {
                 "max_tokens must be greater than 0".to_string(),
             ));
         }
+        let overlap = self.configs()?.get_integer_or_default(CONFIG_OVERLAP).max(0) as usize;
+        // checked before loading the tokenizer
+        chunk_config(max_tokens, overlap, CONFIG_MAX_TOKENS)?;
 
-        let tokenizer_model = self.configs()?.get_string_or_default(CONFIG_TOKENIZER);
+        let mut tokenizer_model = self.configs()?.get_string_or_default(CONFIG_TOKENIZER);
+        if self.configs()?.get_bool_or_default(CONFIG_AUTO_TOKENIZER)
+            && let Some(tokenizer) =
+                tokenizer_for_model(&self.configs()?.get_string_or_default(CONFIG_MODEL))
+        {
+            tokenizer_model = tokenizer.to_string();
+        }
         if tokenizer_model.is_empty() {
             return Err(AgentError::InvalidConfig(
                 "tokenizer must be a non-empty string".to_string(),
             ));
         }
+        let fallback_tokenizer = self.configs()?.get_string_or_default(CONFIG_FALLBACK_TOKENIZER);
+        let max_chunks = self.configs()?.get_integer_or_default(CONFIG_MAX_CHUNKS).max(0) as usize;
 
         if pin == PIN_STRING {
             let text = value.as_str().unwrap_or("");
@@ -290,7 +652,14 @@ impl AsAgent for SplitTextByTokensAgent {
                     .await;
             }
 
-            let chunks = self.split_into_chunks(text, max_tokens, &tokenizer_model)?;
+            let (chunks, _) = self.split_into_chunks(
+                text,
+                max_tokens,
+                overlap,
+                &tokenizer_model,
+                &fallback_tokenizer,
+                max_chunks,
+            )?;
             self.output(
                 ctx.clone(),
                 PIN_CHUNKS,
@@ -319,27 +688,348 @@ impl AsAgent for SplitTextByTokensAgent {
                     .await;
             }
 
-            let chunks = self.split_into_chunks(text, max_tokens, &tokenizer_model)?;
-            self.output(
-                ctx,
-                PIN_DOC,
-                AgentValue::array(
-                    chunks
-                        .into_iter()
-                        .map(|(offset, chunk)| {
-                            let mut output = value.clone();
-                            output.set("offset".to_string(), AgentValue::integer(offset as i64))?;
-                            output.set("text".to_string(), AgentValue::string(chunk))?;
-                            Ok(output)
-                        })
-                        .collect::<Result<Vec<_>, AgentError>>()?
-                        .into(),
-                ),
-            )
-            .await?;
+            let (chunks, truncated) = self.split_into_chunks(
+                text,
+                max_tokens,
+                overlap,
+                &tokenizer_model,
+                &fallback_tokenizer,
+                max_chunks,
+            )?;
+            let mut docs = chunks
+                .into_iter()
+                .map(|(offset, chunk)| {
+                    let mut output = value.clone();
+                    output.set("offset".to_string(), AgentValue::integer(offset as i64))?;
+                    output.set("text".to_string(), AgentValue::string(chunk))?;
+                    Ok(output)
+                })
+                .collect::<Result<Vec<_>, AgentError>>()?;
+            if truncated && let Some(last) = docs.last_mut() {
+                set_truncated(last)?;
+            }
+            self.output(ctx, PIN_DOC, AgentValue::array(docs.into()))
+                .await?;
             return Ok(());
         }
 
         Err(AgentError::InvalidPin(pin))
     }
 }
+
+/// Error text that suggests the tokenizer could not be fetched from the Hugging Face Hub.
+const TOKENIZER_NETWORK_ERRORS: [&str; 12] = [
+    "network",
+    "connect",
+    "dns",
+    "resolve",
+    "timed out",
+    "timeout",
+    "request",
+    "http",
+    "401",
+    "403",
+    "unauthorized",
+    "forbidden",
+];
+
+/// Tokenizers loaded so far, keyed by name or path.
+static TOKENIZERS: LazyLock<Mutex<HashMap<String, Arc<Tokenizer>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Load a tokenizer from a local tokenizer.json path or the Hugging Face Hub.
+///
+/// Tokenizers are cached for the whole process, so agents splitting or counting
+/// with the same tokenizer load it only once. A failed load is not cached and is tried
+/// again next time.
+pub(crate) fn load_tokenizer(tokenizer: &str) -> Result<Arc<Tokenizer>, AgentError> {
+    if let Some(cached) = TOKENIZERS.lock().unwrap().get(tokenizer) {
+        return Ok(cached.clone());
+    }
+    // Loaded without holding the lock, as a download can take a while
+    let loaded = Arc::new(read_tokenizer(tokenizer)?);
+    Ok(TOKENIZERS
+        .lock()
+        .unwrap()
+        .entry(tokenizer.to_string())
+        .or_insert(loaded)
+        .clone())
+}
+
+fn read_tokenizer(tokenizer: &str) -> Result<Tokenizer, AgentError> {
+    if std::path::Path::new(tokenizer).is_file() {
+        return Tokenizer::from_file(tokenizer)
+            .map_err(|e| tokenizer_load_error(&e.to_string()));
+    }
+    Tokenizer::from_pretrained(tokenizer, None).map_err(|e| tokenizer_load_error(&e.to_string()))
+}
+
+/// Load the tokenizer, or the fallback tokenizer if it fails and one is given.
+fn load_tokenizer_with_fallback(
+    tokenizer: &str,
+    fallback: &str,
+) -> Result<Arc<Tokenizer>, AgentError> {
+    let err = match load_tokenizer(tokenizer) {
+        Ok(tokenizer) => return Ok(tokenizer),
+        Err(err) if fallback.is_empty() => return Err(err),
+        Err(err) => err,
+    };
+    log::warn!(
+        "Using fallback tokenizer '{}' instead of '{}': {}",
+        fallback,
+        tokenizer,
+        err
+    );
+    load_tokenizer(fallback).map_err(|e| {
+        AgentError::InvalidConfig(format!(
+            "Failed to load tokenizer '{}' and fallback tokenizer '{}': {}",
+            tokenizer, fallback, e
+        ))
+    })
+}
+
+fn tokenizer_load_error(err: &str) -> AgentError {
+    let lower = err.to_lowercase();
+    if TOKENIZER_NETWORK_ERRORS.iter().any(|k| lower.contains(k)) {
+        return AgentError::InvalidConfig(format!(
+            "Failed to load tokenizer: {} (the tokenizer could not be downloaded; \
+             set tokenizer to a local tokenizer.json path, or set HF_HUB_OFFLINE=1 to use the cached copy)",
+            err
+        ));
+    }
+    AgentError::InvalidConfig(format!("Failed to load tokenizer: {}", err))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_text_stream_chunks() {
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(100);
+        let splitter = TextSplitter::new(64);
+
+        // batch mode
+        let batch = AgentValue::array(
+            split_into_chunks(&splitter, &text)
+                .map(|(offset, chunk)| chunk_value(offset, chunk))
+                .collect::<Vec<_>>()
+                .into(),
+        );
+
+        // stream mode emits each chunk as an array of one (offset, string) pair
+        let streamed = streamed_chunk_values(&splitter, &text, usize::MAX).collect::<Vec<_>>();
+        let batch = batch.as_array().unwrap();
+        assert!(batch.len() > 1);
+        assert_eq!(batch.len(), streamed.len());
+        for (b, s) in batch.iter().zip(streamed.iter()) {
+            let pairs = s.as_array().unwrap();
+            assert_eq!(pairs.len(), 1);
+            assert_eq!(&pairs[0], b);
+            assert!(pairs[0].as_array().unwrap()[0].as_i64().is_some());
+        }
+
+        // and stops at the limit
+        assert_eq!(streamed_chunk_values(&splitter, &text, 2).count(), 2);
+    }
+
+    #[test]
+    fn test_chunk_overlap() {
+        let text = "lorem ipsum dolor sit amet ".repeat(20);
+        let splitter = TextSplitter::new(chunk_config(32, 12, CONFIG_MAX_CHARACTERS).unwrap());
+        let chunks: Vec<_> = split_into_chunks(&splitter, &text).collect();
+        assert!(chunks.len() > 1);
+        for (offset, chunk) in &chunks {
+            // offsets still point into the original text
+            assert_eq!(&text[*offset..*offset + chunk.len()], chunk);
+        }
+        for pair in chunks.windows(2) {
+            let ((prev, prev_chunk), (next, _)) = (&pair[0], &pair[1]);
+            assert!(next < &(prev + prev_chunk.len()));
+        }
+
+        let err = chunk_config(64, 64, CONFIG_MAX_CHARACTERS).unwrap_err();
+        assert!(matches!(err, AgentError::InvalidConfig(msg) if msg.contains("max_characters")));
+    }
+
+    #[test]
+    fn test_tokenizer_for_model() {
+        assert_eq!(
+            tokenizer_for_model("nomic-embed-text-v2-moe:latest"),
+            Some("nomic-ai/nomic-embed-text-v2-moe")
+        );
+        assert_eq!(
+            tokenizer_for_model("nomic-embed-text"),
+            Some("nomic-ai/nomic-embed-text-v1.5")
+        );
+        assert_eq!(
+            tokenizer_for_model("mixedbread-ai/mxbai-embed-large"),
+            Some("mixedbread-ai/mxbai-embed-large-v1")
+        );
+        assert_eq!(tokenizer_for_model("unknown-model"), None);
+        assert_eq!(tokenizer_for_model(""), None);
+    }
+
+    #[test]
+    fn test_take_chunks() {
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(100);
+        let splitter = TextSplitter::new(64);
+        let total = split_into_chunks(&splitter, &text).count();
+        assert!(total > 3);
+
+        let (chunks, truncated) = take_chunks(split_into_chunks(&splitter, &text), 0);
+        assert_eq!(chunks.len(), total);
+        assert!(!truncated);
+
+        let (chunks, truncated) = take_chunks(split_into_chunks(&splitter, &text), 3);
+        assert_eq!(chunks.len(), 3);
+        assert!(truncated);
+
+        let (chunks, truncated) = take_chunks(split_into_chunks(&splitter, &text), total);
+        assert_eq!(chunks.len(), total);
+        assert!(!truncated);
+
+        let mut doc = chunk_doc(
+            &AgentValue::object(im::hashmap! {"title".into() => AgentValue::string("t")}),
+            chunks[0].0,
+            chunks[0].1.clone(),
+        )
+        .unwrap();
+        set_truncated(&mut doc).unwrap();
+        assert_eq!(doc.get_bool("truncated"), Some(true));
+    }
+
+    #[test]
+    fn test_tokenizer_load_error() {
+        let err = tokenizer_load_error(
+            "request error: error sending request for url (https://huggingface.co/...)",
+        );
+        match err {
+            AgentError::InvalidConfig(msg) => {
+                assert!(msg.starts_with("Failed to load tokenizer: request error"));
+                assert!(msg.contains("HF_HUB_OFFLINE"));
+                assert!(msg.contains("local tokenizer.json path"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let err = tokenizer_load_error("expected value at line 1 column 1");
+        assert!(matches!(err, AgentError::InvalidConfig(msg) if !msg.contains("HF_HUB_OFFLINE")));
+
+        let path = std::env::temp_dir().join(format!("tokenizer-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "not a tokenizer").unwrap();
+        assert!(load_tokenizer(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// A minimal tokenizer.json that loads without a download.
+    pub(crate) fn word_level_tokenizer() -> String {
+        serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"[UNK]": 0, "the": 1, "fox": 2},
+                "unk_token": "[UNK]"
+            }
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_fallback_tokenizer() {
+        let dir = std::env::temp_dir();
+        let bad = dir.join(format!("tokenizer-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&bad, "not a tokenizer").unwrap();
+        let good = dir.join(format!("tokenizer-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&good, word_level_tokenizer()).unwrap();
+        let bad = bad.to_str().unwrap();
+        let good = good.to_str().unwrap();
+
+        let tokenizer = load_tokenizer_with_fallback(bad, good).unwrap();
+        let splitter = TextSplitter::new(ChunkConfig::new(8).with_sizer(tokenizer));
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(10);
+        assert!(splitter.chunks(&text).count() > 1);
+
+        assert!(load_tokenizer_with_fallback(bad, "").is_err());
+        let err = load_tokenizer_with_fallback(bad, bad).unwrap_err();
+        assert!(matches!(err, AgentError::InvalidConfig(msg) if msg.contains("fallback tokenizer")));
+
+        std::fs::remove_file(bad).unwrap();
+        std::fs::remove_file(good).unwrap();
+    }
+
+    #[test]
+    fn test_tokenizer_cache() {
+        let path = std::env::temp_dir().join(format!("tokenizer-{}.json", uuid::Uuid::new_v4()));
+        let name = path.to_str().unwrap();
+
+        // a failed load is not cached
+        std::fs::write(&path, "not a tokenizer").unwrap();
+        assert!(load_tokenizer(name).is_err());
+        std::fs::write(&path, word_level_tokenizer()).unwrap();
+        let first = load_tokenizer(name).unwrap();
+
+        // later loads reuse the tokenizer, even after the file is gone
+        std::fs::remove_file(&path).unwrap();
+        let second = load_tokenizer(name).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn test_decode_text() {
+        // utf-8, with and without a byte order mark
+        let (text, encoding) = decode_text("日本語 text".as_bytes(), None).unwrap();
+        assert_eq!(text, "日本語 text");
+        assert_eq!(encoding, TextEncoding::Utf8);
+        let (text, _) = decode_text(b"\xEF\xBB\xBFabc", None).unwrap();
+        assert_eq!(text, "abc");
+
+        // "日本" in Shift_JIS
+        let sjis = [0x93, 0xFA, 0x96, 0x7B];
+        let (text, encoding) = decode_text(&sjis, None).unwrap();
+        assert_eq!(text, "日本");
+        assert_eq!(encoding, TextEncoding::ShiftJis);
+        // half-width katakana and ASCII mixed in
+        let (text, _) = decode_text(b"\x83e\x83X\x83g \xB1 ok", None).unwrap();
+        assert_eq!(text, "テスト ｱ ok");
+
+        // latin-1 falls back to windows-1252
+        let (text, encoding) = decode_text(b"caf\xE9 \x80", None).unwrap();
+        assert_eq!(text, "café €");
+        assert_eq!(encoding, TextEncoding::Windows1252);
+
+        // utf-16 with a byte order mark
+        let (text, encoding) = decode_text(b"\xFF\xFEh\x00i\x00", None).unwrap();
+        assert_eq!(text, "hi");
+        assert_eq!(encoding, TextEncoding::Utf16Le);
+
+        // the override wins over detection
+        let utf8 = "café".as_bytes();
+        let latin1 = "latin1".parse::<TextEncoding>().unwrap();
+        let (text, encoding) = decode_text(utf8, Some(latin1)).unwrap();
+        assert_eq!(text, "cafÃ©");
+        assert_eq!(encoding, TextEncoding::Windows1252);
+        assert!(decode_text(&sjis, Some(TextEncoding::Utf8)).is_err());
+        assert_eq!("Shift_JIS".parse::<TextEncoding>().unwrap(), TextEncoding::ShiftJis);
+        assert_eq!("sjis".parse::<TextEncoding>().unwrap(), TextEncoding::ShiftJis);
+        assert_eq!("utf8".parse::<TextEncoding>().unwrap(), TextEncoding::Utf8);
+        assert!("euc-jp".parse::<TextEncoding>().is_err());
+        assert!("unknown".parse::<TextEncoding>().is_err());
+
+        // bytes as an array or base64
+        let array =
+            AgentValue::array(vec![AgentValue::integer(104), AgentValue::integer(105)].into());
+        assert_eq!(bytes_from_value(&array).unwrap(), b"hi");
+        let data_url = AgentValue::string("data:text/plain;base64,aGk=");
+        assert_eq!(bytes_from_value(&data_url).unwrap(), b"hi");
+        let out_of_range = AgentValue::array(vec![AgentValue::integer(256)].into());
+        assert!(bytes_from_value(&out_of_range).is_err());
+    }
+}
@@ -0,0 +1,723 @@
+use std::future::Future;
+use std::str::FromStr;
+
+use agent_stream_kit::{
+    ASKit, Agent, AgentContext, AgentData, AgentError, AgentOutput, AgentSpec, AgentValue, AsAgent,
+    askit_agent, async_trait,
+};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use futures::{StreamExt, TryStreamExt, stream};
+use im::Vector;
+
+const CATEGORY: &str = "LLM/Embeddings";
+
+const PIN_DOCS: &str = "docs";
+const PIN_VALUE: &str = "value";
+
+const CONFIG_DIMENSIONS: &str = "dimensions";
+const CONFIG_METHOD: &str = "method";
+const CONFIG_NORMALIZE: &str = "normalize";
+const CONFIG_TOP_K: &str = "top_k";
+
+pub const EMBEDDING_ENCODING_FLOAT: &str = "float";
+pub const EMBEDDING_ENCODING_BASE64: &str = "base64";
+
+pub const INPUT_TYPE_NONE: &str = "none";
+pub const INPUT_TYPE_QUERY: &str = "query";
+pub const INPUT_TYPE_PASSAGE: &str = "passage";
+
+pub const REDUCE_METHOD_TRUNCATE: &str = "truncate";
+pub const REDUCE_METHOD_PROJECTION: &str = "projection";
+
+/// Representation of embedding vectors on the output pins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmbeddingEncoding {
+    /// A tensor of f32 values.
+    #[default]
+    Float,
+    /// A base64 string of the vector as consecutive little-endian f32 values (4 bytes each).
+    Base64,
+}
+
+impl FromStr for EmbeddingEncoding {
+    type Err = AgentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | EMBEDDING_ENCODING_FLOAT => Ok(EmbeddingEncoding::Float),
+            EMBEDDING_ENCODING_BASE64 => Ok(EmbeddingEncoding::Base64),
+            other => Err(AgentError::InvalidConfig(format!(
+                "Unsupported embedding encoding: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Convert an embedding vector into an output value with the given encoding.
+pub fn embedding_value(embedding: Vec<f32>, encoding: EmbeddingEncoding) -> AgentValue {
+    match encoding {
+        EmbeddingEncoding::Float => AgentValue::tensor(embedding),
+        EmbeddingEncoding::Base64 => {
+            let bytes = embedding
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<u8>>();
+            AgentValue::string(STANDARD.encode(bytes))
+        }
+    }
+}
+
+/// Decode a base64 embedding produced by `embedding_value`.
+pub fn decode_base64_embedding(s: &str) -> Result<Vec<f32>, AgentError> {
+    let bytes = STANDARD
+        .decode(s)
+        .map_err(|e| AgentError::InvalidValue(format!("Invalid base64 embedding: {}", e)))?;
+    if bytes.len() % 4 != 0 {
+        return Err(AgentError::InvalidValue(
+            "Base64 embedding length is not a multiple of 4 bytes".to_string(),
+        ));
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+/// Whether texts are search queries or the passages searched, for models that
+/// embed the two differently.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputType {
+    #[default]
+    None,
+    Query,
+    Passage,
+}
+
+impl FromStr for InputType {
+    type Err = AgentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | INPUT_TYPE_NONE => Ok(InputType::None),
+            INPUT_TYPE_QUERY => Ok(InputType::Query),
+            INPUT_TYPE_PASSAGE => Ok(InputType::Passage),
+            other => Err(AgentError::InvalidConfig(format!(
+                "Unsupported input type: {}",
+                other
+            ))),
+        }
+    }
+}
+
+const RETRIEVAL_QUERY_PREFIX: &str = "Represent this sentence for searching relevant passages: ";
+
+// (model name fragment, query prefix, passage prefix); the first match wins
+const INPUT_PREFIXES: [(&str, &str, &str); 4] = [
+    ("nomic-embed-text", "search_query: ", "search_document: "),
+    ("e5-", "query: ", "passage: "),
+    ("mxbai-embed-large", RETRIEVAL_QUERY_PREFIX, ""),
+    ("snowflake-arctic-embed", RETRIEVAL_QUERY_PREFIX, ""),
+];
+
+/// The prefix the model expects for the input type, if any.
+pub fn input_prefix(model: &str, input_type: InputType) -> &'static str {
+    let model = model.to_lowercase();
+    let Some((_, query, passage)) = INPUT_PREFIXES
+        .iter()
+        .find(|(fragment, _, _)| model.contains(fragment))
+    else {
+        return "";
+    };
+    match input_type {
+        InputType::None => "",
+        InputType::Query => query,
+        InputType::Passage => passage,
+    }
+}
+
+/// Prepend the prefix of the input type to the texts, unless they already have it.
+pub fn with_input_prefix(texts: Vec<String>, model: &str, input_type: InputType) -> Vec<String> {
+    let prefix = input_prefix(model, input_type);
+    if prefix.is_empty() {
+        return texts;
+    }
+    texts
+        .into_iter()
+        .map(|text| {
+            if text.starts_with(prefix) {
+                text
+            } else {
+                format!("{}{}", prefix, text)
+            }
+        })
+        .collect()
+}
+
+/// Texts to embed from the string pin of the Embeddings agents.
+///
+/// The input is a string, an object with a text field like a doc, or an array of strings.
+pub fn embedding_input_texts(value: &AgentValue) -> Result<Vec<String>, AgentError> {
+    if let Some(arr) = value.as_array() {
+        return arr
+            .iter()
+            .enumerate()
+            .map(|(i, item)| match item.as_str() {
+                Some(text) if !text.is_empty() => Ok(text.to_string()),
+                Some(_) => Err(AgentError::InvalidValue(format!(
+                    "Input text at index {} is an empty string",
+                    i
+                ))),
+                None => Err(AgentError::InvalidValue(format!(
+                    "Input array must contain only strings, but index {} is not a string",
+                    i
+                ))),
+            })
+            .collect();
+    }
+    let text = if value.is_object() {
+        value.get_str("text").unwrap_or_default()
+    } else {
+        value.as_str().unwrap_or_default()
+    };
+    if text.is_empty() {
+        return Err(AgentError::InvalidValue(
+            "Input text is an empty string".to_string(),
+        ));
+    }
+    Ok(vec![text.to_string()])
+}
+
+/// Embed texts in sub-batches of batch_size (0 = a single request),
+/// running up to concurrency requests at once.
+///
+/// The embeddings are returned in the order of the input texts.
+pub async fn embed_in_batches<F, Fut>(
+    texts: Vec<String>,
+    batch_size: usize,
+    concurrency: usize,
+    embed: F,
+) -> Result<Vec<Vec<f32>>, AgentError>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<Vec<f32>>, AgentError>>,
+{
+    let batch_size = if batch_size == 0 {
+        texts.len().max(1)
+    } else {
+        batch_size
+    };
+    let batches = texts
+        .chunks(batch_size)
+        .map(|batch| batch.to_vec())
+        .collect::<Vec<_>>();
+    let results = stream::iter(batches)
+        .map(|batch| {
+            let len = batch.len();
+            let fut = embed(batch);
+            async move {
+                let embeddings = fut.await?;
+                if embeddings.len() != len {
+                    return Err(AgentError::Other(format!(
+                        "Expected {} embeddings but got {}",
+                        len,
+                        embeddings.len()
+                    )));
+                }
+                Ok(embeddings)
+            }
+        })
+        // buffered keeps the batch order regardless of completion order
+        .buffered(concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(results.into_iter().flatten().collect())
+}
+
+/// Read an embedding given as a tensor, a base64 string, or an array of numbers.
+pub fn embedding_from_value(value: &AgentValue) -> Result<Vec<f32>, AgentError> {
+    if let Some(tensor) = value.as_tensor() {
+        return Ok(tensor.clone());
+    }
+    if let Some(s) = value.as_str() {
+        return decode_base64_embedding(s);
+    }
+    if let Some(arr) = value.as_array() {
+        return arr
+            .iter()
+            .map(|v| {
+                v.as_f64().map(|f| f as f32).ok_or_else(|| {
+                    AgentError::InvalidValue("Embedding contains non-numeric values".to_string())
+                })
+            })
+            .collect();
+    }
+    Err(AgentError::InvalidValue(
+        "Embedding must be a tensor, a base64 string or an array of numbers".to_string(),
+    ))
+}
+
+/// Euclidean (L2) distance between two vectors of the same dimension.
+pub fn euclidean_distance(a: &[f32], b: &[f32]) -> Result<f32, AgentError> {
+    if a.len() != b.len() {
+        return Err(AgentError::InvalidValue(format!(
+            "Embedding dimensions do not match: {} != {}",
+            a.len(),
+            b.len()
+        )));
+    }
+    Ok(a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<f32>()
+        .sqrt())
+}
+
+/// Rank docs by L2 distance to the query, nearest first, keeping at most top_k (0 = all).
+///
+/// Each doc is either an object with an `embedding` field, which is returned with a
+/// `distance` field added, or a bare embedding, which is returned as `{ index, distance }`.
+fn rank_by_distance(
+    query: &[f32],
+    docs: &Vector<AgentValue>,
+    top_k: usize,
+) -> Result<Vec<AgentValue>, AgentError> {
+    let mut ranked = Vec::with_capacity(docs.len());
+    for (index, doc) in docs.iter().enumerate() {
+        let (embedding, mut output) = match doc.get("embedding") {
+            Some(embedding) if doc.is_object() => (embedding, doc.clone()),
+            _ => (
+                doc,
+                AgentValue::object(im::hashmap! {
+                    "index".into() => AgentValue::integer(index as i64),
+                }),
+            ),
+        };
+        let distance = euclidean_distance(query, &embedding_from_value(embedding)?)?;
+        output.set("distance".to_string(), AgentValue::number(distance as f64))?;
+        ranked.push((distance, output));
+    }
+    ranked.sort_by(|a, b| a.0.total_cmp(&b.0));
+    if top_k > 0 {
+        ranked.truncate(top_k);
+    }
+    Ok(ranked.into_iter().map(|(_, doc)| doc).collect())
+}
+
+/// Rank docs by Euclidean distance to a query embedding.
+///
+/// The input is an object `{ query, docs }`. The docs are output nearest first
+/// with a distance field, limited to top_k when top_k > 0.
+#[askit_agent(
+    title="Euclidean Distance",
+    category=CATEGORY,
+    inputs=[PIN_VALUE],
+    outputs=[PIN_DOCS],
+    integer_config(name=CONFIG_TOP_K, title="Top K"),
+)]
+pub struct EuclideanDistanceAgent {
+    data: AgentData,
+}
+
+#[async_trait]
+impl AsAgent for EuclideanDistanceAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        Ok(Self {
+            data: AgentData::new(askit, id, spec),
+        })
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let query = value.get("query").ok_or_else(|| {
+            AgentError::InvalidValue("Input must have a query field".to_string())
+        })?;
+        let query = embedding_from_value(query)?;
+        let docs = value
+            .get("docs")
+            .and_then(|docs| docs.as_array())
+            .ok_or_else(|| AgentError::InvalidValue("Input must have a docs array".to_string()))?;
+        let top_k = self.configs()?.get_integer_or_default(CONFIG_TOP_K).max(0) as usize;
+
+        let ranked = rank_by_distance(&query, docs, top_k)?;
+        self.output(ctx, PIN_DOCS, AgentValue::array(ranked.into()))
+            .await
+    }
+}
+
+/// How embeddings are reduced to fewer dimensions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReduceMethod {
+    /// Keep the leading components, for Matryoshka models.
+    #[default]
+    Truncate,
+    /// Multiply by a fixed random sign matrix, which roughly preserves distances.
+    Projection,
+}
+
+impl FromStr for ReduceMethod {
+    type Err = AgentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | REDUCE_METHOD_TRUNCATE => Ok(ReduceMethod::Truncate),
+            REDUCE_METHOD_PROJECTION => Ok(ReduceMethod::Projection),
+            other => Err(AgentError::InvalidConfig(format!(
+                "Unsupported reduce method: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Scale the vector to unit L2 norm. A zero vector is left as is.
+fn normalize(v: &mut [f32]) {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Sign of the projection matrix entry, from a fixed hash so that
+/// every call projects vectors of the same dimension the same way.
+fn projection_sign(row: usize, col: usize) -> f32 {
+    // splitmix64
+    let mut z = ((row as u64) << 32 ^ col as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    if z & 1 == 0 { 1.0 } else { -1.0 }
+}
+
+/// Reduce an embedding to `dimensions` components, optionally re-normalizing it.
+pub fn reduce_dimensions(
+    embedding: &[f32],
+    dimensions: usize,
+    method: ReduceMethod,
+    normalized: bool,
+) -> Result<Vec<f32>, AgentError> {
+    if dimensions == 0 || dimensions >= embedding.len() {
+        return Err(AgentError::InvalidValue(format!(
+            "Target dimensions must be between 1 and {}, but got {}",
+            embedding.len().saturating_sub(1),
+            dimensions
+        )));
+    }
+    let mut reduced = match method {
+        ReduceMethod::Truncate => embedding[..dimensions].to_vec(),
+        ReduceMethod::Projection => {
+            let scale = 1.0 / (dimensions as f32).sqrt();
+            (0..dimensions)
+                .map(|row| {
+                    embedding
+                        .iter()
+                        .enumerate()
+                        .map(|(col, x)| x * projection_sign(row, col))
+                        .sum::<f32>()
+                        * scale
+                })
+                .collect()
+        }
+    };
+    if normalized {
+        normalize(&mut reduced);
+    }
+    Ok(reduced)
+}
+
+/// Reduce an embedding, a doc with an embedding field, or an array of them.
+fn reduce_value(
+    value: &AgentValue,
+    dimensions: usize,
+    method: ReduceMethod,
+    normalized: bool,
+) -> Result<AgentValue, AgentError> {
+    if let Some(arr) = value.as_array()
+        && arr.iter().any(|v| !v.is_number() && !v.is_integer())
+    {
+        return Ok(AgentValue::array(
+            arr.iter()
+                .map(|v| reduce_value(v, dimensions, method, normalized))
+                .collect::<Result<_, _>>()?,
+        ));
+    }
+    if value.is_object() {
+        let embedding = value.get("embedding").ok_or_else(|| {
+            AgentError::InvalidValue("Doc must have an embedding field".to_string())
+        })?;
+        let reduced = reduce_value(embedding, dimensions, method, normalized)?;
+        let mut doc = value.clone();
+        doc.set("embedding".to_string(), reduced)?;
+        return Ok(doc);
+    }
+    let embedding = embedding_from_value(value)?;
+    let reduced = reduce_dimensions(&embedding, dimensions, method, normalized)?;
+    Ok(AgentValue::tensor(reduced))
+}
+
+/// Reduce embeddings to fewer dimensions without re-embedding.
+///
+/// The input is an embedding, a doc with an embedding field, or an array of them,
+/// and the output has the same shape with the embeddings reduced to dimensions.
+/// Use truncate for Matryoshka models and projection otherwise.
+#[askit_agent(
+    title="Reduce Dimensions",
+    category=CATEGORY,
+    inputs=[PIN_VALUE],
+    outputs=[PIN_VALUE],
+    integer_config(name=CONFIG_DIMENSIONS, default=256, title="Dimensions"),
+    string_config(name=CONFIG_METHOD, default=REDUCE_METHOD_TRUNCATE, title="Method"),
+    boolean_config(name=CONFIG_NORMALIZE, default=true, title="Normalize"),
+)]
+pub struct ReduceDimAgent {
+    data: AgentData,
+}
+
+#[async_trait]
+impl AsAgent for ReduceDimAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        Ok(Self {
+            data: AgentData::new(askit, id, spec),
+        })
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let dimensions = self.configs()?.get_integer_or_default(CONFIG_DIMENSIONS);
+        if dimensions <= 0 {
+            return Err(AgentError::InvalidConfig(
+                "dimensions must be greater than 0".to_string(),
+            ));
+        }
+        let method: ReduceMethod = self
+            .configs()?
+            .get_string_or_default(CONFIG_METHOD)
+            .parse()?;
+        let normalized = self.configs()?.get_bool_or_default(CONFIG_NORMALIZE);
+        let reduced = reduce_value(&value, dimensions as usize, method, normalized)?;
+        self.output(ctx, PIN_VALUE, reduced).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedding_value_base64() {
+        let embedding = vec![0.0, 1.5, -2.25, f32::MIN_POSITIVE, 1e10];
+
+        let value = embedding_value(embedding.clone(), EmbeddingEncoding::Float);
+        assert_eq!(value.as_tensor().unwrap().as_slice(), embedding.as_slice());
+
+        let value = embedding_value(embedding.clone(), EmbeddingEncoding::Base64);
+        let decoded = decode_base64_embedding(value.as_str().unwrap()).unwrap();
+        assert_eq!(decoded, embedding);
+
+        assert_eq!(
+            "base64".parse::<EmbeddingEncoding>().unwrap(),
+            EmbeddingEncoding::Base64
+        );
+        assert!("f16".parse::<EmbeddingEncoding>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_embed_in_batches() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let texts = (0..250).map(|i| i.to_string()).collect::<Vec<_>>();
+        let requests = AtomicUsize::new(0);
+        let embeddings = embed_in_batches(texts, 100, 3, |batch| {
+            let n = requests.fetch_add(1, Ordering::SeqCst);
+            async move {
+                // Later batches finish first
+                tokio::time::sleep(std::time::Duration::from_millis(30 - 10 * n as u64)).await;
+                Ok(batch
+                    .iter()
+                    .map(|t| vec![t.parse::<f32>().unwrap()])
+                    .collect())
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(embeddings.len(), 250);
+        for (i, embedding) in embeddings.iter().enumerate() {
+            assert_eq!(embedding, &vec![i as f32]);
+        }
+
+        // A short response is an error
+        let res = embed_in_batches(vec!["a".into(), "b".into()], 0, 1, |_| async {
+            Ok(vec![vec![0.0]])
+        })
+        .await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_rank_by_distance() {
+        // 3-4-5 triangle
+        assert_eq!(euclidean_distance(&[0.0, 0.0], &[3.0, 4.0]).unwrap(), 5.0);
+        assert!(euclidean_distance(&[0.0, 0.0], &[1.0]).is_err());
+
+        let query = [1.0, 1.0];
+        let docs = im::vector![
+            AgentValue::object(im::hashmap! {
+                "text".into() => AgentValue::string("far"),
+                "embedding".into() => AgentValue::tensor(vec![4.0, 5.0]),
+            }),
+            AgentValue::object(im::hashmap! {
+                "text".into() => AgentValue::string("near"),
+                "embedding".into() => AgentValue::tensor(vec![1.0, 2.0]),
+            }),
+            embedding_value(vec![2.0, 2.0], EmbeddingEncoding::Base64),
+        ];
+
+        let ranked = rank_by_distance(&query, &docs, 0).unwrap();
+        assert_eq!(ranked.len(), 3);
+        assert_eq!(ranked[0].get_str("text"), Some("near"));
+        assert_eq!(ranked[0].get("distance").unwrap().as_f64(), Some(1.0));
+        // sqrt(2)
+        assert_eq!(ranked[1].get("index").unwrap().as_i64(), Some(2));
+        let d = ranked[1].get("distance").unwrap().as_f64().unwrap();
+        assert!((d - 2f64.sqrt()).abs() < 1e-6);
+        assert_eq!(ranked[2].get_str("text"), Some("far"));
+        assert_eq!(ranked[2].get("distance").unwrap().as_f64(), Some(5.0));
+
+        let ranked = rank_by_distance(&query, &docs, 1).unwrap();
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].get_str("text"), Some("near"));
+
+        let bad = im::vector![AgentValue::tensor(vec![1.0, 2.0, 3.0])];
+        assert!(rank_by_distance(&query, &bad, 0).is_err());
+    }
+
+    #[test]
+    fn test_reduce_dimensions_truncate() {
+        let embedding = [3.0, 4.0, 1.0, 2.0];
+
+        // the leading components are kept as is
+        let reduced = reduce_dimensions(&embedding, 2, ReduceMethod::Truncate, false).unwrap();
+        assert_eq!(reduced, vec![3.0, 4.0]);
+
+        // and scaled to unit length when normalized
+        let reduced = reduce_dimensions(&embedding, 2, ReduceMethod::Truncate, true).unwrap();
+        assert_eq!(reduced, vec![0.6, 0.8]);
+
+        assert!(reduce_dimensions(&embedding, 4, ReduceMethod::Truncate, true).is_err());
+        assert!(reduce_dimensions(&embedding, 0, ReduceMethod::Truncate, true).is_err());
+    }
+
+    #[test]
+    fn test_reduce_dimensions_projection() {
+        let embedding: Vec<f32> = (0..64).map(|i| (i as f32).sin()).collect();
+        let reduced = reduce_dimensions(&embedding, 16, ReduceMethod::Projection, true).unwrap();
+        assert_eq!(reduced.len(), 16);
+        let norm = reduced.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-5);
+
+        // the same projection every time
+        let again = reduce_dimensions(&embedding, 16, ReduceMethod::Projection, true).unwrap();
+        assert_eq!(reduced, again);
+
+        assert_eq!(
+            "projection".parse::<ReduceMethod>().unwrap(),
+            ReduceMethod::Projection
+        );
+        assert!("pca".parse::<ReduceMethod>().is_err());
+    }
+
+    #[test]
+    fn test_reduce_value() {
+        let docs = AgentValue::array(im::vector![
+            AgentValue::object(im::hashmap! {
+                "text".into() => AgentValue::string("a"),
+                "embedding".into() => AgentValue::tensor(vec![3.0, 4.0, 5.0]),
+            }),
+            AgentValue::array(im::vector![
+                AgentValue::number(0.0),
+                AgentValue::number(2.0),
+                AgentValue::number(1.0),
+            ]),
+        ]);
+        let reduced = reduce_value(&docs, 2, ReduceMethod::Truncate, true).unwrap();
+        let reduced = reduced.as_array().unwrap();
+        assert_eq!(reduced[0].get_str("text"), Some("a"));
+        assert_eq!(
+            reduced[0].get("embedding").unwrap().as_tensor().unwrap().as_slice(),
+            &[0.6, 0.8]
+        );
+        assert_eq!(reduced[1].as_tensor().unwrap().as_slice(), &[0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_input_prefix() {
+        assert_eq!("".parse::<InputType>().unwrap(), InputType::None);
+        assert_eq!("Query".parse::<InputType>().unwrap(), InputType::Query);
+        assert!("document".parse::<InputType>().is_err());
+
+        let texts = vec!["What is Rust?".to_string()];
+        assert_eq!(
+            with_input_prefix(texts.clone(), "intfloat/multilingual-e5-large", InputType::Query),
+            vec!["query: What is Rust?".to_string()]
+        );
+        assert_eq!(
+            with_input_prefix(texts.clone(), "nomic-embed-text:latest", InputType::Passage),
+            vec!["search_document: What is Rust?".to_string()]
+        );
+        // no prefix for passages of mxbai, unknown models or without an input type
+        assert_eq!(
+            with_input_prefix(texts.clone(), "mxbai-embed-large", InputType::Passage),
+            texts
+        );
+        assert_eq!(
+            with_input_prefix(texts.clone(), "text-embedding-3-small", InputType::Query),
+            texts
+        );
+        assert_eq!(with_input_prefix(texts.clone(), "e5-large", InputType::None), texts);
+        // an existing prefix is not doubled
+        let prefixed = vec!["query: What is Rust?".to_string()];
+        assert_eq!(
+            with_input_prefix(prefixed.clone(), "e5-large", InputType::Query),
+            prefixed
+        );
+    }
+
+    #[test]
+    fn test_embedding_input_texts() {
+        assert_eq!(
+            embedding_input_texts(&AgentValue::string("hello")).unwrap(),
+            vec!["hello".to_string()]
+        );
+        assert!(embedding_input_texts(&AgentValue::string("")).is_err());
+
+        let doc = AgentValue::object(im::hashmap! {
+            "text".into() => AgentValue::string("a doc"),
+            "id".into() => AgentValue::integer(1),
+        });
+        assert_eq!(embedding_input_texts(&doc).unwrap(), vec!["a doc".to_string()]);
+        assert!(embedding_input_texts(&AgentValue::object_default()).is_err());
+
+        let arr = AgentValue::array(im::vector![AgentValue::string("a"), AgentValue::string("b")]);
+        assert_eq!(
+            embedding_input_texts(&arr).unwrap(),
+            vec!["a".to_string(), "b".to_string()]
+        );
+        assert!(embedding_input_texts(&AgentValue::array_default()).unwrap().is_empty());
+
+        let mixed = AgentValue::array(im::vector![AgentValue::string("a"), AgentValue::integer(1)]);
+        let err = embedding_input_texts(&mixed).unwrap_err();
+        assert!(matches!(err, AgentError::InvalidValue(msg) if msg.contains("index 1")));
+
+        assert!(embedding_input_texts(&AgentValue::integer(1)).is_err());
+    }
+}
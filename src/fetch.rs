@@ -0,0 +1,478 @@
+#![cfg(feature = "fetch")]
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+use agent_stream_kit::tool::{Tool, ToolInfo};
+use agent_stream_kit::{AgentContext, AgentError, AgentValue, async_trait};
+use encoding_rs::Encoding;
+use regex::Regex;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Url, redirect};
+
+use crate::common::{DEFAULT_TRUNCATION_MARKER, TruncateStrategy, truncate};
+
+pub const FETCH_URL_TOOL_NAME: &str = "fetch_url";
+
+const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_CHARS: usize = 8000;
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const MAX_REDIRECTS: usize = 10;
+
+static RE_HIDDEN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)<!--.*?-->|<(script|style|noscript|template)\b[^>]*>.*?</(script|style|noscript|template)\s*>").unwrap()
+});
+static RE_BLOCK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)</?(br|p|div|li|tr|h[1-6]|section|article|header|footer|pre|blockquote|table|ul|ol)\b[^>]*>").unwrap()
+});
+static RE_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<[^>]*>").unwrap());
+static RE_SPACES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[ \t\r\f\v]+").unwrap());
+static RE_BLANK_LINES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\n\s*\n+").unwrap());
+
+/// Convert HTML into plain text.
+///
+/// Scripts, styles and comments are removed, block elements become line breaks,
+/// common entities are decoded and whitespace is collapsed.
+pub fn html_to_text(html: &str) -> String {
+    let text = RE_HIDDEN.replace_all(html, "");
+    let text = RE_BLOCK.replace_all(&text, "\n");
+    let text = RE_TAG.replace_all(&text, "");
+    let text = decode_entities(&text);
+    let text = RE_SPACES.replace_all(&text, " ");
+    let text = text
+        .lines()
+        .map(|line| line.trim())
+        .collect::<Vec<_>>()
+        .join("\n");
+    RE_BLANK_LINES
+        .replace_all(&text, "\n\n")
+        .trim()
+        .to_string()
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Whether the address is reachable on the public internet.
+///
+/// Loopback, private, link-local (including cloud metadata at 169.254.169.254),
+/// shared, unspecified, broadcast, documentation and multicast addresses are not.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // 100.64.0.0/10, shared address space
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // fc00::/7, unique local
+                || (first & 0xfe00) == 0xfc00
+                // fe80::/10, link-local
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Reject a URL that is not http(s) or whose host is a non-public IP address.
+///
+/// Host names are checked when they are resolved, by `PublicResolver`.
+fn check_url(url: &Url, allow_private: bool) -> Result<(), String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("Unsupported URL scheme: {}", url.scheme()));
+    }
+    // IPv6 hosts are in brackets
+    let host = url.host_str().unwrap_or_default();
+    let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() else {
+        return Ok(());
+    };
+    if !allow_private && !is_public_ip(ip) {
+        return Err(format!("Fetching a private address is not allowed: {}", ip));
+    }
+    Ok(())
+}
+
+/// DNS resolver that drops non-public addresses, so a host name cannot point
+/// the tool at the local machine or network.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} does not resolve to a public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn build_client(timeout: Duration, allow_private: bool) -> Result<reqwest::Client, AgentError> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        // Every redirect is checked like the requested URL
+        .redirect(redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            match check_url(attempt.url(), allow_private) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e),
+            }
+        }));
+    if !allow_private {
+        // A proxy would resolve the host names itself
+        builder = builder.no_proxy().dns_resolver(Arc::new(PublicResolver));
+    }
+    builder
+        .build()
+        .map_err(|e| AgentError::Other(format!("Failed to create HTTP client: {}", e)))
+}
+
+/// Decode the body with the charset of the content type, or the detected encoding.
+fn decode_body(body: &[u8], content_type: Option<&str>) -> String {
+    let charset = content_type.and_then(|content_type| {
+        content_type.split(';').skip(1).find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            key.trim()
+                .eq_ignore_ascii_case("charset")
+                .then(|| value.trim().trim_matches('"'))
+        })
+    });
+    if let Some(encoding) = charset.and_then(|label| Encoding::for_label(label.as_bytes())) {
+        return encoding.decode(body).0.into_owned();
+    }
+    crate::doc::decode_text(body, None)
+        .map(|(text, _)| text)
+        .unwrap_or_else(|_| String::from_utf8_lossy(body).into_owned())
+}
+
+/// Built-in tool that fetches a URL and returns its content as plain text.
+///
+/// At most `max_bytes` of the body are read, and the text is truncated to
+/// `max_chars` characters so it can be passed to a model for summarizing.
+/// By default the end of a long page is cut; see `with_truncation`.
+///
+/// The URL comes from the model, so loopback, private and link-local addresses
+/// are refused, after DNS resolution and on every redirect, unless allowed with
+/// `with_private_addresses`.
+pub struct FetchUrlTool {
+    info: ToolInfo,
+    client: reqwest::Client,
+    timeout: Duration,
+    allow_private: bool,
+    max_bytes: usize,
+    max_chars: usize,
+    strategy: TruncateStrategy,
+    marker: String,
+}
+
+impl FetchUrlTool {
+    pub fn new() -> Result<Self, AgentError> {
+        Self::with_limits(
+            Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            DEFAULT_MAX_BYTES,
+            DEFAULT_MAX_CHARS,
+        )
+    }
+
+    pub fn with_limits(
+        timeout: Duration,
+        max_bytes: usize,
+        max_chars: usize,
+    ) -> Result<Self, AgentError> {
+        Ok(Self {
+            info: ToolInfo {
+                name: FETCH_URL_TOOL_NAME.to_string(),
+                description: "Fetch a web page by URL and return its text content.".to_string(),
+                parameters: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "url": {
+                            "type": "string",
+                            "description": "The http or https URL to fetch"
+                        }
+                    },
+                    "required": ["url"]
+                })),
+            },
+            client: build_client(timeout, false)?,
+            timeout,
+            allow_private: false,
+            max_bytes,
+            max_chars,
+            strategy: TruncateStrategy::default(),
+            marker: DEFAULT_TRUNCATION_MARKER.to_string(),
+        })
+    }
+
+    /// Set which part of a long page is cut and the marker put in its place.
+    pub fn with_truncation(mut self, strategy: TruncateStrategy, marker: impl Into<String>) -> Self {
+        self.strategy = strategy;
+        self.marker = marker.into();
+        self
+    }
+
+    /// Allow fetching loopback, private and link-local addresses.
+    ///
+    /// Only for tools whose URLs come from a trusted source, such as tests
+    /// against a local server.
+    pub fn with_private_addresses(mut self, allow: bool) -> Result<Self, AgentError> {
+        self.client = build_client(self.timeout, allow)?;
+        self.allow_private = allow;
+        Ok(self)
+    }
+
+    async fn fetch(&self, url: &str) -> Result<String, AgentError> {
+        let parsed = Url::parse(url)
+            .map_err(|e| AgentError::InvalidValue(format!("Invalid URL '{}': {}", url, e)))?;
+        check_url(&parsed, self.allow_private).map_err(AgentError::InvalidValue)?;
+
+        let mut response = self
+            .client
+            .get(parsed)
+            .send()
+            .await
+            .map_err(|e| AgentError::IoError(format!("Failed to fetch {}: {}", url, e)))?;
+        if !response.status().is_success() {
+            return Err(AgentError::IoError(format!(
+                "Failed to fetch {}: HTTP {}",
+                url,
+                response.status()
+            )));
+        }
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let is_html = content_type.as_deref().is_none_or(|v| v.contains("html"));
+
+        let mut body = Vec::new();
+        let mut truncated = false;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| AgentError::IoError(format!("Failed to read {}: {}", url, e)))?
+        {
+            if body.len() + chunk.len() > self.max_bytes {
+                body.extend_from_slice(&chunk[..self.max_bytes - body.len()]);
+                truncated = true;
+                break;
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        let body = decode_body(&body, content_type.as_deref());
+        let text = if is_html {
+            html_to_text(&body)
+        } else {
+            body.trim().to_string()
+        };
+        let text = truncate(&text, self.max_chars, self.strategy, &self.marker);
+        // The end of the body was not read
+        if truncated && !text.ends_with(&self.marker) {
+            return Ok(format!("{}{}", text, self.marker));
+        }
+        Ok(text)
+    }
+}
+
+#[async_trait]
+impl Tool for FetchUrlTool {
+    fn info(&self) -> &ToolInfo {
+        &self.info
+    }
+
+    async fn call(&self, _ctx: AgentContext, args: AgentValue) -> Result<AgentValue, AgentError> {
+        let url = args
+            .get_str("url")
+            .or_else(|| args.as_str())
+            .ok_or_else(|| AgentError::InvalidValue("fetch_url requires a url".to_string()))?;
+        let text = self.fetch(url).await?;
+        Ok(AgentValue::string(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve a single HTTP response on a local port and return its URL.
+    async fn serve_once(content_type: &'static str, body: impl Into<Vec<u8>>) -> String {
+        let body = body.into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                content_type,
+                body.len()
+            );
+            socket.write_all(header.as_bytes()).await.unwrap();
+            socket.write_all(&body).await.unwrap();
+        });
+        format!("http://{}/page", addr)
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let html = r#"<html><head><title>T</title><style>p { color: red; }</style>
+            <script>alert("x")</script></head>
+            <body><h1>Hello</h1><!-- note --><p>Fish &amp; chips<br>are&nbsp;good.</p></body></html>"#;
+        assert_eq!(html_to_text(html), "T\n\nHello\n\nFish & chips\nare good.");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_url() {
+        let url = serve_once(
+            "text/html; charset=utf-8",
+            "<html><body><p>Hello, <b>world</b>!</p></body></html>".to_string(),
+        )
+        .await;
+        let tool = FetchUrlTool::new()
+            .unwrap()
+            .with_private_addresses(true)
+            .unwrap();
+        let args = AgentValue::object(im::hashmap! {
+            "url".into() => AgentValue::string(url),
+        });
+        let result = tool.call(AgentContext::new(), args).await.unwrap();
+        assert_eq!(result.as_str(), Some("Hello, world!"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_url_limits() {
+        let url = serve_once("text/plain", "a".repeat(100)).await;
+        let tool = FetchUrlTool::with_limits(Duration::from_secs(5), 1024, 10)
+            .unwrap()
+            .with_private_addresses(true)
+            .unwrap();
+        let text = tool.fetch(&url).await.unwrap();
+        assert_eq!(text, format!("{}{}", "a".repeat(10), DEFAULT_TRUNCATION_MARKER));
+
+        let url = serve_once("text/plain", "b".repeat(100)).await;
+        let tool = FetchUrlTool::with_limits(Duration::from_secs(5), 20, 1000)
+            .unwrap()
+            .with_private_addresses(true)
+            .unwrap();
+        let text = tool.fetch(&url).await.unwrap();
+        assert_eq!(text, format!("{}{}", "b".repeat(20), DEFAULT_TRUNCATION_MARKER));
+
+        let url = serve_once("text/plain", format!("{}{}", "c".repeat(50), "d".repeat(50))).await;
+        let tool = FetchUrlTool::with_limits(Duration::from_secs(5), 1024, 10)
+            .unwrap()
+            .with_private_addresses(true)
+            .unwrap()
+            .with_truncation(TruncateStrategy::Head, "...");
+        let text = tool.fetch(&url).await.unwrap();
+        assert_eq!(text, format!("...{}", "d".repeat(10)));
+
+        assert!(tool.fetch("file:///etc/passwd").await.is_err());
+        assert!(tool.fetch("not a url").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_url_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // accept but never respond
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        let tool = FetchUrlTool::with_limits(Duration::from_millis(200), 1024, 1000)
+            .unwrap()
+            .with_private_addresses(true)
+            .unwrap();
+        assert!(tool.fetch(&format!("http://{}/", addr)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_url_private_addresses() {
+        let url = serve_once("text/plain", "secret").await;
+        let tool = FetchUrlTool::new().unwrap();
+
+        // refused before connecting, by address or after resolving the name
+        let localhost = url.replace("127.0.0.1", "localhost");
+        for url in [
+            url.as_str(),
+            localhost.as_str(),
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]/",
+            "http://[::ffff:10.0.0.1]/",
+        ] {
+            assert!(tool.fetch(url).await.is_err(), "{}", url);
+        }
+
+        // redirects are checked in the same way
+        for url in [
+            "http://10.1.2.3/",
+            "http://192.168.0.1/",
+            "http://100.64.0.1/",
+            "https://[fd00::1]/",
+            "https://[fe80::1]/",
+        ] {
+            assert!(check_url(&Url::parse(url).unwrap(), false).is_err(), "{}", url);
+            assert!(check_url(&Url::parse(url).unwrap(), true).is_ok(), "{}", url);
+        }
+        let public = Url::parse("https://93.184.216.34/").unwrap();
+        assert!(check_url(&public, false).is_ok());
+        assert!(check_url(&Url::parse("https://example.com/").unwrap(), false).is_ok());
+
+        let tool = tool.with_private_addresses(true).unwrap();
+        assert_eq!(tool.fetch(&url).await.unwrap(), "secret");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_url_charset() {
+        // "日本" in Shift_JIS
+        let url = serve_once("text/plain; charset=Shift_JIS", [0x93, 0xFA, 0x96, 0x7B]).await;
+        let tool = FetchUrlTool::new()
+            .unwrap()
+            .with_private_addresses(true)
+            .unwrap();
+        assert_eq!(tool.fetch(&url).await.unwrap(), "日本");
+
+        assert_eq!(
+            decode_body(b"caf\xe9", Some("text/html; charset=\"iso-8859-1\"")),
+            "café"
+        );
+        // without a charset the encoding is detected
+        assert_eq!(decode_body("café".as_bytes(), Some("text/plain")), "café");
+        assert_eq!(decode_body(&[0x93, 0xFA, 0x96, 0x7B], None), "日本");
+    }
+}
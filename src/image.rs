@@ -0,0 +1,202 @@
+use std::str::FromStr;
+
+use agent_stream_kit::AgentError;
+
+#[cfg(feature = "image")]
+use std::sync::Arc;
+
+#[cfg(feature = "image")]
+use agent_stream_kit::{AgentValue, PhotonImage};
+#[cfg(feature = "image")]
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+
+pub const IMAGE_FORMAT_PNG: &str = "png";
+pub const IMAGE_FORMAT_JPEG: &str = "jpeg";
+
+#[cfg(feature = "image")]
+const JPEG_QUALITY: u8 = 85;
+
+/// Encoding used when an image is sent to a provider.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+impl ImageFormat {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = AgentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | IMAGE_FORMAT_PNG => Ok(ImageFormat::Png),
+            IMAGE_FORMAT_JPEG | "jpg" => Ok(ImageFormat::Jpeg),
+            other => Err(AgentError::InvalidConfig(format!(
+                "Unsupported image format: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Encode the image as base64 without the data URL prefix.
+#[cfg(feature = "image")]
+pub fn encode_base64(img: &PhotonImage, format: ImageFormat) -> String {
+    let bytes = match format {
+        ImageFormat::Png => img.get_bytes(),
+        ImageFormat::Jpeg => img.get_bytes_jpeg(JPEG_QUALITY),
+    };
+    STANDARD.encode(bytes)
+}
+
+/// Encode the image as a `data:image/...;base64,` URL.
+#[cfg(feature = "image")]
+pub fn to_data_url(img: &PhotonImage, format: ImageFormat) -> String {
+    format!(
+        "data:{};base64,{}",
+        format.mime_type(),
+        encode_base64(img, format)
+    )
+}
+
+/// Split a `data:<mime>;base64,<data>` URL into its mime type and base64 payload.
+///
+/// Returns `None` if the string is not a base64 image data URL.
+pub fn parse_data_url(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix("data:")?;
+    let (mime, data) = rest.split_once(',')?;
+    let mime = mime.strip_suffix(";base64")?;
+    if !mime.starts_with("image/") {
+        return None;
+    }
+    Some((mime, data))
+}
+
+/// Decode an image given either as a data URL or as plain base64.
+#[cfg(feature = "image")]
+pub fn decode_image(s: &str) -> Result<PhotonImage, AgentError> {
+    let data = match parse_data_url(s) {
+        Some((_mime, data)) => data,
+        None => s,
+    };
+    let bytes = STANDARD
+        .decode(data.trim())
+        .map_err(|e| AgentError::InvalidValue(format!("Invalid base64 image: {}", e)))?;
+    // PhotonImage::new_from_byteslice panics on undecodable bytes
+    let img = ::image::load_from_memory(&bytes)
+        .map_err(|e| AgentError::InvalidValue(format!("Invalid image: {}", e)))?;
+    let (width, height) = (img.width(), img.height());
+    Ok(PhotonImage::new(img.into_rgba8().into_raw(), width, height))
+}
+
+/// Replace data URL strings in the `image` field of message objects with decoded images.
+///
+/// The message parser only understands PNG data URLs, so this should be applied
+/// before converting values into messages.
+#[cfg(feature = "image")]
+pub fn decode_message_images(value: AgentValue) -> Result<AgentValue, AgentError> {
+    match value {
+        AgentValue::Array(arr) => Ok(AgentValue::array(
+            arr.into_iter()
+                .map(decode_message_images)
+                .collect::<Result<_, _>>()?,
+        )),
+        AgentValue::Object(_) => {
+            let Some(image) = value.get_str("image") else {
+                return Ok(value);
+            };
+            let image = decode_image(image)?;
+            let mut value = value;
+            value.set("image".to_string(), AgentValue::image_arc(Arc::new(image)))?;
+            Ok(value)
+        }
+        _ => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_format_from_str() {
+        assert_eq!("".parse::<ImageFormat>().unwrap(), ImageFormat::Png);
+        assert_eq!("PNG".parse::<ImageFormat>().unwrap(), ImageFormat::Png);
+        assert_eq!("jpg".parse::<ImageFormat>().unwrap(), ImageFormat::Jpeg);
+        assert!("gif".parse::<ImageFormat>().is_err());
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_to_data_url() {
+        let img = PhotonImage::new(vec![255u8; 4 * 4 * 4], 4, 4);
+
+        let png = to_data_url(&img, ImageFormat::Png);
+        assert!(png.starts_with("data:image/png;base64,"));
+
+        let jpeg = to_data_url(&img, ImageFormat::Jpeg);
+        assert!(jpeg.starts_with("data:image/jpeg;base64,"));
+        let bytes = STANDARD
+            .decode(jpeg.trim_start_matches("data:image/jpeg;base64,"))
+            .unwrap();
+        // JPEG SOI marker
+        assert_eq!(&bytes[..2], &[0xFF, 0xD8]);
+    }
+
+    #[test]
+    fn test_parse_data_url() {
+        assert_eq!(
+            parse_data_url("data:image/webp;base64,AAAA"),
+            Some(("image/webp", "AAAA"))
+        );
+        assert_eq!(parse_data_url("data:text/plain;base64,AAAA"), None);
+        assert_eq!(parse_data_url("data:image/png,AAAA"), None);
+        assert_eq!(parse_data_url("AAAA"), None);
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_decode_image() {
+        let img = PhotonImage::new(vec![255u8; 2 * 2 * 4], 2, 2);
+
+        let png = decode_image(&to_data_url(&img, ImageFormat::Png)).unwrap();
+        assert_eq!((png.get_width(), png.get_height()), (2, 2));
+
+        let jpeg = decode_image(&to_data_url(&img, ImageFormat::Jpeg)).unwrap();
+        assert_eq!((jpeg.get_width(), jpeg.get_height()), (2, 2));
+
+        // 1x1 lossless WebP
+        let webp =
+            decode_image("data:image/webp;base64,UklGRhoAAABXRUJQVlA4TA0AAAAvAAAAEAcQERGIiP4HAA==")
+                .unwrap();
+        assert_eq!((webp.get_width(), webp.get_height()), (1, 1));
+
+        assert!(decode_image("data:image/png;base64,!!!").is_err());
+        // valid base64 that is not an image
+        assert!(decode_image("data:image/png;base64,AAAA").is_err());
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_decode_message_images() {
+        let img = PhotonImage::new(vec![255u8; 2 * 2 * 4], 2, 2);
+        let value = AgentValue::object(im::hashmap! {
+            "role".into() => AgentValue::string("user"),
+            "content".into() => AgentValue::string("What is this?"),
+            "image".into() => AgentValue::string(to_data_url(&img, ImageFormat::Jpeg)),
+        });
+        let value = decode_message_images(value).unwrap();
+        let message = value.to_message().unwrap();
+        assert_eq!(message.content, "What is this?");
+        assert_eq!(message.image.unwrap().get_width(), 2);
+    }
+}
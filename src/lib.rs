@@ -1,7 +1,17 @@
 #![recursion_limit = "256"]
 
+pub mod common;
 pub mod doc;
+pub mod embeddings;
+pub mod image;
 pub mod message;
+pub mod tool;
+
+#[cfg(feature = "anthropic")]
+pub mod anthropic;
+
+#[cfg(feature = "fetch")]
+pub mod fetch;
 
 #[cfg(feature = "ollama")]
 pub mod ollama;
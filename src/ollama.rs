@@ -1,9 +1,10 @@
 #![cfg(feature = "ollama")]
 
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::vec;
 
-use agent_stream_kit::tool::{self, list_tool_infos_patterns};
+use agent_stream_kit::tool;
 use agent_stream_kit::{
     ASKit, Agent, AgentConfigs, AgentContext, AgentData, AgentError, AgentOutput, AgentSpec,
     AgentValue, AsAgent, Message, ToolCall, ToolCallFunction, askit_agent, async_trait,
@@ -22,11 +23,23 @@ use ollama_rs::{
     models::ModelOptions,
 };
 use schemars::{Schema, json_schema};
-use tokio_stream::StreamExt;
+
+use crate::common::{
+    StreamResponses, empty_input, first_token_deadline, is_empty_message,
+    next_before, output_message_parts, prompt_messages, reply_message_id, response_value,
+    retry_on_empty,
+};
+use crate::embeddings::{
+    EMBEDDING_ENCODING_FLOAT, EmbeddingEncoding, INPUT_TYPE_NONE, InputType, embed_in_batches,
+    embedding_input_texts, embedding_value, with_input_prefix,
+};
+use crate::image::{IMAGE_FORMAT_PNG, ImageFormat};
+use crate::tool::configured_tool_infos;
 
 const CATEGORY: &str = "LLM/Ollama";
 
 const PIN_CHUNKS: &str = "chunks";
+const PIN_CONTENT: &str = "content";
 const PIN_DOC: &str = "doc";
 const PIN_EMBEDDING: &str = "embedding";
 const PIN_EMBEDDINGS: &str = "embeddings";
@@ -38,39 +51,155 @@ const PIN_PROMPT: &str = "prompt";
 const PIN_RESET: &str = "reset";
 const PIN_RESPONSE: &str = "response";
 const PIN_STRING: &str = "string";
+const PIN_TOOL_CALLS: &str = "tool_calls";
 const PIN_UNIT: &str = "unit";
 
+const CONFIG_BATCH_SIZE: &str = "batch_size";
+const CONFIG_CONCURRENCY: &str = "concurrency";
+const CONFIG_EMIT_PROMPT: &str = "emit_prompt";
+const CONFIG_ENCODING: &str = "encoding";
+const CONFIG_ERROR_ON_EMPTY: &str = "error_on_empty";
+const CONFIG_FIRST_TOKEN_TIMEOUT_SECS: &str = "first_token_timeout_secs";
+const CONFIG_IMAGE_FORMAT: &str = "image_format";
+const CONFIG_INPUT_TYPE: &str = "input_type";
+const CONFIG_MERGE_SYSTEM: &str = "merge_system";
 const CONFIG_MODEL: &str = "model";
+const CONFIG_OLLAMA_DEFAULT_MODEL: &str = "ollama_default_model";
 const CONFIG_OLLAMA_URL: &str = "ollama_url";
+const CONFIG_MAX_EMPTY_RETRIES: &str = "max_empty_retries";
+const CONFIG_MAX_IMAGES: &str = "max_images";
+const CONFIG_MAX_PROMPT_TOKENS: &str = "max_prompt_tokens";
+const CONFIG_MAX_THINKING_TOKENS: &str = "max_thinking_tokens";
 const CONFIG_OPTIONS: &str = "options";
+const CONFIG_PREFIX_TOOL_NAME: &str = "prefix_tool_name";
+const CONFIG_PRETTY_RESPONSE: &str = "pretty_response";
+const CONFIG_REDACT_RESPONSE: &str = "redact_response";
+const CONFIG_REPAIR_MESSAGES: &str = "repair_messages";
+const CONFIG_RESEND_THINKING: &str = "resend_thinking";
+const CONFIG_RETRY_ON_EMPTY: &str = "retry_on_empty";
+const CONFIG_STABLE_TURN_ID: &str = "stable_turn_id";
 const CONFIG_STREAM: &str = "stream";
+const CONFIG_STREAM_RESPONSE: &str = "stream_response";
 const CONFIG_SYSTEM: &str = "system";
+const CONFIG_TOKENIZER: &str = "tokenizer";
 const CONFIG_TOOLS: &str = "tools";
+const CONFIG_TRIM_PROMPT: &str = "trim_prompt";
 const CONFIG_USE_CONTEXT: &str = "use_context";
+const CONFIG_USER_AGENT: &str = "user_agent";
+const CONFIG_VALIDATE_MESSAGES: &str = "validate_messages";
 
-const DEFAULT_CONFIG_MODEL: &str = "gpt-oss:20b";
+pub(crate) const DEFAULT_CONFIG_MODEL: &str = "gpt-oss:20b";
 const DEFAULT_CONFIG_EMBEDDINGS_MODEL: &str = "nomic-embed-text-v2-moe:latest";
 const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
 
+/// Option keys understood by `ModelOptions`.
+const MODEL_OPTION_KEYS: [&str; 16] = [
+    "mirostat",
+    "mirostat_eta",
+    "mirostat_tau",
+    "num_ctx",
+    "num_gqa",
+    "num_gpu",
+    "num_thread",
+    "repeat_last_n",
+    "repeat_penalty",
+    "temperature",
+    "seed",
+    "stop",
+    "tfs_z",
+    "num_predict",
+    "top_k",
+    "top_p",
+];
+
+/// Parse the options config, rejecting keys that `ModelOptions` would silently drop.
+fn model_options(options: serde_json::Value) -> Result<ModelOptions, AgentError> {
+    if let Some(obj) = options.as_object() {
+        let unknown = obj
+            .keys()
+            .filter(|key| !MODEL_OPTION_KEYS.contains(&key.as_str()))
+            .map(|key| key.as_str())
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            return Err(AgentError::InvalidConfig(format!(
+                "Unknown Ollama option(s): {}",
+                unknown.join(", ")
+            )));
+        }
+    }
+    serde_json::from_value::<ModelOptions>(options)
+        .map_err(|e| AgentError::InvalidConfig(format!("Invalid options: {}", e)))
+}
+
+/// Fail early if the model can only produce embeddings.
+fn check_chat_capabilities(model: &str, capabilities: &[String]) -> Result<(), AgentError> {
+    let has = |c: &str| capabilities.iter().any(|cap| cap == c);
+    if has("embedding") && !has("completion") {
+        return Err(AgentError::InvalidConfig(format!(
+            "model {} is embedding-only",
+            model
+        )));
+    }
+    Ok(())
+}
+
+/// Chat model used when an agent's model config is empty.
+///
+/// The `ollama_default_model` global config takes precedence over the compiled-in default.
+pub(crate) fn default_chat_model(askit: &ASKit) -> String {
+    askit
+        .get_global_configs(crate::ollama::OllamaCompletionAgent::DEF_NAME)
+        .and_then(|cfg| cfg.get_string(CONFIG_OLLAMA_DEFAULT_MODEL).ok())
+        .filter(|model| !model.is_empty())
+        .unwrap_or_else(|| DEFAULT_CONFIG_MODEL.to_string())
+}
+
+/// The client with the url and user agent it was created with.
+type CachedClient = (String, Option<String>, Ollama);
+
 // Shared client management for Ollama agents
 struct OllamaManager {
-    client: Arc<Mutex<Option<Ollama>>>,
+    client: Arc<Mutex<Option<CachedClient>>>,
+    // Model capabilities reported by /api/show, keyed by model name
+    capabilities: Arc<Mutex<HashMap<String, Vec<String>>>>,
 }
 
 impl OllamaManager {
     fn new() -> Self {
         Self {
             client: Arc::new(Mutex::new(None)),
+            capabilities: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Look up the capabilities of the model, caching the result.
+    ///
+    /// Returns an empty list if the server does not report them.
+    async fn get_capabilities(&self, client: &Ollama, model: &str) -> Vec<String> {
+        if let Some(capabilities) = self.capabilities.lock().unwrap().get(model) {
+            return capabilities.clone();
         }
+        let capabilities = match client.show_model_info(model.to_string()).await {
+            Ok(info) => info.capabilities,
+            Err(e) => {
+                // Leave it to the actual request to report the error
+                log::debug!("Failed to get model info for {}: {}", model, e);
+                return vec![];
+            }
+        };
+        self.capabilities
+            .lock()
+            .unwrap()
+            .insert(model.to_string(), capabilities.clone());
+        capabilities
     }
 
     fn get_ollama_url(global_config: Option<AgentConfigs>) -> String {
         if let Some(ollama_url) =
             global_config.and_then(|cfg| cfg.get_string(CONFIG_OLLAMA_URL).ok())
+            && !ollama_url.is_empty()
         {
-            if !ollama_url.is_empty() {
-                return ollama_url;
-            }
+            return ollama_url;
         }
         if let Ok(ollama_api_base_url) = std::env::var("OLLAMA_API_BASE_URL") {
             return ollama_api_base_url;
@@ -80,19 +209,48 @@ impl OllamaManager {
         DEFAULT_OLLAMA_URL.to_string()
     }
 
+    /// Get the shared client, recreating it when the url or the user agent change.
     fn get_client(&self, askit: &ASKit) -> Result<Ollama, AgentError> {
-        let mut client_guard = self.client.lock().unwrap();
+        let global_config =
+            askit.get_global_configs(crate::ollama::OllamaCompletionAgent::DEF_NAME);
+        let user_agent = global_config
+            .as_ref()
+            .and_then(|cfg| cfg.get_string(CONFIG_USER_AGENT).ok())
+            .map(|ua| ua.trim().to_string())
+            .filter(|ua| !ua.is_empty());
+        let api_base_url = Self::get_ollama_url(global_config);
 
-        if let Some(client) = client_guard.as_ref() {
+        let mut client_guard = self.client.lock().unwrap();
+        if let Some((url, ua, client)) = client_guard.as_ref()
+            && *url == api_base_url
+            && *ua == user_agent
+        {
             return Ok(client.clone());
         }
 
-        let global_config =
-            askit.get_global_configs(crate::ollama::OllamaCompletionAgent::DEF_NAME);
-        let api_base_url = Self::get_ollama_url(global_config);
-        let new_client = Ollama::try_new(api_base_url)
+        let url = reqwest::Url::parse(&api_base_url)
             .map_err(|e| AgentError::IoError(format!("Ollama Client Error: {}", e)))?;
-        *client_guard = Some(new_client.clone());
+        let Some(port) = url.port_or_known_default() else {
+            return Err(AgentError::InvalidConfig(format!(
+                "Ollama URL has no port: {}",
+                api_base_url
+            )));
+        };
+        let new_client = match &user_agent {
+            Some(user_agent) => {
+                let http_client = reqwest::Client::builder()
+                    .user_agent(user_agent)
+                    .build()
+                    .map_err(|e| AgentError::IoError(format!("Ollama Client Error: {}", e)))?;
+                Ollama::new_with_client(url, port, http_client)
+            }
+            None => Ollama::from_url(url),
+        };
+        // Another server may have other models
+        if client_guard.as_ref().is_some_and(|(url, _, _)| *url != api_base_url) {
+            self.capabilities.lock().unwrap().clear();
+        }
+        *client_guard = Some((api_base_url, user_agent, new_client.clone()));
 
         Ok(new_client)
     }
@@ -108,7 +266,11 @@ impl OllamaManager {
     text_config(name=CONFIG_SYSTEM, default=""),
     boolean_config(name=CONFIG_USE_CONTEXT),
     object_config(name=CONFIG_OPTIONS),
+    boolean_config(name=CONFIG_REDACT_RESPONSE, title="Redact Response"),
+    boolean_config(name=CONFIG_ERROR_ON_EMPTY, title="Error on Empty"),
     string_global_config(name=CONFIG_OLLAMA_URL, default=DEFAULT_OLLAMA_URL, title="Ollama URL"),
+    string_global_config(name=CONFIG_OLLAMA_DEFAULT_MODEL, title="Ollama Default Chat Model"),
+    string_global_config(name=CONFIG_USER_AGENT, title="User-Agent"),
 )]
 pub struct OllamaCompletionAgent {
     data: AgentData,
@@ -149,7 +311,8 @@ impl AsAgent for OllamaCompletionAgent {
 
         let prompt = value.as_str().unwrap_or("");
         if prompt.is_empty() {
-            return Ok(());
+            let error_on_empty = self.configs()?.get_bool_or_default(CONFIG_ERROR_ON_EMPTY);
+            return empty_input(error_on_empty, "Prompt is empty");
         }
 
         let mut request = GenerationRequest::new(config_model.to_string(), prompt);
@@ -163,20 +326,12 @@ impl AsAgent for OllamaCompletionAgent {
         if !config_options.is_empty() {
             let config_options = serde_json::to_value(&config_options)
                 .map_err(|e| AgentError::InvalidValue(format!("Invalid JSON in options: {}", e)))?;
-            if let Ok(options_json) = serde_json::from_value::<ModelOptions>(config_options) {
-                request = request.options(options_json);
-            } else {
-                return Err(AgentError::InvalidValue(
-                    "Invalid JSON in options".to_string(),
-                ));
-            }
+            request = request.options(model_options(config_options)?);
         }
 
         let use_context = self.configs()?.get_bool_or_default(CONFIG_USE_CONTEXT);
-        if use_context {
-            if let Some(context) = &self.context {
-                request = request.context(context.clone());
-            }
+        if use_context && let Some(context) = &self.context {
+            request = request.context(context.clone());
         }
 
         let client = self.manager.get_client(self.askit())?;
@@ -193,7 +348,8 @@ impl AsAgent for OllamaCompletionAgent {
         self.output(ctx.clone(), PIN_MESSAGE, message.into())
             .await?;
 
-        let out_response = AgentValue::from_serialize(&res)?;
+        let redact_response = self.configs()?.get_bool_or_default(CONFIG_REDACT_RESPONSE);
+        let out_response = response_value(&res, false, redact_response)?;
         self.output(ctx, PIN_RESPONSE, out_response).await?;
 
         Ok(())
@@ -205,11 +361,31 @@ impl AsAgent for OllamaCompletionAgent {
     title="Chat",
     category=CATEGORY,
     inputs=[PIN_MESSAGE],
-    outputs=[PIN_MESSAGE, PIN_RESPONSE],
+    outputs=[PIN_MESSAGE, PIN_RESPONSE, PIN_CONTENT, PIN_TOOL_CALLS, PIN_PROMPT],
     boolean_config(name=CONFIG_STREAM, title="Stream"),
-    string_config(name=CONFIG_MODEL, default=DEFAULT_CONFIG_MODEL),
+    boolean_config(name=CONFIG_STREAM_RESPONSE, default=true, title="Stream Response"),
+    integer_config(name=CONFIG_FIRST_TOKEN_TIMEOUT_SECS, title="First Token Timeout (secs)"),
+    string_config(name=CONFIG_MODEL),
     text_config(name=CONFIG_TOOLS),
     object_config(name=CONFIG_OPTIONS),
+    string_config(name=CONFIG_IMAGE_FORMAT, default=IMAGE_FORMAT_PNG, title="Image Format"),
+    integer_config(name=CONFIG_MAX_IMAGES, title="Max Images"),
+    boolean_config(name=CONFIG_RETRY_ON_EMPTY, title="Retry on Empty"),
+    integer_config(name=CONFIG_MAX_EMPTY_RETRIES, default=2, title="Max Empty Retries"),
+    integer_config(name=CONFIG_MAX_THINKING_TOKENS, title="Max Thinking Tokens"),
+    boolean_config(name=CONFIG_RESEND_THINKING, title="Resend Thinking"),
+    boolean_config(name=CONFIG_PRETTY_RESPONSE, title="Pretty Response"),
+    boolean_config(name=CONFIG_REDACT_RESPONSE, title="Redact Response"),
+    boolean_config(name=CONFIG_ERROR_ON_EMPTY, title="Error on Empty"),
+    boolean_config(name=CONFIG_MERGE_SYSTEM, title="Merge System Messages"),
+    integer_config(name=CONFIG_MAX_PROMPT_TOKENS, title="Max Prompt Tokens"),
+    boolean_config(name=CONFIG_TRIM_PROMPT, title="Trim Prompt"),
+    string_config(name=CONFIG_TOKENIZER, title="Tokenizer"),
+    boolean_config(name=CONFIG_VALIDATE_MESSAGES, title="Validate Messages"),
+    boolean_config(name=CONFIG_REPAIR_MESSAGES, title="Repair Messages"),
+    boolean_config(name=CONFIG_EMIT_PROMPT, title="Emit Prompt"),
+    boolean_config(name=CONFIG_STABLE_TURN_ID, title="Stable Turn Id"),
+    boolean_config(name=CONFIG_PREFIX_TOOL_NAME, title="Prefix Tool Name"),
 )]
 pub struct OllamaChatAgent {
     data: AgentData,
@@ -231,30 +407,18 @@ impl AsAgent for OllamaChatAgent {
         _pin: String,
         value: AgentValue,
     ) -> Result<(), AgentError> {
-        let config_model = &self.configs()?.get_string_or_default(CONFIG_MODEL);
+        let mut config_model = self.configs()?.get_string_or_default(CONFIG_MODEL);
         if config_model.is_empty() {
-            return Ok(());
+            config_model = default_chat_model(self.askit());
         }
+        let config_model = &config_model;
 
-        // Convert value to messages
-        let Some(value) = value.to_message_value() else {
-            return Err(AgentError::InvalidValue(
-                "Input value is not a valid message".to_string(),
-            ));
-        };
-        let messages = if value.is_array() {
-            value.into_array().unwrap()
-        } else {
-            vector![value]
-        };
-        if messages.is_empty() {
-            return Ok(());
-        }
-
-        // If the last message isn’t a user/tool message, just return
-        let role = &messages.last().unwrap().as_message().unwrap().role;
-        if role != "user" && role != "tool" {
+        let Some(messages) = prompt_messages(self.configs()?, value)? else {
             return Ok(());
+        };
+        if self.configs()?.get_bool_or_default(CONFIG_EMIT_PROMPT) {
+            self.output(ctx.clone(), PIN_PROMPT, AgentValue::array(messages.clone()))
+                .await?;
         }
 
         let config_options = self.configs()?.get_object_or_default(CONFIG_OPTIONS);
@@ -262,39 +426,50 @@ impl AsAgent for OllamaChatAgent {
             let value = serde_json::to_value(&config_options).map_err(|e| {
                 AgentError::InvalidConfig(format!("Invalid JSON in options: {}", e))
             })?;
-            Some(serde_json::from_value::<ModelOptions>(value).map_err(|e| {
-                AgentError::InvalidConfig(format!("Invalid JSON in options: {}", e))
-            })?)
+            Some(model_options(value)?)
         } else {
             None
         };
 
         let config_tools = self.configs()?.get_string_or_default(CONFIG_TOOLS);
-        let tool_infos = if config_tools.is_empty() {
-            vec![]
+        let tool_infos = configured_tool_infos(&config_tools)?
+            .into_iter()
+            .map(from_tool_info_to_ollama_tool_info)
+            .collect::<Vec<ollama_rs::generation::tools::ToolInfo>>();
+
+        let use_stream = self.configs()?.get_bool_or_default(CONFIG_STREAM);
+        let pretty_response = self.configs()?.get_bool_or_default(CONFIG_PRETTY_RESPONSE);
+        let redact_response = self.configs()?.get_bool_or_default(CONFIG_REDACT_RESPONSE);
+
+        let max_empty_retries = if self.configs()?.get_bool_or_default(CONFIG_RETRY_ON_EMPTY) {
+            self.configs()?
+                .get_integer_or_default(CONFIG_MAX_EMPTY_RETRIES)
+                .max(0) as usize
         } else {
-            list_tool_infos_patterns(&config_tools)
-                .map_err(|e| {
-                    AgentError::InvalidConfig(format!(
-                        "Invalid regex patterns in tools config: {}",
-                        e
-                    ))
-                })?
-                .into_iter()
-                .map(|tool| from_tool_info_to_ollama_tool_info(tool))
-                .collect::<Vec<ollama_rs::generation::tools::ToolInfo>>()
+            0
         };
 
-        let use_stream = self.configs()?.get_bool_or_default(CONFIG_STREAM);
+        let image_format: ImageFormat = self
+            .configs()?
+            .get_string_or_default(CONFIG_IMAGE_FORMAT)
+            .parse()?;
 
         let client = self.manager.get_client(self.askit())?;
+        let capabilities = self.manager.get_capabilities(&client, config_model).await;
+        check_chat_capabilities(config_model, &capabilities)?;
 
+        let prefix_tool_name = self.configs()?.get_bool_or_default(CONFIG_PREFIX_TOOL_NAME);
         let mut request = ChatMessageRequest::new(
             config_model.to_string(),
             messages
                 .iter()
-                .cloned()
-                .map(|m| message_to_chat(m.as_message().unwrap().clone()))
+                .map(|m| {
+                    let mut msg = m.as_message().unwrap().clone();
+                    if prefix_tool_name {
+                        msg = prefix_tool_content(msg);
+                    }
+                    message_to_chat(msg, image_format)
+                })
                 .collect(),
         );
 
@@ -306,70 +481,41 @@ impl AsAgent for OllamaChatAgent {
             request = request.tools(tool_infos.clone());
         }
 
-        let id = uuid::Uuid::new_v4().to_string();
-        if use_stream {
-            let mut stream = client
-                .send_chat_messages_stream(request)
-                .await
-                .map_err(|e| AgentError::IoError(format!("Ollama Error: {}", e)))?;
-
-            let mut message = Message::assistant("".to_string());
-            let mut content = String::new();
-            let mut thinking = String::new();
-            let mut tool_calls: Vec<ToolCall> = vec![];
-            while let Some(res) = stream.next().await {
-                let res = res.map_err(|_| AgentError::IoError(format!("Ollama Stream Error")))?;
-
-                content.push_str(&res.message.content);
-                if let Some(thinking_str) = res.message.thinking.as_ref() {
-                    thinking.push_str(thinking_str);
-                }
-                for call in &res.message.tool_calls {
-                    let mut parameters = call.function.arguments.clone();
-                    if parameters.is_object() {
-                        if let Some(obj) = parameters.as_object() {
-                            if let Some(props) = obj.get("properties") {
-                                parameters = props.clone();
-                            }
-                        }
-                    }
-
-                    let tool_call = ToolCall {
-                        function: ToolCallFunction {
-                            id: None,
-                            name: call.function.name.clone(),
-                            parameters,
-                        },
-                    };
-                    tool_calls.push(tool_call);
-                }
-
-                message.content = content.clone();
-                if !thinking.is_empty() {
-                    message.thinking = Some(thinking.clone());
-                }
-                if tool_calls.len() > 0 {
-                    message.tool_calls = Some(tool_calls.clone().into());
-                }
-                message.id = Some(id.clone());
-
-                self.output(ctx.clone(), PIN_MESSAGE, message.clone().into())
-                    .await?;
-
-                let out_response = AgentValue::from_serialize(&res)?;
-                self.output(ctx.clone(), PIN_RESPONSE, out_response).await?;
+        let max_thinking_tokens = self
+            .configs()?
+            .get_integer_or_default(CONFIG_MAX_THINKING_TOKENS);
+        if max_thinking_tokens > 0 {
+            // Ollama only supports turning thinking on or off
+            log::warn!(
+                "Ollama does not support a thinking budget; max_thinking_tokens is ignored ({})",
+                self.id()
+            );
+        }
 
-                if res.done {
-                    break;
-                }
-            }
+        let stable_turn_id = self.configs()?.get_bool_or_default(CONFIG_STABLE_TURN_ID);
+        let id = reply_message_id(&messages, stable_turn_id);
+        if use_stream {
+            let message = retry_on_empty(
+                max_empty_retries,
+                || self.send_chat_stream(&ctx, &client, request.clone(), &id),
+                is_empty_message,
+            )
+            .await?;
+            output_message_parts(self, &ctx, &message).await?;
 
             return Ok(());
         } else {
-            let res = client
-                .send_chat_messages(request)
-                .await
-                .map_err(|e| AgentError::IoError(format!("Ollama Error: {}", e)))?;
+            let res = retry_on_empty(
+                max_empty_retries,
+                || async {
+                    client
+                        .send_chat_messages(request.clone())
+                        .await
+                        .map_err(|e| AgentError::IoError(format!("Ollama Error: {}", e)))
+                },
+                |res| res.message.content.trim().is_empty() && res.message.tool_calls.is_empty(),
+            )
+            .await?;
 
             let mut message: Message = message_from_ollama(res.message.clone());
             message.id = Some(id.clone());
@@ -377,21 +523,97 @@ impl AsAgent for OllamaChatAgent {
             self.output(ctx.clone(), PIN_MESSAGE, message.clone().into())
                 .await?;
 
-            let out_response = AgentValue::from_serialize(&res)?;
+            let out_response = response_value(&res, pretty_response, redact_response)?;
             self.output(ctx.clone(), PIN_RESPONSE, out_response).await?;
+            output_message_parts(self, &ctx, &message).await?;
 
             return Ok(());
         }
     }
 }
 
+impl OllamaChatAgent {
+    async fn send_chat_stream(
+        &self,
+        ctx: &AgentContext,
+        client: &Ollama,
+        request: ChatMessageRequest,
+        id: &str,
+    ) -> Result<Message, AgentError> {
+        let pretty_response = self.configs()?.get_bool_or_default(CONFIG_PRETTY_RESPONSE);
+        let redact_response = self.configs()?.get_bool_or_default(CONFIG_REDACT_RESPONSE);
+        let mut responses =
+            StreamResponses::new(self.configs()?.get_bool_or_default(CONFIG_STREAM_RESPONSE));
+        let mut deadline = first_token_deadline(
+            self.configs()?
+                .get_integer_or_default(CONFIG_FIRST_TOKEN_TIMEOUT_SECS),
+        );
+
+        let mut stream = client
+            .send_chat_messages_stream(request)
+            .await
+            .map_err(|e| AgentError::IoError(format!("Ollama Error: {}", e)))?;
+
+        let mut message = Message::assistant("".to_string());
+        let mut content = String::new();
+        let mut thinking = String::new();
+        let mut tool_calls: Vec<ToolCall> = vec![];
+        while let Some(res) = next_before(&mut stream, deadline.take()).await? {
+            let res = res.map_err(|_| AgentError::IoError("Ollama Stream Error".to_string()))?;
+
+            content.push_str(&res.message.content);
+            if let Some(thinking_str) = res.message.thinking.as_ref() {
+                thinking.push_str(thinking_str);
+            }
+            // Some models repeat tool calls in later chunks
+            push_chunk_tool_calls(
+                &mut tool_calls,
+                res.message.tool_calls.iter().cloned().map(tool_call_from_ollama),
+            );
+
+            message.content = content.clone();
+            if !thinking.is_empty() {
+                message.thinking = Some(thinking.clone());
+            }
+            if !tool_calls.is_empty() {
+                message.tool_calls = Some(tool_calls.clone().into());
+            }
+            message.id = Some(id.to_string());
+
+            self.output(ctx.clone(), PIN_MESSAGE, message.clone().into())
+                .await?;
+
+            let done = res.done;
+            if let Some(res) = responses.push(res) {
+                let out_response = response_value(&res, pretty_response, redact_response)?;
+                self.output(ctx.clone(), PIN_RESPONSE, out_response).await?;
+            }
+
+            if done {
+                break;
+            }
+        }
+
+        if let Some(res) = responses.finish() {
+            let out_response = response_value(&res, pretty_response, redact_response)?;
+            self.output(ctx.clone(), PIN_RESPONSE, out_response).await?;
+        }
+
+        Ok(message)
+    }
+}
+
 #[askit_agent(
     title="Embeddings",
     category=CATEGORY,
     inputs=[PIN_STRING, PIN_CHUNKS, PIN_DOC],
     outputs=[PIN_EMBEDDING, PIN_EMBEDDINGS, PIN_DOC],
     string_config(name=CONFIG_MODEL, default=DEFAULT_CONFIG_EMBEDDINGS_MODEL),
-    text_config(name=CONFIG_OPTIONS, default="{}")
+    text_config(name=CONFIG_OPTIONS, default="{}"),
+    string_config(name=CONFIG_ENCODING, default=EMBEDDING_ENCODING_FLOAT, title="Encoding"),
+    string_config(name=CONFIG_INPUT_TYPE, default=INPUT_TYPE_NONE, title="Input Type"),
+    integer_config(name=CONFIG_BATCH_SIZE, title="Batch Size"),
+    integer_config(name=CONFIG_CONCURRENCY, default=4, title="Concurrency"),
 )]
 pub struct OllamaEmbeddingsAgent {
     data: AgentData,
@@ -401,10 +623,20 @@ pub struct OllamaEmbeddingsAgent {
 impl OllamaEmbeddingsAgent {
     async fn generate_embeddings(
         &self,
-        input: EmbeddingsInput,
+        texts: Vec<String>,
         model_name: String,
         model_options: Option<ModelOptions>,
     ) -> Result<Vec<Vec<f32>>, AgentError> {
+        let input_type: InputType = self
+            .configs()?
+            .get_string_or_default(CONFIG_INPUT_TYPE)
+            .parse()?;
+        let texts = with_input_prefix(texts, &model_name, input_type);
+        // A single text is sent as a single input
+        let input: EmbeddingsInput = match <[String; 1]>::try_from(texts) {
+            Ok([text]) => text.into(),
+            Err(texts) => texts.into(),
+        };
         let client = self.manager.get_client(self.askit())?;
         let mut request = GenerateEmbeddingsRequest::new(model_name, input);
         if let Some(options) = model_options {
@@ -416,6 +648,21 @@ impl OllamaEmbeddingsAgent {
             .map_err(|e| AgentError::IoError(format!("generate_embeddings: {}", e)))?;
         Ok(res.embeddings)
     }
+
+    /// Split large inputs into sub-batches according to batch_size and concurrency.
+    async fn generate_embeddings_batched(
+        &self,
+        texts: Vec<String>,
+        model_name: String,
+        model_options: Option<ModelOptions>,
+    ) -> Result<Vec<Vec<f32>>, AgentError> {
+        let batch_size = self.configs()?.get_integer_or_default(CONFIG_BATCH_SIZE).max(0);
+        let concurrency = self.configs()?.get_integer_or_default(CONFIG_CONCURRENCY).max(1);
+        embed_in_batches(texts, batch_size as usize, concurrency as usize, |batch| {
+            self.generate_embeddings(batch, model_name.clone(), model_options.clone())
+        })
+        .await
+    }
 }
 
 #[async_trait]
@@ -438,27 +685,43 @@ impl AsAgent for OllamaEmbeddingsAgent {
             return Err(AgentError::InvalidConfig("model is not set".to_string()));
         }
 
+        let encoding: EmbeddingEncoding = self
+            .configs()?
+            .get_string_or_default(CONFIG_ENCODING)
+            .parse()?;
+
         let config_options = self.configs()?.get_string_or_default(CONFIG_OPTIONS);
         let model_options = if config_options.is_empty() || config_options == "{}" {
             None
         } else {
-            Some(
-                serde_json::from_str::<ModelOptions>(&config_options).map_err(|e| {
-                    AgentError::InvalidConfig(format!("Invalid JSON in options: {}", e))
-                })?,
-            )
+            let value = serde_json::from_str(&config_options).map_err(|e| {
+                AgentError::InvalidConfig(format!("Invalid JSON in options: {}", e))
+            })?;
+            Some(model_options(value)?)
         };
 
         if pin == PIN_STRING {
-            let text = value.as_str().unwrap_or_default();
-            if text.is_empty() {
-                return Err(AgentError::InvalidValue(
-                    "Input text is an empty string".to_string(),
-                ));
+            let texts = embedding_input_texts(&value)?;
+            if value.is_array() {
+                // One embedding per string, in the order of the input
+                if texts.is_empty() {
+                    return self
+                        .output(ctx, PIN_EMBEDDING, AgentValue::array_default())
+                        .await;
+                }
+                let embeddings = self
+                    .generate_embeddings_batched(texts, config_model.to_string(), model_options)
+                    .await?;
+                let values = embeddings
+                    .into_iter()
+                    .map(|emb| embedding_value(emb, encoding))
+                    .collect();
+                return self
+                    .output(ctx, PIN_EMBEDDING, AgentValue::array(values))
+                    .await;
             }
-            let input: EmbeddingsInput = text.into();
             let embeddings = self
-                .generate_embeddings(input, config_model.to_string(), model_options)
+                .generate_embeddings(texts, config_model.to_string(), model_options)
                 .await?;
             if embeddings.len() != 1 {
                 return Err(AgentError::Other(
@@ -469,7 +732,7 @@ impl AsAgent for OllamaEmbeddingsAgent {
                 .output(
                     ctx,
                     PIN_EMBEDDING,
-                    AgentValue::tensor(embeddings.into_iter().next().unwrap()),
+                    embedding_value(embeddings.into_iter().next().unwrap(), encoding),
                 )
                 .await;
         }
@@ -517,7 +780,7 @@ impl AsAgent for OllamaEmbeddingsAgent {
                     .await;
             }
             let embeddings = self
-                .generate_embeddings(texts.into(), config_model.to_string(), model_options)
+                .generate_embeddings_batched(texts, config_model.to_string(), model_options)
                 .await?;
             let embedding_values_with_offsets: Vector<AgentValue> = offsets
                 .into_iter()
@@ -525,7 +788,7 @@ impl AsAgent for OllamaEmbeddingsAgent {
                 .map(|(offset, emb)| {
                     AgentValue::array(vector![
                         AgentValue::integer(offset),
-                        AgentValue::tensor(emb)
+                        embedding_value(emb, encoding)
                     ])
                 })
                 .collect();
@@ -571,7 +834,7 @@ impl AsAgent for OllamaEmbeddingsAgent {
             }
 
             let embeddings = self
-                .generate_embeddings(texts.into(), config_model.to_string(), model_options)
+                .generate_embeddings_batched(texts, config_model.to_string(), model_options)
                 .await?;
             if embeddings.len() != indices.len() {
                 return Err(AgentError::Other(
@@ -582,7 +845,7 @@ impl AsAgent for OllamaEmbeddingsAgent {
             if value.is_object() {
                 let embedding = embeddings.into_iter().next().unwrap();
                 let mut output = value.clone();
-                output.set("embedding".to_string(), AgentValue::tensor(embedding))?;
+                output.set("embedding".to_string(), embedding_value(embedding, encoding))?;
                 return self.output(ctx.clone(), PIN_DOC, output).await;
             } else {
                 let mut arr = value.clone().into_array().unwrap();
@@ -591,7 +854,7 @@ impl AsAgent for OllamaEmbeddingsAgent {
                     let index = indices[i];
                     arr[index as usize].set(
                         "embedding".to_string(),
-                        AgentValue::tensor(embedding.clone()),
+                        embedding_value(embedding.clone(), encoding),
                     )?;
                 }
                 return self
@@ -687,6 +950,27 @@ impl AsAgent for OllamaShowModelInfoAgent {
     }
 }
 
+/// Send the messages in a single non-streaming request and return the reply text.
+pub(crate) async fn chat_once(
+    askit: &ASKit,
+    model: &str,
+    messages: &[Message],
+) -> Result<String, AgentError> {
+    let client = OllamaManager::new().get_client(askit)?;
+    let request = ChatMessageRequest::new(
+        model.to_string(),
+        messages
+            .iter()
+            .map(|m| message_to_chat(m.clone(), ImageFormat::default()))
+            .collect(),
+    );
+    let res = client
+        .send_chat_messages(request)
+        .await
+        .map_err(|e| AgentError::IoError(format!("Ollama Error: {}", e)))?;
+    Ok(res.message.content)
+}
+
 fn message_from_ollama(msg: ChatMessage) -> Message {
     let role = match msg.role {
         MessageRole::User => "user",
@@ -698,35 +982,98 @@ fn message_from_ollama(msg: ChatMessage) -> Message {
     if !msg.tool_calls.is_empty() {
         let mut calls = vector![];
         for call in msg.tool_calls {
-            let tool_call = ToolCall {
-                function: ToolCallFunction {
-                    id: None,
-                    name: call.function.name,
-                    parameters: call.function.arguments,
-                },
-            };
-            calls.push_back(tool_call);
+            calls.push_back(tool_call_from_ollama(call));
         }
         message.tool_calls = Some(calls);
     }
+    #[cfg(feature = "image")]
+    {
+        // Message holds a single image, so only the first one is kept
+        if let Some(img) = msg.images.as_ref().and_then(|images| images.first()) {
+            match crate::image::decode_image(img.to_base64()) {
+                Ok(img) => message.image = Some(std::sync::Arc::new(img)),
+                Err(e) => log::warn!("Failed to decode image from Ollama: {}", e),
+            }
+        }
+    }
     message
+}
+
+// Keys that may appear in a JSON schema wrapping the actual arguments
+const SCHEMA_WRAPPER_KEYS: [&str; 6] = [
+    "$schema",
+    "additionalProperties",
+    "description",
+    "properties",
+    "required",
+    "type",
+];
+
+/// Extract the arguments of a tool call.
+///
+/// Some models wrap the arguments in a schema like `{"type": "object", "properties": {...}}`.
+/// The wrapper is removed only when the shape is clearly a schema, so arguments that
+/// legitimately contain a `properties` field are left as is.
+fn tool_call_arguments(arguments: serde_json::Value) -> serde_json::Value {
+    if let Some(obj) = arguments.as_object()
+        && obj.get("type").and_then(|t| t.as_str()) == Some("object")
+        && let Some(props) = obj.get("properties").filter(|p| p.is_object())
+        && obj.keys().all(|k| SCHEMA_WRAPPER_KEYS.contains(&k.as_str()))
+    {
+        return props.clone();
+    }
+    arguments
+}
+
+fn tool_call_from_ollama(call: ollama_rs::generation::tools::ToolCall) -> ToolCall {
+    ToolCall {
+        function: ToolCallFunction {
+            id: None,
+            name: call.function.name,
+            parameters: tool_call_arguments(call.function.arguments),
+        },
+    }
+}
+
+/// Add the tool calls of a chunk, skipping the ones already sent in earlier chunks.
+///
+/// Identical calls within the same chunk are all kept.
+fn push_chunk_tool_calls(
+    tool_calls: &mut Vec<ToolCall>,
+    chunk_calls: impl IntoIterator<Item = ToolCall>,
+) {
+    let earlier = tool_calls.len();
+    for tool_call in chunk_calls {
+        if tool_calls[..earlier].iter().any(|c| {
+            c.function.name == tool_call.function.name
+                && c.function.parameters == tool_call.function.parameters
+        }) {
+            continue;
+        }
+        tool_calls.push(tool_call);
+    }
+}
 
-    // #[cfg(feature = "image")]
-    // {
-    //     if let Some(images) = msg.images {
-    //         if !images.is_empty() {
-    //             let img = images[0].clone();
-    //             let img_base64 = format!("data:image/png;base64,{}", img.to_base64());
-    //             message.image = Some(crate::message::ImageData::from_base64(img_base64));
-    //         }
-    //     }
-    // }
+/// Prepend `[tool_name]` to the content of tool result messages.
+fn prefix_tool_content(mut msg: Message) -> Message {
+    if msg.role == "tool"
+        && let Some(tool_name) = msg.tool_name.as_deref().filter(|name| !name.is_empty())
+    {
+        msg.content = format!("[{}]\n{}", tool_name, msg.content);
+    }
+    msg
 }
 
-fn message_to_chat(msg: Message) -> ChatMessage {
+#[cfg_attr(not(feature = "image"), allow(unused_variables))]
+fn message_to_chat(msg: Message, image_format: ImageFormat) -> ChatMessage {
     let mut cmsg = match msg.role.as_str() {
         "user" => ChatMessage::user(msg.content),
-        "assistant" => ChatMessage::assistant(msg.content),
+        "assistant" => {
+            let mut cmsg = ChatMessage::assistant(msg.content);
+            // Thinking is only present with resend_thinking
+            cmsg.thinking = msg.thinking;
+            cmsg
+        }
         "system" => ChatMessage::system(msg.content),
         "tool" => ChatMessage::tool(msg.content),
         _ => ChatMessage::user(msg.content), // Default to user if unknown role
@@ -734,10 +1081,7 @@ fn message_to_chat(msg: Message) -> ChatMessage {
     #[cfg(feature = "image")]
     {
         if let Some(img) = msg.image {
-            let img_str = img
-                .get_base64()
-                .trim_start_matches("data:image/png;base64,")
-                .to_string();
+            let img_str = crate::image::encode_base64(&img, image_format);
             cmsg = cmsg.add_image(ollama_rs::generation::images::Image::from_base64(img_str));
         }
     }
@@ -764,3 +1108,245 @@ fn from_tool_info_to_ollama_tool_info(
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_user_agent() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Answer /api/tags twice and keep the requests
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            for _ in 0..2 {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                received
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&buf[..n]).to_lowercase());
+                let body = r#"{"models":[]}"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let askit = ASKit::init().unwrap();
+        let set_configs = |ollama_url: &str, user_agent: &str| {
+            let mut configs = AgentConfigs::new();
+            configs.set(CONFIG_OLLAMA_URL.to_string(), AgentValue::string(ollama_url));
+            configs.set(CONFIG_USER_AGENT.to_string(), AgentValue::string(user_agent));
+            askit.set_global_configs(OllamaCompletionAgent::DEF_NAME.to_string(), configs);
+        };
+        let manager = OllamaManager::new();
+
+        set_configs(&url, "my-app/1.0");
+        let client = manager.get_client(&askit).unwrap();
+        client.list_local_models().await.unwrap();
+
+        // a new user agent takes effect without a restart
+        set_configs(&url, "my-app/2.0");
+        let client = manager.get_client(&askit).unwrap();
+        client.list_local_models().await.unwrap();
+
+        let requests = requests.lock().unwrap().clone();
+        assert!(requests[0].contains("user-agent: my-app/1.0\r\n"));
+        assert!(requests[1].contains("user-agent: my-app/2.0\r\n"));
+
+        // a URL that cannot have a port is an error, not a panic
+        set_configs("file:///tmp/ollama", "my-app/2.0");
+        assert!(matches!(
+            manager.get_client(&askit),
+            Err(AgentError::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_completion_error_on_empty() {
+        let askit = ASKit::init().unwrap();
+        let spec = askit
+            .new_agent_spec(OllamaCompletionAgent::DEF_NAME)
+            .unwrap();
+        let mut agent =
+            <OllamaCompletionAgent as Agent>::new(askit, "completion".into(), spec).unwrap();
+
+        // silent by default
+        let res = AsAgent::process(
+            &mut agent,
+            AgentContext::new(),
+            PIN_PROMPT.into(),
+            AgentValue::string(""),
+        )
+        .await;
+        assert!(res.is_ok());
+
+        agent
+            .set_config(CONFIG_ERROR_ON_EMPTY.into(), AgentValue::boolean(true))
+            .unwrap();
+        let res = AsAgent::process(
+            &mut agent,
+            AgentContext::new(),
+            PIN_PROMPT.into(),
+            AgentValue::string(""),
+        )
+        .await;
+        assert!(matches!(res, Err(AgentError::InvalidValue(_))));
+    }
+
+    #[tokio::test]
+    async fn test_chat_embedding_only_model() {
+        let askit = ASKit::init().unwrap();
+        let spec = askit.new_agent_spec(OllamaChatAgent::DEF_NAME).unwrap();
+        let mut agent = <OllamaChatAgent as Agent>::new(askit, "chat".into(), spec).unwrap();
+        agent
+            .set_config(CONFIG_MODEL.into(), AgentValue::string("nomic-embed-text"))
+            .unwrap();
+        // Seed the cache so that no request is made
+        agent.manager.capabilities.lock().unwrap().insert(
+            "nomic-embed-text".to_string(),
+            vec!["embedding".to_string()],
+        );
+
+        let res = AsAgent::process(
+            &mut agent,
+            AgentContext::new(),
+            PIN_MESSAGE.into(),
+            AgentValue::message(Message::user("Hello".to_string())),
+        )
+        .await;
+        match res {
+            Err(AgentError::InvalidConfig(msg)) => {
+                assert_eq!(msg, "model nomic-embed-text is embedding-only")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let capabilities = vec!["completion".to_string(), "tools".to_string()];
+        assert!(check_chat_capabilities("gpt-oss:20b", &capabilities).is_ok());
+        assert!(check_chat_capabilities("gpt-oss:20b", &[]).is_ok());
+    }
+
+    #[test]
+    fn test_prefix_tool_content() {
+        let msg = prefix_tool_content(Message::tool("get_weather".to_string(), "sunny".to_string()));
+        assert_eq!(msg.content, "[get_weather]\nsunny");
+
+        let msg = prefix_tool_content(Message::user("sunny".to_string()));
+        assert_eq!(msg.content, "sunny");
+    }
+
+    #[test]
+    fn test_model_options() {
+        assert!(model_options(json!({"temperature": 0.2, "num_ctx": 4096})).is_ok());
+
+        match model_options(json!({"temparature": 0.2, "top_k": 40})) {
+            Err(AgentError::InvalidConfig(msg)) => assert!(msg.contains("temparature"), "{}", msg),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_tool_call_arguments() {
+        // schema wrapper is removed
+        let args = json!({
+            "type": "object",
+            "properties": {"city": "Tokyo"},
+            "required": ["city"],
+        });
+        assert_eq!(tool_call_arguments(args), json!({"city": "Tokyo"}));
+
+        // a literal properties field is kept
+        let args = json!({"properties": {"color": "red"}});
+        assert_eq!(tool_call_arguments(args.clone()), args);
+
+        let args = json!({
+            "type": "object",
+            "properties": {"color": "red"},
+            "name": "box",
+        });
+        assert_eq!(tool_call_arguments(args.clone()), args);
+
+        let args = json!({"type": "object", "properties": "none"});
+        assert_eq!(tool_call_arguments(args.clone()), args);
+    }
+
+    #[test]
+    fn test_push_tool_call_dedupe() {
+        let call = |name: &str, parameters: serde_json::Value| ToolCall {
+            function: ToolCallFunction {
+                id: None,
+                name: name.to_string(),
+                parameters,
+            },
+        };
+
+        // identical calls in one chunk are separate calls
+        let mut tool_calls = vec![];
+        push_chunk_tool_calls(
+            &mut tool_calls,
+            [call("get_time", json!({})), call("get_time", json!({}))],
+        );
+        assert_eq!(tool_calls.len(), 2);
+
+        // calls repeated in a later chunk are dropped
+        push_chunk_tool_calls(
+            &mut tool_calls,
+            [
+                call("get_time", json!({})),
+                call("search", json!({"q": "a"})),
+                call("get_time", json!({})),
+            ],
+        );
+        assert_eq!(tool_calls.len(), 3);
+        push_chunk_tool_calls(
+            &mut tool_calls,
+            [
+                call("search", json!({"q": "a"})),
+                call("search", json!({"q": "b"})),
+                call("fetch", json!({"q": "a"})),
+            ],
+        );
+        let names = tool_calls
+            .iter()
+            .map(|c| c.function.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["get_time", "get_time", "search", "search", "fetch"]);
+        assert_eq!(tool_calls[3].function.parameters, json!({"q": "b"}));
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_image_round_trip() {
+        let img = agent_stream_kit::PhotonImage::new(vec![255u8; 3 * 2 * 4], 3, 2);
+        let mut msg = Message::assistant("Here it is".to_string());
+        msg.image = Some(std::sync::Arc::new(img));
+
+        let chat = message_to_chat(msg, ImageFormat::Png);
+        assert_eq!(chat.images.as_ref().map(|images| images.len()), Some(1));
+
+        let msg = message_from_ollama(chat);
+        assert_eq!(msg.content, "Here it is");
+        let img = msg.image.unwrap();
+        assert_eq!((img.get_width(), img.get_height()), (3, 2));
+
+        // an undecodable image is dropped, whether the base64 or the image data is broken
+        for data in ["!!!", "AAAA"] {
+            let chat = ChatMessage::assistant("broken".to_string())
+                .add_image(ollama_rs::generation::images::Image::from_base64(data));
+            let msg = message_from_ollama(chat);
+            assert_eq!(msg.content, "broken");
+            assert!(msg.image.is_none());
+        }
+    }
+}
@@ -1,21 +1,26 @@
 #![cfg(feature = "openai")]
 
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::vec;
 
-use agent_stream_kit::tool::{self, list_tool_infos_patterns};
+use agent_stream_kit::tool;
 use agent_stream_kit::{
     ASKit, Agent, AgentContext, AgentData, AgentError, AgentOutput, AgentSpec, AgentValue, AsAgent,
     Message, ToolCall, ToolCallFunction, askit_agent, async_trait,
 };
 // use async_openai::types::responses::{FunctionArgs, ToolDefinition};
 use async_openai::types::{
-    ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk, ChatCompletionTool,
-    ChatCompletionToolArgs, FunctionObjectArgs,
+    ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk,
+    ChatCompletionNamedToolChoice, ChatCompletionTool, ChatCompletionToolArgs,
+    ChatCompletionToolChoiceOption, ChatCompletionToolType, FunctionCall, FunctionName,
+    FunctionObjectArgs,
 };
 use async_openai::{
     Client,
-    config::OpenAIConfig,
+    error::OpenAIError,
+    config::{Config, OpenAIConfig},
     types::{
         ChatCompletionRequestAssistantMessageArgs,
         ChatCompletionRequestMessage,
@@ -29,37 +34,180 @@ use async_openai::{
         CreateCompletionRequestArgs,
         CreateEmbeddingRequest,
         CreateEmbeddingRequestArgs,
+        ReasoningEffort,
         Role,
         // responses::{self, CreateResponse, CreateResponseArgs, OutputContent, OutputMessage},
     },
 };
 use futures::StreamExt;
-use im::vector;
+use im::{hashmap, vector};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT};
+use secrecy::SecretString;
+
+use crate::common::{
+    RetryPolicy, StreamResponses, content_with_thinking, empty_input, first_token_before,
+    first_token_deadline, is_empty_message, output_message_parts, prompt_messages,
+    reply_message_id, response_value, retry_on_empty, retry_transient, tool_call_id,
+    tool_result_ids,
+};
+use crate::embeddings::{
+    EMBEDDING_ENCODING_FLOAT, EmbeddingEncoding, INPUT_TYPE_NONE, InputType, embed_in_batches,
+    embedding_input_texts, embedding_value, with_input_prefix,
+};
+use crate::image::{IMAGE_FORMAT_PNG, ImageFormat};
+use crate::tool::{
+    check_provider_tool_names, check_tool_arguments, configured_tool_infos, provider_tool_name,
+    registry_tool_name, validate_tool_parameters,
+};
 
 const CATEGORY: &str = "LLM/OpenAI";
 
 const PIN_CHUNKS: &str = "chunks";
+const PIN_CONTENT: &str = "content";
 const PIN_DOC: &str = "doc";
 const PIN_EMBEDDING: &str = "embedding";
 const PIN_EMBEDDINGS: &str = "embeddings";
 const PIN_MESSAGE: &str = "message";
 const PIN_PROMPT: &str = "prompt";
+const PIN_REFUSAL: &str = "refusal";
 const PIN_RESPONSE: &str = "response";
 const PIN_STRING: &str = "string";
-
+const PIN_TOOL_CALLS: &str = "tool_calls";
+const PIN_VALUE: &str = "value";
+
+const CONFIG_BATCH_SIZE: &str = "batch_size";
+const CONFIG_CONCURRENCY: &str = "concurrency";
+const CONFIG_EMIT_PROMPT: &str = "emit_prompt";
+const CONFIG_ENCODING: &str = "encoding";
+const CONFIG_ERROR_ON_EMPTY: &str = "error_on_empty";
+const CONFIG_EXTRA_HEADERS: &str = "extra_headers";
+const CONFIG_FIRST_TOKEN_TIMEOUT_SECS: &str = "first_token_timeout_secs";
+const CONFIG_IMAGE_FORMAT: &str = "image_format";
+const CONFIG_INPUT_TYPE: &str = "input_type";
+const CONFIG_MAX_EMPTY_RETRIES: &str = "max_empty_retries";
+const CONFIG_MAX_IMAGES: &str = "max_images";
+const CONFIG_MAX_PROMPT_TOKENS: &str = "max_prompt_tokens";
+const CONFIG_MAX_RETRIES: &str = "max_retries";
+const CONFIG_MAX_THINKING_TOKENS: &str = "max_thinking_tokens";
+const CONFIG_MERGE_SYSTEM: &str = "merge_system";
 const CONFIG_MODEL: &str = "model";
 const CONFIG_OPENAI_API_KEY: &str = "openai_api_key";
 const CONFIG_OPENAI_API_BASE: &str = "openai_api_base";
+const CONFIG_OPENAI_DEFAULT_MODEL: &str = "openai_default_model";
 const CONFIG_OPTIONS: &str = "options";
+const CONFIG_PRETTY_RESPONSE: &str = "pretty_response";
+const CONFIG_REDACT_RESPONSE: &str = "redact_response";
+const CONFIG_REPAIR_MESSAGES: &str = "repair_messages";
+const CONFIG_RESEND_THINKING: &str = "resend_thinking";
+const CONFIG_RETRY_BASE_MS: &str = "retry_base_ms";
+const CONFIG_RETRY_ON_EMPTY: &str = "retry_on_empty";
+const CONFIG_SCHEMA: &str = "schema";
+const CONFIG_STABLE_TURN_ID: &str = "stable_turn_id";
 const CONFIG_STREAM: &str = "stream";
+const CONFIG_STREAM_RESPONSE: &str = "stream_response";
 const CONFIG_SYSTEM: &str = "system";
+const CONFIG_TOKENIZER: &str = "tokenizer";
 const CONFIG_TOOLS: &str = "tools";
+const CONFIG_TRIM_PROMPT: &str = "trim_prompt";
+const CONFIG_USER_AGENT: &str = "user_agent";
+const CONFIG_VALIDATE_MESSAGES: &str = "validate_messages";
+
+pub(crate) const DEFAULT_CONFIG_MODEL: &str = "gpt-5-nano";
+
+/// Name of the tool the Structured Output agent makes the model call.
+pub const STRUCTURED_OUTPUT_TOOL_NAME: &str = "structured_output";
+
+/// Chat model used when an agent's model config is empty.
+///
+/// The `openai_default_model` global config takes precedence over the compiled-in default.
+pub(crate) fn default_chat_model(askit: &ASKit) -> String {
+    askit
+        .get_global_configs(crate::openai::OpenAICompletionAgent::DEF_NAME)
+        .and_then(|cfg| cfg.get_string(CONFIG_OPENAI_DEFAULT_MODEL).ok())
+        .filter(|model| !model.is_empty())
+        .unwrap_or_else(|| DEFAULT_CONFIG_MODEL.to_string())
+}
+
+/// OpenAI client configuration that adds the user agent and extra headers to every request.
+#[derive(Clone, Debug, Default)]
+struct OpenAIClientConfig {
+    config: OpenAIConfig,
+    extra_headers: HeaderMap,
+    user_agent: Option<HeaderValue>,
+}
+
+impl Config for OpenAIClientConfig {
+    fn headers(&self) -> HeaderMap {
+        let mut headers = self.config.headers();
+        if let Some(user_agent) = &self.user_agent {
+            headers.insert(USER_AGENT, user_agent.clone());
+        }
+        for (name, value) in self.extra_headers.iter() {
+            headers.insert(name.clone(), value.clone());
+        }
+        headers
+    }
 
-const DEFAULT_CONFIG_MODEL: &str = "gpt-5-nano";
+    fn url(&self, path: &str) -> String {
+        self.config.url(path)
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        self.config.query()
+    }
+
+    fn api_base(&self) -> &str {
+        self.config.api_base()
+    }
+
+    fn api_key(&self) -> &SecretString {
+        self.config.api_key()
+    }
+}
+
+/// Parse the `extra_headers` config, a JSON object mapping header names to string values.
+fn parse_extra_headers(text: &str) -> Result<HeaderMap, AgentError> {
+    let mut headers = HeaderMap::new();
+    if text.trim().is_empty() {
+        return Ok(headers);
+    }
+    let json: serde_json::Value = serde_json::from_str(text)
+        .map_err(|e| AgentError::InvalidConfig(format!("Invalid JSON in extra_headers: {}", e)))?;
+    let obj = json.as_object().ok_or_else(|| {
+        AgentError::InvalidConfig("extra_headers must be a JSON object".to_string())
+    })?;
+    for (name, value) in obj {
+        let value = value.as_str().ok_or_else(|| {
+            AgentError::InvalidConfig(format!("extra_headers value for '{}' must be a string", name))
+        })?;
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| {
+            AgentError::InvalidConfig(format!("Invalid header name '{}': {}", name, e))
+        })?;
+        let value = HeaderValue::from_str(value).map_err(|e| {
+            AgentError::InvalidConfig(format!("Invalid value for header '{}': {}", name, e))
+        })?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// The `user_agent` global config as a header value, or None when it is empty.
+fn user_agent(askit: &ASKit) -> Result<Option<HeaderValue>, AgentError> {
+    let Some(user_agent) = askit
+        .get_global_configs(crate::openai::OpenAICompletionAgent::DEF_NAME)
+        .and_then(|cfg| cfg.get_string(CONFIG_USER_AGENT).ok())
+        .filter(|ua| !ua.trim().is_empty())
+    else {
+        return Ok(None);
+    };
+    HeaderValue::from_str(user_agent.trim())
+        .map(Some)
+        .map_err(|e| AgentError::InvalidConfig(format!("Invalid user_agent: {}", e)))
+}
 
 // Shared client management for OpenAI agents
 struct OpenAIManager {
-    client: Arc<Mutex<Option<Client<OpenAIConfig>>>>,
+    client: Arc<Mutex<Option<Client<OpenAIClientConfig>>>>,
 }
 
 impl OpenAIManager {
@@ -69,10 +217,23 @@ impl OpenAIManager {
         }
     }
 
-    fn get_client(&self, askit: &ASKit) -> Result<Client<OpenAIConfig>, AgentError> {
+    fn get_client(&self, askit: &ASKit) -> Result<Client<OpenAIClientConfig>, AgentError> {
+        self.get_client_with_headers(askit, HeaderMap::new())
+    }
+
+    /// Get the shared client, recreating it when the extra headers or the user agent change.
+    fn get_client_with_headers(
+        &self,
+        askit: &ASKit,
+        extra_headers: HeaderMap,
+    ) -> Result<Client<OpenAIClientConfig>, AgentError> {
+        let user_agent = user_agent(askit)?;
         let mut client_guard = self.client.lock().unwrap();
 
-        if let Some(client) = client_guard.as_ref() {
+        if let Some(client) = client_guard.as_ref()
+            && client.config().extra_headers == extra_headers
+            && client.config().user_agent == user_agent
+        {
             return Ok(client.clone());
         }
 
@@ -94,7 +255,11 @@ impl OpenAIManager {
             config = config.with_api_base(&api_base);
         }
 
-        let new_client = Client::with_config(config);
+        let new_client = Client::with_config(OpenAIClientConfig {
+            config,
+            extra_headers,
+            user_agent,
+        });
         *client_guard = Some(new_client.clone());
 
         Ok(new_client)
@@ -110,8 +275,12 @@ impl OpenAIManager {
     string_config(name=CONFIG_MODEL, default="gpt-3.5-turbo-instruct"),
     text_config(name=CONFIG_SYSTEM),
     object_config(name=CONFIG_OPTIONS),
+    boolean_config(name=CONFIG_REDACT_RESPONSE, title="Redact Response"),
+    boolean_config(name=CONFIG_ERROR_ON_EMPTY, title="Error on Empty"),
     string_global_config(name=CONFIG_OPENAI_API_KEY, title="OpenAI API Key"),
     string_global_config(name=CONFIG_OPENAI_API_BASE, title="OpenAI API Base URL", default="https://api.openai.com/v1"),
+    string_global_config(name=CONFIG_OPENAI_DEFAULT_MODEL, title="OpenAI Default Chat Model"),
+    string_global_config(name=CONFIG_USER_AGENT, title="User-Agent"),
 )]
 pub struct OpenAICompletionAgent {
     data: AgentData,
@@ -138,6 +307,8 @@ impl AsAgent for OpenAICompletionAgent {
             return Ok(());
         }
 
+        let error_on_empty = self.configs()?.get_bool_or_default(CONFIG_ERROR_ON_EMPTY);
+
         let mut messages;
         {
             if value.is_array() {
@@ -148,25 +319,29 @@ impl AsAgent for OpenAICompletionAgent {
                     messages.push(msg);
                 }
                 // Just return if the last message is user
-                if let Some(last_msg) = messages.last() {
-                    if last_msg.role != "user" {
-                        return Ok(());
-                    }
+                if let Some(last_msg) = messages.last()
+                    && last_msg.role != "user"
+                {
+                    return empty_input(
+                        error_on_empty,
+                        &format!("Last message must be a user message: {}", last_msg.role),
+                    );
                 }
             } else {
                 let message: Message = value.try_into()?;
                 messages = vec![message];
             }
         }
+        if messages.iter().all(|m| m.content.is_empty()) {
+            return empty_input(error_on_empty, "Prompt is empty");
+        }
 
         let mut request = CreateCompletionRequestArgs::default()
             .model(config_model)
-            .prompt(
-                messages
-                    .iter()
-                    .map(|m| m.content.clone())
-                    .collect::<Vec<String>>(),
-            )
+            .prompt(completion_prompts(
+                &self.configs()?.get_string_or_default(CONFIG_SYSTEM),
+                &messages,
+            ))
             .build()
             .map_err(|e| AgentError::InvalidValue(format!("Failed to build request: {}", e)))?;
 
@@ -201,7 +376,8 @@ impl AsAgent for OpenAICompletionAgent {
         self.output(ctx.clone(), PIN_MESSAGE, message.into())
             .await?;
 
-        let out_response = AgentValue::from_serialize(&res)?;
+        let redact_response = self.configs()?.get_bool_or_default(CONFIG_REDACT_RESPONSE);
+        let out_response = response_value(&res, false, redact_response)?;
         self.output(ctx, PIN_RESPONSE, out_response).await?;
 
         Ok(())
@@ -213,11 +389,33 @@ impl AsAgent for OpenAICompletionAgent {
     title="Chat",
     category=CATEGORY,
     inputs=[PIN_MESSAGE],
-    outputs=[PIN_MESSAGE, PIN_RESPONSE],
+    outputs=[PIN_MESSAGE, PIN_RESPONSE, PIN_REFUSAL, PIN_CONTENT, PIN_TOOL_CALLS, PIN_PROMPT],
     boolean_config(name=CONFIG_STREAM, title="Stream"),
-    string_config(name=CONFIG_MODEL, default=DEFAULT_CONFIG_MODEL),
+    boolean_config(name=CONFIG_STREAM_RESPONSE, default=true, title="Stream Response"),
+    integer_config(name=CONFIG_FIRST_TOKEN_TIMEOUT_SECS, title="First Token Timeout (secs)"),
+    string_config(name=CONFIG_MODEL),
     text_config(name=CONFIG_TOOLS),
     object_config(name=CONFIG_OPTIONS),
+    string_config(name=CONFIG_IMAGE_FORMAT, default=IMAGE_FORMAT_PNG, title="Image Format"),
+    integer_config(name=CONFIG_MAX_IMAGES, title="Max Images"),
+    boolean_config(name=CONFIG_RETRY_ON_EMPTY, title="Retry on Empty"),
+    integer_config(name=CONFIG_MAX_EMPTY_RETRIES, default=2, title="Max Empty Retries"),
+    integer_config(name=CONFIG_MAX_THINKING_TOKENS, title="Max Thinking Tokens"),
+    boolean_config(name=CONFIG_RESEND_THINKING, title="Resend Thinking"),
+    boolean_config(name=CONFIG_PRETTY_RESPONSE, title="Pretty Response"),
+    boolean_config(name=CONFIG_REDACT_RESPONSE, title="Redact Response"),
+    boolean_config(name=CONFIG_ERROR_ON_EMPTY, title="Error on Empty"),
+    boolean_config(name=CONFIG_MERGE_SYSTEM, title="Merge System Messages"),
+    integer_config(name=CONFIG_MAX_PROMPT_TOKENS, title="Max Prompt Tokens"),
+    boolean_config(name=CONFIG_TRIM_PROMPT, title="Trim Prompt"),
+    string_config(name=CONFIG_TOKENIZER, title="Tokenizer"),
+    boolean_config(name=CONFIG_VALIDATE_MESSAGES, title="Validate Messages"),
+    boolean_config(name=CONFIG_REPAIR_MESSAGES, title="Repair Messages"),
+    boolean_config(name=CONFIG_EMIT_PROMPT, title="Emit Prompt"),
+    boolean_config(name=CONFIG_STABLE_TURN_ID, title="Stable Turn Id"),
+    text_config(name=CONFIG_EXTRA_HEADERS, title="Extra Headers"),
+    integer_config(name=CONFIG_MAX_RETRIES, default=3, title="Max Retries"),
+    integer_config(name=CONFIG_RETRY_BASE_MS, default=500, title="Retry Base Delay (ms)"),
 )]
 pub struct OpenAIChatAgent {
     data: AgentData,
@@ -239,30 +437,18 @@ impl AsAgent for OpenAIChatAgent {
         _pin: String,
         value: AgentValue,
     ) -> Result<(), AgentError> {
-        let config_model = &self.configs()?.get_string_or_default(CONFIG_MODEL);
+        let mut config_model = self.configs()?.get_string_or_default(CONFIG_MODEL);
         if config_model.is_empty() {
-            return Ok(());
-        }
-
-        // Convert value to messages
-        let Some(value) = value.to_message_value() else {
-            return Err(AgentError::InvalidValue(
-                "Input value is not a valid message".to_string(),
-            ));
-        };
-        let messages = if value.is_array() {
-            value.into_array().unwrap()
-        } else {
-            vector![value]
-        };
-        if messages.is_empty() {
-            return Ok(());
+            config_model = default_chat_model(self.askit());
         }
+        let config_model = &config_model;
 
-        // If the last message isn’t a user message, just return
-        let role = &messages.last().unwrap().as_message().unwrap().role;
-        if role != "user" && role != "tool" {
+        let Some(messages) = prompt_messages(self.configs()?, value)? else {
             return Ok(());
+        };
+        if self.configs()?.get_bool_or_default(CONFIG_EMIT_PROMPT) {
+            self.output(ctx.clone(), PIN_PROMPT, AgentValue::array(messages.clone()))
+                .await?;
         }
 
         let config_options = self.configs()?.get_object_or_default(CONFIG_OPTIONS);
@@ -276,33 +462,52 @@ impl AsAgent for OpenAIChatAgent {
             };
 
         let config_tools = self.configs()?.get_string_or_default(CONFIG_TOOLS);
-        let tool_infos = if config_tools.is_empty() {
-            vec![]
-        } else {
-            list_tool_infos_patterns(&config_tools)
-                .map_err(|e| {
-                    AgentError::InvalidConfig(format!(
-                        "Invalid regex patterns in tools config: {}",
-                        e
-                    ))
-                })?
-                .into_iter()
-                .map(|tool| try_from_tool_info_to_chat_completion_tool(tool))
-                .collect::<Result<Vec<ChatCompletionTool>, AgentError>>()?
-        };
+        let tool_infos = configured_tool_infos(&config_tools)?;
+        check_provider_tool_names(&tool_infos)?;
+        let tool_infos = tool_infos
+            .into_iter()
+            .map(try_from_tool_info_to_chat_completion_tool)
+            .collect::<Result<Vec<ChatCompletionTool>, AgentError>>()?;
 
         let use_stream = self.configs()?.get_bool_or_default(CONFIG_STREAM);
+        let pretty_response = self.configs()?.get_bool_or_default(CONFIG_PRETTY_RESPONSE);
+        let redact_response = self.configs()?.get_bool_or_default(CONFIG_REDACT_RESPONSE);
 
-        let client = self.manager.get_client(self.askit())?;
+        let max_empty_retries = if self.configs()?.get_bool_or_default(CONFIG_RETRY_ON_EMPTY) {
+            self.configs()?
+                .get_integer_or_default(CONFIG_MAX_EMPTY_RETRIES)
+                .max(0) as usize
+        } else {
+            0
+        };
+
+        let image_format: ImageFormat = self
+            .configs()?
+            .get_string_or_default(CONFIG_IMAGE_FORMAT)
+            .parse()?;
+
+        let extra_headers =
+            parse_extra_headers(&self.configs()?.get_string_or_default(CONFIG_EXTRA_HEADERS))?;
+        // Retries are done here with max_retries instead of the client's own backoff
+        let no_retry = backoff::ExponentialBackoffBuilder::new()
+            .with_max_elapsed_time(Some(Duration::ZERO))
+            .build();
+        let client = self
+            .manager
+            .get_client_with_headers(self.askit(), extra_headers)?
+            .with_backoff(no_retry);
 
         let mut request = CreateChatCompletionRequestArgs::default()
             .model(config_model)
             .messages(
-                messages
-                    .iter()
-                    .filter_map(|m| m.as_message())
-                    .map(message_to_chat_completion_msg)
-                    .collect::<Vec<ChatCompletionRequestMessage>>(),
+                messages_to_chat_completion_msgs(
+                    &messages
+                        .iter()
+                        .filter_map(|m| m.as_message())
+                        .cloned()
+                        .collect::<Vec<_>>(),
+                    image_format,
+                ),
             )
             .tools(tool_infos.clone())
             .stream(use_stream)
@@ -312,6 +517,11 @@ impl AsAgent for OpenAIChatAgent {
             .build()
             .map_err(|e| AgentError::InvalidValue(format!("Failed to build request: {}", e)))?;
 
+        let max_thinking_tokens = self
+            .configs()?
+            .get_integer_or_default(CONFIG_MAX_THINKING_TOKENS);
+        request.reasoning_effort = reasoning_effort_from_budget(max_thinking_tokens);
+
         if let Some(options_json) = &options_json {
             // Merge options into request
             let mut request_json = serde_json::to_value(&request)
@@ -328,80 +538,319 @@ impl AsAgent for OpenAIChatAgent {
                 .map_err(|e| AgentError::InvalidValue(format!("Deserialization error: {}", e)))?;
         }
 
-        let id = uuid::Uuid::new_v4().to_string();
+        let stable_turn_id = self.configs()?.get_bool_or_default(CONFIG_STABLE_TURN_ID);
+        let id = reply_message_id(&messages, stable_turn_id);
         if use_stream {
-            let mut stream = client
-                .chat()
-                .create_stream(request)
-                .await
-                .map_err(|e| AgentError::IoError(format!("OpenAI Stream Error: {}", e)))?;
+            let message = retry_on_empty(
+                max_empty_retries,
+                || self.send_chat_stream(&ctx, &client, request.clone(), &id),
+                is_empty_message,
+            )
+            .await?;
+            output_message_parts(self, &ctx, &message).await?;
 
-            let mut message = Message::assistant("".to_string());
-            message.id = Some(id.clone());
-            let mut content = String::new();
-            let mut thinking = String::new();
-            let mut tool_calls: Vec<ToolCall> = Vec::new();
-            while let Some(res) = stream.next().await {
-                let res = res.map_err(|_| AgentError::IoError(format!("OpenAI Stream Error")))?;
-
-                for c in &res.choices {
-                    if let Some(ref delta_content) = c.delta.content {
-                        content.push_str(delta_content);
-                    }
-                    // FIXME: correct tool call chunks handling in streaming
-                    if let Some(tc) = &c.delta.tool_calls {
-                        for call in tc {
-                            if let Ok(c) =
-                                try_from_chat_completion_message_tool_call_chunk_to_tool_call(call)
-                            {
-                                tool_calls.push(c);
-                            }
-                        }
-                    }
-                    if let Some(refusal) = &c.delta.refusal {
-                        thinking.push_str(&format!("Refusal: {}", refusal));
-                    }
-                }
+            return Ok(());
+        } else {
+            let retry_policy = self.retry_policy()?;
+            let res = retry_on_empty(
+                max_empty_retries,
+                || {
+                    retry_transient(
+                        retry_policy,
+                        "OpenAI Error",
+                        || async { client.chat().create(request.clone()).await },
+                        is_transient_error,
+                    )
+                },
+                |res| {
+                    res.choices.iter().all(|c| {
+                        c.message.content.as_deref().unwrap_or_default().trim().is_empty()
+                            && c.message.tool_calls.as_ref().is_none_or(|t| t.is_empty())
+                    })
+                },
+            )
+            .await?;
 
-                message.content = content.clone();
-                if !thinking.is_empty() {
-                    message.thinking = Some(thinking.clone());
-                }
-                if !tool_calls.is_empty() {
-                    message.tool_calls = Some(tool_calls.clone().into());
-                }
+            for c in &res.choices {
+                let mut message: Message = message_from_openai_msg(c.message.clone());
+                message.id = Some(id.clone());
 
                 self.output(ctx.clone(), PIN_MESSAGE, message.clone().into())
                     .await?;
 
-                let out_response = AgentValue::from_serialize(&res)?;
+                let out_response = response_value(&res, pretty_response, redact_response)?;
                 self.output(ctx.clone(), PIN_RESPONSE, out_response).await?;
+                output_message_parts(self, &ctx, &message).await?;
+
+                if let Some(refusal) = &c.message.refusal {
+                    self.output(ctx.clone(), PIN_REFUSAL, refusal_value(&message, refusal))
+                        .await?;
+                }
             }
 
             return Ok(());
-        } else {
-            let res = client
-                .chat()
-                .create(request)
-                .await
-                .map_err(|e| AgentError::IoError(format!("OpenAI Error: {}", e)))?;
+        }
+    }
+}
+
+impl OpenAIChatAgent {
+    fn retry_policy(&self) -> Result<RetryPolicy, AgentError> {
+        Ok(RetryPolicy::new(
+            self.configs()?.get_integer_or_default(CONFIG_MAX_RETRIES),
+            self.configs()?.get_integer_or_default(CONFIG_RETRY_BASE_MS),
+        ))
+    }
+
+    async fn send_chat_stream(
+        &self,
+        ctx: &AgentContext,
+        client: &Client<OpenAIClientConfig>,
+        request: CreateChatCompletionRequest,
+        id: &str,
+    ) -> Result<Message, AgentError> {
+        let pretty_response = self.configs()?.get_bool_or_default(CONFIG_PRETTY_RESPONSE);
+        let redact_response = self.configs()?.get_bool_or_default(CONFIG_REDACT_RESPONSE);
+        let mut responses =
+            StreamResponses::new(self.configs()?.get_bool_or_default(CONFIG_STREAM_RESPONSE));
+        let deadline = first_token_deadline(
+            self.configs()?
+                .get_integer_or_default(CONFIG_FIRST_TOKEN_TIMEOUT_SECS),
+        );
+
+        // Rate limit and server errors arrive as the first item of the stream,
+        // so the start of the stream is retried up to the first item.
+        let start = retry_transient(
+            self.retry_policy()?,
+            "OpenAI Stream Error",
+            || async {
+                let mut stream = client.chat().create_stream(request.clone()).await?;
+                match stream.next().await {
+                    Some(Err(e)) => Err(e),
+                    first => Ok((stream, first)),
+                }
+            },
+            is_transient_error,
+        );
+        let (mut stream, first) = first_token_before(start, deadline).await??;
+        let mut first = Some(first);
+
+        let mut message = Message::assistant("".to_string());
+        message.id = Some(id.to_string());
+        let mut content = String::new();
+        let mut thinking = String::new();
+        let mut refusal = String::new();
+        let mut tool_calls = ToolCallChunks::default();
+        loop {
+            let res = match first.take() {
+                Some(first) => first,
+                None => stream.next().await,
+            };
+            let Some(res) = res else {
+                break;
+            };
+            let res = res.map_err(|_| AgentError::IoError("OpenAI Stream Error".to_string()))?;
 
             for c in &res.choices {
-                let mut message: Message = message_from_openai_msg(c.message.clone());
-                message.id = Some(id.clone());
+                if let Some(ref delta_content) = c.delta.content {
+                    content.push_str(delta_content);
+                }
+                if let Some(tc) = &c.delta.tool_calls {
+                    for call in tc {
+                        tool_calls.push(call);
+                    }
+                }
+                if let Some(delta_refusal) = &c.delta.refusal {
+                    thinking.push_str(&format!("Refusal: {}", delta_refusal));
+                    refusal.push_str(delta_refusal);
+                }
+                if c.finish_reason.is_some() {
+                    tool_calls.finish();
+                }
+            }
 
-                self.output(ctx.clone(), PIN_MESSAGE, message.clone().into())
-                    .await?;
+            message.content = content.clone();
+            if !thinking.is_empty() {
+                message.thinking = Some(thinking.clone());
+            }
+            // Tool calls are attached only once their arguments are complete
+            if let Some(calls) = tool_calls.tool_calls()? {
+                message.tool_calls = Some(calls.into());
+            }
+
+            self.output(ctx.clone(), PIN_MESSAGE, message.clone().into())
+                .await?;
 
-                let out_response = AgentValue::from_serialize(&res)?;
+            if let Some(res) = responses.push(res) {
+                let out_response = response_value(&res, pretty_response, redact_response)?;
                 self.output(ctx.clone(), PIN_RESPONSE, out_response).await?;
             }
+        }
 
-            return Ok(());
+        if let Some(res) = responses.finish() {
+            let out_response = response_value(&res, pretty_response, redact_response)?;
+            self.output(ctx.clone(), PIN_RESPONSE, out_response).await?;
+        }
+
+        // The stream may end without a finish_reason
+        if message.tool_calls.is_none() {
+            tool_calls.finish();
+            if let Some(calls) = tool_calls.tool_calls()? {
+                message.tool_calls = Some(calls.into());
+                self.output(ctx.clone(), PIN_MESSAGE, message.clone().into())
+                    .await?;
+            }
+        }
+
+        if !refusal.is_empty() {
+            self.output(ctx.clone(), PIN_REFUSAL, refusal_value(&message, &refusal))
+                .await?;
+        }
+
+        Ok(message)
+    }
+}
+
+// OpenAI Structured Output Agent
+//
+// The model is made to call a single tool whose parameters are the schema,
+// and the arguments of the call are the output.
+#[askit_agent(
+    title="Structured Output",
+    category=CATEGORY,
+    inputs=[PIN_MESSAGE],
+    outputs=[PIN_VALUE, PIN_RESPONSE],
+    string_config(name=CONFIG_MODEL),
+    object_config(name=CONFIG_SCHEMA, title="Schema"),
+    boolean_config(name=CONFIG_PRETTY_RESPONSE, title="Pretty Response"),
+    boolean_config(name=CONFIG_REDACT_RESPONSE, title="Redact Response"),
+    integer_config(name=CONFIG_MAX_RETRIES, default=3, title="Max Retries"),
+    integer_config(name=CONFIG_RETRY_BASE_MS, default=500, title="Retry Base Delay (ms)"),
+)]
+pub struct OpenAIStructuredOutputAgent {
+    data: AgentData,
+    manager: OpenAIManager,
+}
+
+impl OpenAIStructuredOutputAgent {
+    fn schema(&self) -> Result<serde_json::Value, AgentError> {
+        let schema = self
+            .configs()?
+            .get(CONFIG_SCHEMA)
+            .ok()
+            .and_then(|v| serde_json::to_value(v).ok())
+            .filter(|v| v.as_object().is_some_and(|obj| !obj.is_empty()))
+            .ok_or_else(|| AgentError::InvalidConfig("schema is required".to_string()))?;
+        validate_tool_parameters(&schema)?;
+        Ok(schema)
+    }
+}
+
+#[async_trait]
+impl AsAgent for OpenAIStructuredOutputAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        Ok(Self {
+            data: AgentData::new(askit, id, spec),
+            manager: OpenAIManager::new(),
+        })
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let mut config_model = self.configs()?.get_string_or_default(CONFIG_MODEL);
+        if config_model.is_empty() {
+            config_model = default_chat_model(self.askit());
         }
+        let schema = self.schema()?;
+
+        let Some(messages) = prompt_messages(self.configs()?, value)? else {
+            return Ok(());
+        };
+        let messages = messages
+            .iter()
+            .filter_map(|m| m.as_message())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let request = structured_output_request(&config_model, &messages, &schema)?;
+        let client = self.manager.get_client(self.askit())?;
+        let retry_policy = RetryPolicy::new(
+            self.configs()?.get_integer_or_default(CONFIG_MAX_RETRIES),
+            self.configs()?.get_integer_or_default(CONFIG_RETRY_BASE_MS),
+        );
+        let res = retry_transient(
+            retry_policy,
+            "OpenAI Error",
+            || async { client.chat().create(request.clone()).await },
+            is_transient_error,
+        )
+        .await?;
+
+        let message = res
+            .choices
+            .first()
+            .map(|c| message_from_openai_msg(c.message.clone()))
+            .ok_or_else(|| AgentError::IoError("OpenAI returned no choices".to_string()))?;
+        let output = structured_output_value(&message, &schema)?;
+        self.output(ctx.clone(), PIN_VALUE, output).await?;
+
+        let pretty_response = self.configs()?.get_bool_or_default(CONFIG_PRETTY_RESPONSE);
+        let redact_response = self.configs()?.get_bool_or_default(CONFIG_REDACT_RESPONSE);
+        let out_response = response_value(&res, pretty_response, redact_response)?;
+        self.output(ctx, PIN_RESPONSE, out_response).await
     }
 }
 
+/// Build a request that makes the model call the structured output tool.
+///
+/// The tool is only part of this request; it is not registered, so other
+/// agents never see it and there is nothing to clean up.
+fn structured_output_request(
+    model: &str,
+    messages: &[Message],
+    schema: &serde_json::Value,
+) -> Result<CreateChatCompletionRequest, AgentError> {
+    let tool = try_from_tool_info_to_chat_completion_tool(tool::ToolInfo {
+        name: STRUCTURED_OUTPUT_TOOL_NAME.to_string(),
+        description: "Respond with data that follows the parameter schema.".to_string(),
+        parameters: Some(schema.clone()),
+    })?;
+    CreateChatCompletionRequestArgs::default()
+        .model(model)
+        .messages(messages_to_chat_completion_msgs(messages, ImageFormat::default()))
+        .tools(vec![tool])
+        .tool_choice(ChatCompletionToolChoiceOption::Named(
+            ChatCompletionNamedToolChoice {
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionName {
+                    name: STRUCTURED_OUTPUT_TOOL_NAME.to_string(),
+                },
+            },
+        ))
+        .build()
+        .map_err(|e| AgentError::InvalidValue(format!("Failed to build request: {}", e)))
+}
+
+/// The arguments of the structured output tool call, checked against the schema.
+fn structured_output_value(
+    message: &Message,
+    schema: &serde_json::Value,
+) -> Result<AgentValue, AgentError> {
+    let call = message
+        .tool_calls
+        .iter()
+        .flatten()
+        .find(|call| call.function.name == STRUCTURED_OUTPUT_TOOL_NAME)
+        .ok_or_else(|| {
+            AgentError::InvalidValue("The model did not return structured output".to_string())
+        })?;
+    check_tool_arguments(schema, &call.function.parameters)?;
+    AgentValue::from_json(call.function.parameters.clone())
+}
+
 // OpenAI Embeddings Agent
 #[askit_agent(
     title="Embeddings",
@@ -409,7 +858,11 @@ impl AsAgent for OpenAIChatAgent {
     inputs=[PIN_STRING, PIN_CHUNKS, PIN_DOC],
     outputs=[PIN_EMBEDDING, PIN_EMBEDDINGS, PIN_DOC],
     string_config(name=CONFIG_MODEL, default="text-embedding-3-small"),
-    object_config(name=CONFIG_OPTIONS)
+    object_config(name=CONFIG_OPTIONS),
+    string_config(name=CONFIG_ENCODING, default=EMBEDDING_ENCODING_FLOAT, title="Encoding"),
+    string_config(name=CONFIG_INPUT_TYPE, default=INPUT_TYPE_NONE, title="Input Type"),
+    integer_config(name=CONFIG_BATCH_SIZE, title="Batch Size"),
+    integer_config(name=CONFIG_CONCURRENCY, default=4, title="Concurrency"),
 )]
 pub struct OpenAIEmbeddingsAgent {
     data: AgentData,
@@ -422,6 +875,11 @@ impl OpenAIEmbeddingsAgent {
         texts: Vec<String>,
         model_name: &str,
     ) -> Result<Vec<Vec<f32>>, AgentError> {
+        let input_type: InputType = self
+            .configs()?
+            .get_string_or_default(CONFIG_INPUT_TYPE)
+            .parse()?;
+        let texts = with_input_prefix(texts, model_name, input_type);
         let client = self.manager.get_client(self.askit())?;
         let mut request = CreateEmbeddingRequestArgs::default()
             .model(model_name.to_string())
@@ -456,6 +914,20 @@ impl OpenAIEmbeddingsAgent {
 
         Ok(res.data.into_iter().map(|d| d.embedding).collect())
     }
+
+    /// Split large inputs into sub-batches according to batch_size and concurrency.
+    async fn generate_embeddings_batched(
+        &self,
+        texts: Vec<String>,
+        model_name: &str,
+    ) -> Result<Vec<Vec<f32>>, AgentError> {
+        let batch_size = self.configs()?.get_integer_or_default(CONFIG_BATCH_SIZE).max(0);
+        let concurrency = self.configs()?.get_integer_or_default(CONFIG_CONCURRENCY).max(1);
+        embed_in_batches(texts, batch_size as usize, concurrency as usize, |batch| {
+            self.generate_embeddings(batch, model_name)
+        })
+        .await
+    }
 }
 
 #[async_trait]
@@ -478,16 +950,30 @@ impl AsAgent for OpenAIEmbeddingsAgent {
             return Err(AgentError::InvalidConfig("model is not set".to_string()));
         }
 
+        let encoding: EmbeddingEncoding = self
+            .configs()?
+            .get_string_or_default(CONFIG_ENCODING)
+            .parse()?;
+
         if pin == PIN_STRING {
-            let text = value.as_str().unwrap_or_default();
-            if text.is_empty() {
-                return Err(AgentError::InvalidValue(
-                    "Input text is an empty string".to_string(),
-                ));
+            let texts = embedding_input_texts(&value)?;
+            if value.is_array() {
+                // One embedding per string, in the order of the input
+                if texts.is_empty() {
+                    return self
+                        .output(ctx, PIN_EMBEDDING, AgentValue::array_default())
+                        .await;
+                }
+                let embeddings = self.generate_embeddings_batched(texts, config_model).await?;
+                let values = embeddings
+                    .into_iter()
+                    .map(|emb| embedding_value(emb, encoding))
+                    .collect();
+                return self
+                    .output(ctx, PIN_EMBEDDING, AgentValue::array(values))
+                    .await;
             }
-            let embeddings = self
-                .generate_embeddings(vec![text.to_string()], config_model)
-                .await?;
+            let embeddings = self.generate_embeddings(texts, config_model).await?;
             if embeddings.len() != 1 {
                 return Err(AgentError::Other(
                     "Expected exactly one embedding for single string input".to_string(),
@@ -497,7 +983,7 @@ impl AsAgent for OpenAIEmbeddingsAgent {
                 .output(
                     ctx,
                     PIN_EMBEDDING,
-                    AgentValue::tensor(embeddings.into_iter().next().unwrap()),
+                    embedding_value(embeddings.into_iter().next().unwrap(), encoding),
                 )
                 .await;
         }
@@ -544,14 +1030,14 @@ impl AsAgent for OpenAIEmbeddingsAgent {
                     .output(ctx.clone(), PIN_EMBEDDINGS, AgentValue::array_default())
                     .await;
             }
-            let embeddings = self.generate_embeddings(texts, config_model).await?;
+            let embeddings = self.generate_embeddings_batched(texts, config_model).await?;
             let embedding_values_with_offsets: im::Vector<AgentValue> = offsets
                 .into_iter()
                 .zip(embeddings)
                 .map(|(offset, emb)| {
                     AgentValue::array(vector![
                         AgentValue::integer(offset),
-                        AgentValue::tensor(emb)
+                        embedding_value(emb, encoding)
                     ])
                 })
                 .collect();
@@ -596,7 +1082,7 @@ impl AsAgent for OpenAIEmbeddingsAgent {
                 ));
             }
 
-            let embeddings = self.generate_embeddings(texts, config_model).await?;
+            let embeddings = self.generate_embeddings_batched(texts, config_model).await?;
             if embeddings.len() != indices.len() {
                 return Err(AgentError::Other(
                     "Mismatch between number of embeddings and texts".to_string(),
@@ -606,7 +1092,7 @@ impl AsAgent for OpenAIEmbeddingsAgent {
             if value.is_object() {
                 let embedding = embeddings.into_iter().next().unwrap();
                 let mut output = value.clone();
-                output.set("embedding".to_string(), AgentValue::tensor(embedding))?;
+                output.set("embedding".to_string(), embedding_value(embedding, encoding))?;
                 return self.output(ctx.clone(), PIN_DOC, output).await;
             } else {
                 let mut arr = value.clone().into_array().unwrap();
@@ -615,7 +1101,7 @@ impl AsAgent for OpenAIEmbeddingsAgent {
                     let index = indices[i];
                     arr[index as usize].set(
                         "embedding".to_string(),
-                        AgentValue::tensor(embedding.clone()),
+                        embedding_value(embedding.clone(), encoding),
                     )?;
                 }
                 return self
@@ -835,6 +1321,69 @@ impl AsAgent for OpenAIEmbeddingsAgent {
 //     output_text
 // }
 
+/// Map a thinking token budget onto OpenAI's reasoning effort.
+///
+/// OpenAI does not accept an exact reasoning token limit, so the budget is bucketed.
+/// A budget of 0 or less leaves the model default.
+fn reasoning_effort_from_budget(max_thinking_tokens: i64) -> Option<ReasoningEffort> {
+    match max_thinking_tokens {
+        i64::MIN..=0 => None,
+        1..=1024 => Some(ReasoningEffort::Minimal),
+        1025..=4096 => Some(ReasoningEffort::Low),
+        4097..=16384 => Some(ReasoningEffort::Medium),
+        _ => Some(ReasoningEffort::High),
+    }
+}
+
+/// Build the completion prompts, prefixing each with the system text if any.
+fn completion_prompts(system: &str, messages: &[Message]) -> Vec<String> {
+    messages
+        .iter()
+        .map(|m| {
+            if system.is_empty() {
+                m.content.clone()
+            } else {
+                format!("{}\n\n{}", system, m.content)
+            }
+        })
+        .collect()
+}
+
+/// Send the messages in a single non-streaming request and return the reply text.
+pub(crate) async fn chat_once(
+    askit: &ASKit,
+    model: &str,
+    messages: &[Message],
+) -> Result<String, AgentError> {
+    let client = OpenAIManager::new().get_client(askit)?;
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model)
+        .messages(
+            messages_to_chat_completion_msgs(messages, ImageFormat::default()),
+        )
+        .build()
+        .map_err(|e| AgentError::InvalidValue(format!("Failed to build request: {}", e)))?;
+    let res = client
+        .chat()
+        .create(request)
+        .await
+        .map_err(|e| AgentError::IoError(format!("OpenAI Error: {}", e)))?;
+    Ok(res
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|c| c.message.content)
+        .unwrap_or_default())
+}
+
+/// Value emitted on the refusal pin: `{ message, refusal }`.
+fn refusal_value(message: &Message, refusal: &str) -> AgentValue {
+    AgentValue::object(hashmap! {
+        "message".into() => AgentValue::message(message.clone()),
+        "refusal".into() => AgentValue::string(refusal),
+    })
+}
+
 fn message_from_openai_msg(msg: ChatCompletionResponseMessage) -> Message {
     let role = match msg.role {
         Role::System => "system",
@@ -869,7 +1418,30 @@ fn message_from_openai_msg(msg: ChatCompletionResponseMessage) -> Message {
     message
 }
 
-fn message_to_chat_completion_msg(msg: &Message) -> ChatCompletionRequestMessage {
+/// Convert messages for a chat completion request, giving each tool result
+/// the id of the tool call it answers.
+fn messages_to_chat_completion_msgs(
+    messages: &[Message],
+    image_format: ImageFormat,
+) -> Vec<ChatCompletionRequestMessage> {
+    messages
+        .iter()
+        .zip(tool_result_ids(messages))
+        .map(|(msg, id)| {
+            let mut cmsg = message_to_chat_completion_msg(msg, image_format);
+            if let (ChatCompletionRequestMessage::Tool(tool), Some(id)) = (&mut cmsg, id) {
+                tool.tool_call_id = id;
+            }
+            cmsg
+        })
+        .collect()
+}
+
+#[cfg_attr(not(feature = "image"), allow(unused_variables))]
+fn message_to_chat_completion_msg(
+    msg: &Message,
+    image_format: ImageFormat,
+) -> ChatCompletionRequestMessage {
     match msg.role.as_str() {
         "system" => ChatCompletionRequestSystemMessageArgs::default()
             .content(msg.content.clone())
@@ -886,7 +1458,7 @@ fn message_to_chat_completion_msg(msg: &Message) -> ChatCompletionRequestMessage
                     };
 
                     let image_url = ImageUrl {
-                        url: image.get_base64(),
+                        url: crate::image::to_data_url(image, image_format),
                         detail: Some(async_openai::types::ImageDetail::Auto),
                     };
                     let img = ChatCompletionRequestMessageContentPartImage { image_url };
@@ -907,12 +1479,28 @@ fn message_to_chat_completion_msg(msg: &Message) -> ChatCompletionRequestMessage
                 .unwrap()
                 .into()
         }
-        "assistant" => ChatCompletionRequestAssistantMessageArgs::default()
-            .content(msg.content.clone())
-            .build()
-            .unwrap()
-            .into(),
-        "tool" => ChatCompletionRequestToolMessageArgs::default()
+        "assistant" => {
+            let mut args = ChatCompletionRequestAssistantMessageArgs::default();
+            let tool_calls = msg
+                .tool_calls
+                .iter()
+                .flatten()
+                .enumerate()
+                .map(|(i, call)| tool_call_to_chat_completion_tool_call(call, i))
+                .collect::<Vec<_>>();
+            // Thinking is only present with resend_thinking
+            let content = content_with_thinking(msg);
+            // OpenAI rejects an empty content string alongside tool calls, so leave it null
+            if tool_calls.is_empty() || !content.is_empty() {
+                args.content(content);
+            }
+            if !tool_calls.is_empty() {
+                args.tool_calls(tool_calls);
+            }
+            args.build().unwrap().into()
+        }
+        // The legacy function role is sent as a tool message
+        "tool" | "function" => ChatCompletionRequestToolMessageArgs::default()
             .content(msg.content.clone())
             .build()
             .unwrap()
@@ -976,7 +1564,7 @@ fn try_from_tool_info_to_chat_completion_tool(
     info: tool::ToolInfo,
 ) -> Result<ChatCompletionTool, AgentError> {
     let mut function = FunctionObjectArgs::default();
-    function.name(info.name);
+    function.name(provider_tool_name(&info.name));
     if !info.description.is_empty() {
         function.description(info.description);
     }
@@ -986,12 +1574,12 @@ fn try_from_tool_info_to_chat_completion_tool(
         // })?);
         function.parameters(params);
     }
-    Ok(ChatCompletionToolArgs::default()
+    ChatCompletionToolArgs::default()
         .function(function.build().map_err(|e| {
             AgentError::InvalidValue(format!("Failed to build tool function: {}", e))
         })?)
         .build()
-        .map_err(|e| AgentError::InvalidValue(format!("Failed to build tool: {}", e)))?)
+        .map_err(|e| AgentError::InvalidValue(format!("Failed to build tool: {}", e)))
 }
 
 // impl TryFrom<tool::ToolInfo> for ToolDefinition {
@@ -1015,33 +1603,107 @@ fn try_from_tool_info_to_chat_completion_tool(
 //     }
 // }
 
-fn try_from_chat_completion_message_tool_call_chunk_to_tool_call(
-    call: &ChatCompletionMessageToolCallChunk,
-) -> Result<ToolCall, AgentError> {
-    let Some(function) = &call.function else {
-        return Err(AgentError::InvalidValue(
-            "ToolCallChunk missing function".to_string(),
-        ));
-    };
-    let Some(name) = &function.name else {
-        return Err(AgentError::InvalidValue(
-            "ToolCallChunk function missing name".to_string(),
-        ));
-    };
-    let parameters = if let Some(arguments) = &function.arguments {
-        serde_json::from_str(arguments).map_err(|e| {
-            AgentError::InvalidValue(format!("Failed to parse tool call arguments JSON: {}", e))
-        })?
-    } else {
-        serde_json::json!({})
-    };
+/// Tool calls assembled from streamed chunks.
+///
+/// OpenAI streams the arguments of each tool call as JSON fragments spread over
+/// several chunks, keyed by the call index. The calls are only parsed once the
+/// stream signals they are complete, so a partial fragment is never acted on.
+#[derive(Default)]
+struct ToolCallChunks {
+    // index -> (id, name, arguments)
+    calls: BTreeMap<u32, (Option<String>, String, String)>,
+    finished: bool,
+}
 
-    let function = ToolCallFunction {
-        id: call.id.clone(),
-        name: name.clone(),
-        parameters,
-    };
-    Ok(ToolCall { function })
+impl ToolCallChunks {
+    fn push(&mut self, chunk: &ChatCompletionMessageToolCallChunk) {
+        let (id, name, arguments) = self.calls.entry(chunk.index).or_default();
+        if let Some(chunk_id) = &chunk.id {
+            *id = Some(chunk_id.clone());
+        }
+        if let Some(function) = &chunk.function {
+            if let Some(chunk_name) = &function.name {
+                name.push_str(chunk_name);
+            }
+            if let Some(chunk_arguments) = &function.arguments {
+                arguments.push_str(chunk_arguments);
+            }
+        }
+    }
+
+    /// Mark the tool calls as complete, on a finish_reason or at the end of the stream.
+    fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    /// The completed tool calls, or None while they are still streaming.
+    fn tool_calls(&self) -> Result<Option<Vec<ToolCall>>, AgentError> {
+        if !self.finished || self.calls.is_empty() {
+            return Ok(None);
+        }
+        let mut tool_calls = Vec::with_capacity(self.calls.len());
+        for (id, name, arguments) in self.calls.values() {
+            if name.is_empty() {
+                return Err(AgentError::InvalidValue(
+                    "ToolCallChunk function missing name".to_string(),
+                ));
+            }
+            let parameters = if arguments.trim().is_empty() {
+                serde_json::json!({})
+            } else {
+                serde_json::from_str(arguments).map_err(|e| {
+                    AgentError::InvalidValue(format!(
+                        "Failed to parse tool call arguments JSON: {}",
+                        e
+                    ))
+                })?
+            };
+            tool_calls.push(ToolCall {
+                function: ToolCallFunction {
+                    id: id.clone(),
+                    name: registry_tool_name(name),
+                    parameters,
+                },
+            });
+        }
+        Ok(Some(tool_calls))
+    }
+}
+
+/// Whether the error is worth retrying: rate limits, server errors and connection failures.
+///
+/// Client errors such as a bad request or an invalid API key fail fast.
+fn is_transient_error(e: &OpenAIError) -> bool {
+    match e {
+        OpenAIError::Reqwest(e) => {
+            e.is_timeout()
+                || e.is_connect()
+                || e
+                    .status()
+                    .is_some_and(|s| s.as_u16() == 429 || s.is_server_error())
+        }
+        OpenAIError::ApiError(e) => {
+            // Server errors are not JSON, so they come without a type or code
+            (e.r#type.is_none() && e.code.is_none())
+                || e.code.as_deref() == Some("rate_limit_exceeded")
+                || e.r#type.as_deref() == Some("server_error")
+        }
+        _ => false,
+    }
+}
+
+fn tool_call_to_chat_completion_tool_call(
+    call: &ToolCall,
+    index: usize,
+) -> ChatCompletionMessageToolCall {
+    ChatCompletionMessageToolCall {
+        id: tool_call_id(call, index),
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionCall {
+            name: provider_tool_name(&call.function.name),
+            arguments: call.function.parameters.to_string(),
+        },
+    }
 }
 
 fn try_from_chat_completion_message_tool_call_to_tool_call(
@@ -1053,8 +1715,396 @@ fn try_from_chat_completion_message_tool_call_to_tool_call(
 
     let function = ToolCallFunction {
         id: Some(call.id.clone()),
-        name: call.function.name.clone(),
+        name: registry_tool_name(&call.function.name),
         parameters,
     };
     Ok(ToolCall { function })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_completion_error_on_empty() {
+        let askit = ASKit::init().unwrap();
+        let spec = askit
+            .new_agent_spec(OpenAICompletionAgent::DEF_NAME)
+            .unwrap();
+        let mut agent =
+            <OpenAICompletionAgent as Agent>::new(askit, "completion".into(), spec).unwrap();
+
+        // silent by default
+        let res = AsAgent::process(
+            &mut agent,
+            AgentContext::new(),
+            PIN_PROMPT.into(),
+            AgentValue::string(""),
+        )
+        .await;
+        assert!(res.is_ok());
+
+        agent
+            .set_config(CONFIG_ERROR_ON_EMPTY.into(), AgentValue::boolean(true))
+            .unwrap();
+        let res = AsAgent::process(
+            &mut agent,
+            AgentContext::new(),
+            PIN_PROMPT.into(),
+            AgentValue::string(""),
+        )
+        .await;
+        assert!(matches!(res, Err(AgentError::InvalidValue(_))));
+    }
+
+    #[test]
+    fn test_extra_headers() {
+        let askit = ASKit::init().unwrap();
+        let headers =
+            parse_extra_headers(r#"{"X-Gateway-Route": "team-a", "X-Api-Token": "secret"}"#)
+                .unwrap();
+        let client = OpenAIManager::new()
+            .get_client_with_headers(&askit, headers)
+            .unwrap();
+        let headers = client.config().headers();
+        assert_eq!(headers["x-gateway-route"], "team-a");
+        assert_eq!(headers["x-api-token"], "secret");
+        assert!(headers.contains_key("authorization"));
+
+        assert!(!headers.contains_key(USER_AGENT));
+
+        let mut configs = agent_stream_kit::AgentConfigs::new();
+        configs.set(
+            CONFIG_USER_AGENT.to_string(),
+            AgentValue::string("my-app/1.0"),
+        );
+        askit.set_global_configs(OpenAICompletionAgent::DEF_NAME.to_string(), configs);
+        let client = OpenAIManager::new().get_client(&askit).unwrap();
+        assert_eq!(client.config().headers()[USER_AGENT], "my-app/1.0");
+
+        assert!(parse_extra_headers("").unwrap().is_empty());
+        assert!(parse_extra_headers("[]").is_err());
+        assert!(parse_extra_headers(r#"{"X-Nested": {"a": "b"}}"#).is_err());
+        assert!(parse_extra_headers(r#"{"X-Count": 1}"#).is_err());
+        assert!(parse_extra_headers(r#"{"bad header": "x"}"#).is_err());
+    }
+
+    fn tool_call_chunk(
+        index: u32,
+        id: Option<&str>,
+        name: Option<&str>,
+        arguments: &str,
+    ) -> ChatCompletionMessageToolCallChunk {
+        ChatCompletionMessageToolCallChunk {
+            index,
+            id: id.map(|s| s.to_string()),
+            r#type: id.map(|_| ChatCompletionToolType::Function),
+            function: Some(async_openai::types::FunctionCallStream {
+                name: name.map(|s| s.to_string()),
+                arguments: Some(arguments.to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_tool_call_chunks() {
+        let mut chunks = ToolCallChunks::default();
+        chunks.push(&tool_call_chunk(0, Some("call_1"), Some("get_weather"), ""));
+        chunks.push(&tool_call_chunk(1, Some("call_2"), Some("get_time"), "{}"));
+        chunks.push(&tool_call_chunk(0, None, None, "{\"city\": "));
+        // not complete until the stream finishes, even if a fragment happens to parse
+        assert!(chunks.tool_calls().unwrap().is_none());
+        chunks.push(&tool_call_chunk(0, None, None, "\"Tokyo\"}"));
+        assert!(chunks.tool_calls().unwrap().is_none());
+
+        chunks.finish();
+        let calls = chunks.tool_calls().unwrap().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].function.id.as_deref(), Some("call_1"));
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(
+            calls[0].function.parameters,
+            serde_json::json!({"city": "Tokyo"})
+        );
+        assert_eq!(calls[1].function.name, "get_time");
+
+        // truncated arguments are an error rather than a partial call
+        let mut chunks = ToolCallChunks::default();
+        chunks.push(&tool_call_chunk(0, Some("call_1"), Some("f"), "{\"a\": "));
+        chunks.finish();
+        assert!(chunks.tool_calls().is_err());
+
+        let mut chunks = ToolCallChunks::default();
+        chunks.finish();
+        assert!(chunks.tool_calls().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_is_transient_error() {
+        let api_error = |r#type: Option<&str>, code: Option<&str>| {
+            OpenAIError::ApiError(async_openai::error::ApiError {
+                message: "error".to_string(),
+                r#type: r#type.map(|s| s.to_string()),
+                param: None,
+                code: code.map(|s| s.to_string()),
+            })
+        };
+        // 5xx bodies are passed through without a type or code
+        assert!(is_transient_error(&api_error(None, None)));
+        assert!(is_transient_error(&api_error(
+            Some("tokens"),
+            Some("rate_limit_exceeded")
+        )));
+        assert!(is_transient_error(&api_error(Some("server_error"), None)));
+
+        assert!(!is_transient_error(&api_error(
+            Some("insufficient_quota"),
+            Some("insufficient_quota")
+        )));
+        assert!(!is_transient_error(&api_error(
+            Some("invalid_request_error"),
+            Some("invalid_api_key")
+        )));
+        assert!(!is_transient_error(&OpenAIError::InvalidArgument(
+            "bad".to_string()
+        )));
+    }
+
+    #[test]
+    fn test_assistant_tool_call_content() {
+        let mut message = Message::assistant("".to_string());
+        message.tool_calls = Some(im::vector![ToolCall {
+            function: ToolCallFunction {
+                id: Some("call_1".to_string()),
+                name: "get_weather".to_string(),
+                parameters: serde_json::json!({"city": "Tokyo"}),
+            },
+        }]);
+        let json =
+            serde_json::to_value(message_to_chat_completion_msg(&message, ImageFormat::default()))
+                .unwrap();
+        assert!(json.get("content").is_none_or(|c| c.is_null()), "{}", json);
+        assert_eq!(json["tool_calls"][0]["id"], "call_1");
+        assert_eq!(json["tool_calls"][0]["function"]["name"], "get_weather");
+        assert_eq!(
+            json["tool_calls"][0]["function"]["arguments"],
+            r#"{"city":"Tokyo"}"#
+        );
+
+        // Plain assistant messages keep their content
+        let message = Message::assistant("".to_string());
+        let json =
+            serde_json::to_value(message_to_chat_completion_msg(&message, ImageFormat::default()))
+                .unwrap();
+        assert_eq!(json["content"], "");
+    }
+
+    #[test]
+    fn test_tool_call_ids() {
+        let mut assistant = Message::assistant("".to_string());
+        assistant.tool_calls = Some(im::vector![
+            ToolCall {
+                function: ToolCallFunction {
+                    id: Some("call_a".to_string()),
+                    name: "weather".to_string(),
+                    parameters: serde_json::json!({"city": "Tokyo"}),
+                },
+            },
+            ToolCall {
+                function: ToolCallFunction {
+                    id: None,
+                    name: "time".to_string(),
+                    parameters: serde_json::json!({}),
+                },
+            },
+        ]);
+        let messages = vec![
+            Message::user("Weather and time?".to_string()),
+            assistant,
+            // parallel results in a different order than the calls
+            Message::tool("time".to_string(), "10:00".to_string()),
+            Message::tool("weather".to_string(), "Sunny".to_string()),
+        ];
+
+        let json = serde_json::to_value(messages_to_chat_completion_msgs(
+            &messages,
+            ImageFormat::default(),
+        ))
+        .unwrap();
+        assert_eq!(json[1]["tool_calls"][0]["id"], "call_a");
+        assert_eq!(json[1]["tool_calls"][1]["id"], "call_1");
+        assert_eq!(json[2]["tool_call_id"], "call_1");
+        assert_eq!(json[3]["tool_call_id"], "call_a");
+    }
+
+    #[test]
+    fn test_function_role() {
+        let msg: ChatCompletionResponseMessage = serde_json::from_value(serde_json::json!({
+            "role": "function",
+            "content": "{\"temperature\": 21}",
+        }))
+        .unwrap();
+        let message = message_from_openai_msg(msg);
+        assert_eq!(message.role, "function");
+
+        match message_to_chat_completion_msg(&message, ImageFormat::default()) {
+            ChatCompletionRequestMessage::Tool(tool) => {
+                let content = serde_json::to_value(&tool.content).unwrap();
+                assert_eq!(content, serde_json::json!("{\"temperature\": 21}"));
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_refusal() {
+        let msg: ChatCompletionResponseMessage = serde_json::from_value(serde_json::json!({
+            "role": "assistant",
+            "content": null,
+            "refusal": "I can't help with that.",
+        }))
+        .unwrap();
+        let refusal = msg.refusal.clone().unwrap();
+        let message = message_from_openai_msg(msg);
+        // The thinking annotation is kept
+        assert_eq!(
+            message.thinking.as_deref(),
+            Some("Refusal: I can't help with that.")
+        );
+
+        let value = refusal_value(&message, &refusal);
+        assert_eq!(value.get_str("refusal"), Some("I can't help with that."));
+        assert_eq!(value.get("message").unwrap().as_message().unwrap().role, "assistant");
+    }
+
+    #[test]
+    fn test_completion_prompts() {
+        let messages = vec![Message::user("Once upon a time".to_string())];
+        assert_eq!(
+            completion_prompts("", &messages),
+            vec!["Once upon a time".to_string()]
+        );
+        assert_eq!(
+            completion_prompts("Write a fairy tale.", &messages),
+            vec!["Write a fairy tale.\n\nOnce upon a time".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_reasoning_effort_from_budget() {
+        assert_eq!(reasoning_effort_from_budget(0), None);
+        assert_eq!(
+            reasoning_effort_from_budget(512),
+            Some(ReasoningEffort::Minimal)
+        );
+        assert_eq!(
+            reasoning_effort_from_budget(2048),
+            Some(ReasoningEffort::Low)
+        );
+        assert_eq!(
+            reasoning_effort_from_budget(8192),
+            Some(ReasoningEffort::Medium)
+        );
+        assert_eq!(
+            reasoning_effort_from_budget(100000),
+            Some(ReasoningEffort::High)
+        );
+
+        // The budget lands in the serialized request
+        let mut request = CreateChatCompletionRequestArgs::default()
+            .model(DEFAULT_CONFIG_MODEL)
+            .messages(Vec::<ChatCompletionRequestMessage>::new())
+            .build()
+            .unwrap();
+        request.reasoning_effort = reasoning_effort_from_budget(2048);
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["reasoning_effort"], "low");
+    }
+
+    #[test]
+    fn test_structured_output() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "tags": {"type": "array", "items": {"type": "string"}}
+            },
+            "required": ["name", "age"]
+        });
+
+        // the model is made to call the tool with the schema as its parameters
+        let messages = vec![Message::user("Alice is 30.".to_string())];
+        let request = structured_output_request("gpt-5-nano", &messages, &schema).unwrap();
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            json["tool_choice"],
+            serde_json::json!({"type": "function", "function": {"name": "structured_output"}})
+        );
+        assert_eq!(json["tools"][0]["function"]["name"], STRUCTURED_OUTPUT_TOOL_NAME);
+        assert_eq!(json["tools"][0]["function"]["parameters"], schema);
+
+        let reply = |parameters: serde_json::Value| {
+            let mut message = Message::assistant("".to_string());
+            message.tool_calls = Some(vector![ToolCall {
+                function: ToolCallFunction {
+                    id: Some("call_1".to_string()),
+                    name: STRUCTURED_OUTPUT_TOOL_NAME.to_string(),
+                    parameters,
+                },
+            }]);
+            message
+        };
+
+        let value = structured_output_value(
+            &reply(serde_json::json!({"name": "Alice", "age": 30, "tags": ["a"]})),
+            &schema,
+        )
+        .unwrap();
+        assert_eq!(value.get_str("name"), Some("Alice"));
+        assert_eq!(value.get_i64("age"), Some(30));
+
+        // output that doesn't match the schema is an error
+        let missing = reply(serde_json::json!({"name": "Alice"}));
+        assert!(structured_output_value(&missing, &schema).is_err());
+        let mistyped = reply(serde_json::json!({"name": "Alice", "age": "30"}));
+        assert!(structured_output_value(&mistyped, &schema).is_err());
+        let no_call = Message::assistant("Alice, 30".to_string());
+        assert!(structured_output_value(&no_call, &schema).is_err());
+    }
+
+    #[test]
+    fn test_mcp_tool_names() {
+        crate::tool::tests::register_test_tool("test_openai_server::search");
+        let valid = regex::Regex::new("^[a-zA-Z0-9_-]{1,64}$").unwrap();
+
+        // the tool list sent to OpenAI uses the provider-safe name
+        let tools = configured_tool_infos("^test_openai_server::")
+            .unwrap()
+            .into_iter()
+            .map(try_from_tool_info_to_chat_completion_tool)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(tools.len(), 1);
+        assert_eq!(tools[0].function.name, "test_openai_server__search");
+        assert!(valid.is_match(&tools[0].function.name));
+
+        // a returned call is mapped back to the registry name
+        let call = ChatCompletionMessageToolCall {
+            id: "call_1".to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: "test_openai_server__search".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        let call = try_from_chat_completion_message_tool_call_to_tool_call(&call).unwrap();
+        assert_eq!(call.function.name, "test_openai_server::search");
+
+        // and sent as the provider name again in the history
+        let sent = tool_call_to_chat_completion_tool_call(&call, 0);
+        assert_eq!(sent.function.name, "test_openai_server__search");
+
+        agent_stream_kit::tool::unregister_tool("test_openai_server::search");
+    }
+}
@@ -0,0 +1,1042 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use agent_stream_kit::tool::{
+    Tool, ToolInfo, get_tool, list_tool_infos, list_tool_infos_patterns, register_tool,
+    unregister_tool,
+};
+use agent_stream_kit::{
+    ASKit, Agent, AgentContext, AgentData, AgentError, AgentOutput, AgentSpec, AgentValue, AsAgent,
+    Message, ToolCall, askit_agent, async_trait,
+};
+use futures::{StreamExt, TryStreamExt, stream};
+use im::{Vector, hashmap};
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+const CATEGORY: &str = "LLM/Tool";
+
+const PIN_APPROVAL_REQUEST: &str = "approval_request";
+const PIN_APPROVAL_RESPONSE: &str = "approval_response";
+const PIN_MESSAGE: &str = "message";
+
+const CONFIG_CONCURRENCY: &str = "concurrency";
+const CONFIG_TOOL_NAME: &str = "name";
+const CONFIG_TOOL_DESCRIPTION: &str = "description";
+const CONFIG_TOOL_PARAMETERS: &str = "parameters";
+const CONFIG_TIMEOUT_SECS: &str = "timeout_secs";
+const CONFIG_TOOLS: &str = "tools";
+
+const DEFAULT_TIMEOUT_SECS: i64 = 300;
+const DEFAULT_TOOL_CONCURRENCY: i64 = 4;
+
+const MAX_PROVIDER_TOOL_NAME_LEN: usize = 64;
+
+type PendingMap = HashMap<String, (AgentValue, oneshot::Sender<AgentValue>)>;
+
+/// Tool calls waiting for a decision, keyed by a unique request id.
+///
+/// The id is generated per call so concurrent calls sharing the same context
+/// cannot overwrite each other.
+#[derive(Clone, Default)]
+struct PendingApprovals {
+    pending: Arc<Mutex<PendingMap>>,
+}
+
+impl PendingApprovals {
+    fn start(&self, args: AgentValue) -> (String, oneshot::Receiver<AgentValue>) {
+        let id = uuid::Uuid::new_v4().to_string();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), (args, tx));
+        (id, rx)
+    }
+
+    fn cancel(&self, id: &str) {
+        self.pending.lock().unwrap().remove(id);
+    }
+
+    fn clear(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+
+    /// Resolve a pending call with the given response.
+    ///
+    /// The response may omit the id when only one call is waiting.
+    fn resolve(&self, response: &AgentValue) -> Result<(), AgentError> {
+        let mut pending = self.pending.lock().unwrap();
+        let id = match response.get_str("id") {
+            Some(id) => id.to_string(),
+            None if pending.len() == 1 => pending.keys().next().unwrap().clone(),
+            None => {
+                return Err(AgentError::InvalidValue(
+                    "approval_response must have an id".to_string(),
+                ));
+            }
+        };
+        let Some((args, tx)) = pending.remove(&id) else {
+            return Err(AgentError::InvalidValue(format!(
+                "No pending approval request: {}",
+                id
+            )));
+        };
+        let _ = tx.send(approval_result(&args, response)?);
+        Ok(())
+    }
+}
+
+/// Build the tool result from the proposed arguments and the decision.
+///
+/// The decision is either a boolean or an object with `approved` and optional
+/// `args` (edited arguments) and `reason` fields.
+fn approval_result(args: &AgentValue, response: &AgentValue) -> Result<AgentValue, AgentError> {
+    let approved = response
+        .as_bool()
+        .or_else(|| response.get_bool("approved"))
+        .ok_or_else(|| {
+            AgentError::InvalidValue("approval_response must have an approved flag".to_string())
+        })?;
+
+    let mut result = AgentValue::object(hashmap! {
+        "approved".into() => AgentValue::boolean(approved),
+    });
+    if approved {
+        let args = response.get("args").cloned().unwrap_or_else(|| args.clone());
+        result.set("args".to_string(), args)?;
+    }
+    if let Some(reason) = response.get_str("reason") {
+        result.set("reason".to_string(), AgentValue::string(reason))?;
+    }
+    Ok(result)
+}
+
+/// The tools offered to a chat model, selected by the regex patterns of its tools config.
+///
+/// Local tools and the tools registered from MCP servers share one registry, so
+/// both are offered. MCP tools are named `<server>::<tool>`, which keeps them
+/// apart from local tools of the same name and lets a pattern select a server.
+/// OpenAI and Anthropic reject `::` in tool names, so they are sent the
+/// `provider_tool_name` of each tool, checked with `check_provider_tool_names`.
+/// An empty config selects no tools.
+pub fn configured_tool_infos(config_tools: &str) -> Result<Vec<ToolInfo>, AgentError> {
+    if config_tools.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    list_tool_infos_patterns(config_tools).map_err(|e| {
+        AgentError::InvalidConfig(format!("Invalid regex patterns in tools config: {}", e))
+    })
+}
+
+/// The name of a tool as offered to a provider.
+///
+/// OpenAI and Anthropic only accept names matching `^[a-zA-Z0-9_-]{1,64}$`, so the
+/// `::` of MCP tools becomes `__`, any other character outside the set becomes `_`,
+/// and the name is cut to 64 characters.
+pub fn provider_tool_name(name: &str) -> String {
+    name.replace("::", "__")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_PROVIDER_TOOL_NAME_LEN)
+        .collect()
+}
+
+/// Check that each tool offered to OpenAI or Anthropic has a provider name
+/// that no other registered tool maps to.
+///
+/// `provider_tool_name` is lossy, so `srv::search` and `srv__search`, or two long
+/// names with the same first 64 characters, share a provider name. The provider
+/// would reject the duplicate, or `registry_tool_name` route the call to the
+/// wrong tool.
+pub fn check_provider_tool_names(tool_infos: &[ToolInfo]) -> Result<(), AgentError> {
+    let registered = list_tool_infos();
+    for info in tool_infos {
+        let name = provider_tool_name(&info.name);
+        if let Some(other) = registered
+            .iter()
+            .find(|other| other.name != info.name && provider_tool_name(&other.name) == name)
+        {
+            return Err(AgentError::InvalidConfig(format!(
+                "Tools '{}' and '{}' have the same provider name '{}'",
+                info.name, other.name, name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The registry name of a tool called by a provider, the reverse of `provider_tool_name`.
+///
+/// A registered name is returned as is. Otherwise the registered tool with that
+/// provider name is looked up, and an unknown name is returned unchanged.
+pub fn registry_tool_name(name: &str) -> String {
+    if get_tool(name).is_some() {
+        return name.to_string();
+    }
+    list_tool_infos()
+        .into_iter()
+        .map(|info| info.name)
+        .find(|registered| provider_tool_name(registered) == name)
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// Create a tool message whose content is the value serialized as JSON,
+/// in the same form as the messages returned by `call_tools_concurrently`.
+pub fn tool_message_from_serialize<T: Serialize>(
+    name: impl Into<String>,
+    value: &T,
+) -> Result<Message, AgentError> {
+    let content = serde_json::to_string(value)
+        .map_err(|e| AgentError::InvalidValue(format!("Failed to serialize tool result: {}", e)))?;
+    Ok(Message::tool(name.into(), content))
+}
+
+/// Call the tools concurrently and return their messages.
+///
+/// At most `max_concurrency` calls run at a time (0 = DEFAULT_TOOL_CONCURRENCY).
+/// The returned messages are always in the order of `tool_calls`, regardless of
+/// which call completes first, so they can be appended to the history as is.
+/// Providers require tool results to follow the order of the model's calls.
+/// Each message has the id of its call, so the provider can tell which call it answers.
+///
+/// If a call fails, the calls still running are cancelled and the first error
+/// in call order is returned.
+async fn call_tools_concurrently(
+    ctx: &AgentContext,
+    tool_calls: &Vector<ToolCall>,
+    max_concurrency: usize,
+) -> Result<Vector<Message>, AgentError> {
+    let mut calls = Vec::with_capacity(tool_calls.len());
+    for call in tool_calls.iter() {
+        let name = call.function.name.clone();
+        let Some(tool) = get_tool(&name) else {
+            return Err(AgentError::Other(format!("Tool '{}' not found", name)));
+        };
+        let args = AgentValue::from_json(call.function.parameters.clone()).map_err(|e| {
+            AgentError::InvalidValue(format!("Failed to parse tool call parameters: {}", e))
+        })?;
+        calls.push((name, call.function.id.clone(), tool, args));
+    }
+
+    let max_concurrency = if max_concurrency == 0 {
+        DEFAULT_TOOL_CONCURRENCY as usize
+    } else {
+        max_concurrency
+    };
+    // Create the futures up front, so the stream does not hold a closure and
+    // stays Send inside agents
+    let calls: Vec<_> = calls
+        .into_iter()
+        .map(|(name, id, tool, args)| {
+            let ctx = ctx.clone();
+            async move {
+                let res = tool.call(ctx, args).await?;
+                let mut message = Message::tool(name, res.to_json().to_string());
+                message.id = id;
+                Ok(message)
+            }
+        })
+        .collect();
+    stream::iter(calls)
+        // buffered yields in the original order
+        .buffered(max_concurrency)
+        .try_collect()
+        .await
+}
+
+/// Check that tool parameters have the shape of a JSON Schema for an object.
+///
+/// The parameters must be a JSON object. If given, `type` must be "object",
+/// `properties` an object of schemas, and `required` a list of property names.
+pub fn validate_tool_parameters(parameters: &serde_json::Value) -> Result<(), AgentError> {
+    let invalid = |reason: &str| {
+        AgentError::InvalidConfig(format!("Invalid tool parameters: {}", reason))
+    };
+    let obj = parameters
+        .as_object()
+        .ok_or_else(|| invalid("must be a JSON object"))?;
+    if let Some(ty) = obj.get("type")
+        && ty != "object"
+    {
+        return Err(invalid(&format!("type must be \"object\", not {}", ty)));
+    }
+    let properties = match obj.get("properties") {
+        Some(properties) => Some(
+            properties
+                .as_object()
+                .ok_or_else(|| invalid("properties must be an object"))?,
+        ),
+        None => None,
+    };
+    for (name, schema) in properties.into_iter().flatten() {
+        if !schema.is_object() {
+            return Err(invalid(&format!("schema of '{}' must be an object", name)));
+        }
+    }
+    if let Some(required) = obj.get("required") {
+        let required = required
+            .as_array()
+            .ok_or_else(|| invalid("required must be an array"))?;
+        for name in required {
+            let name = name
+                .as_str()
+                .ok_or_else(|| invalid("required must contain property names"))?;
+            if properties.is_some_and(|p| !p.contains_key(name)) {
+                return Err(invalid(&format!("required property '{}' is not defined", name)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check tool call arguments against the JSON Schema of the tool parameters.
+///
+/// Only `type`, `enum`, `required`, `properties` and `items` are checked, which
+/// is enough to catch a model leaving out or mistyping a field.
+pub fn check_tool_arguments(
+    parameters: &serde_json::Value,
+    args: &serde_json::Value,
+) -> Result<(), AgentError> {
+    check_schema(parameters, args, "arguments")
+}
+
+fn check_schema(
+    schema: &serde_json::Value,
+    value: &serde_json::Value,
+    path: &str,
+) -> Result<(), AgentError> {
+    let mismatch = |reason: String| {
+        AgentError::InvalidValue(format!("Tool arguments do not match: {} {}", path, reason))
+    };
+    let Some(schema) = schema.as_object() else {
+        return Ok(());
+    };
+    if let Some(ty) = schema.get("type") {
+        let types: Vec<&str> = match ty {
+            serde_json::Value::String(ty) => vec![ty.as_str()],
+            serde_json::Value::Array(tys) => tys.iter().filter_map(|t| t.as_str()).collect(),
+            _ => vec![],
+        };
+        let matches = |ty: &str| match ty {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        if !types.is_empty() && !types.iter().any(|ty| matches(ty)) {
+            return Err(mismatch(format!("must be {}", types.join(" or "))));
+        }
+    }
+    if let Some(values) = schema.get("enum").and_then(|e| e.as_array())
+        && !values.contains(value)
+    {
+        return Err(mismatch(format!(
+            "must be one of {}",
+            serde_json::Value::Array(values.clone())
+        )));
+    }
+    if let Some(obj) = value.as_object() {
+        for name in schema
+            .get("required")
+            .and_then(|r| r.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|n| n.as_str())
+        {
+            if !obj.contains_key(name) {
+                return Err(mismatch(format!("is missing '{}'", name)));
+            }
+        }
+        if let Some(properties) = schema.get("properties").and_then(|p| p.as_object()) {
+            for (name, value) in obj {
+                if let Some(property) = properties.get(name) {
+                    check_schema(property, value, &format!("{}.{}", path, name))?;
+                }
+            }
+        }
+    }
+    if let (Some(items), Some(values)) = (schema.get("items"), value.as_array()) {
+        for (i, value) in values.iter().enumerate() {
+            check_schema(items, value, &format!("{}[{}]", path, i))?;
+        }
+    }
+    Ok(())
+}
+
+// Approval Tool Agent
+#[askit_agent(
+    title="Approval Tool",
+    category=CATEGORY,
+    inputs=[PIN_APPROVAL_RESPONSE],
+    outputs=[PIN_APPROVAL_REQUEST],
+    string_config(name=CONFIG_TOOL_NAME),
+    text_config(name=CONFIG_TOOL_DESCRIPTION),
+    object_config(name=CONFIG_TOOL_PARAMETERS),
+    integer_config(name=CONFIG_TIMEOUT_SECS, default=DEFAULT_TIMEOUT_SECS, title="Timeout (secs)"),
+)]
+pub struct ApprovalToolAgent {
+    data: AgentData,
+    name: String,
+    pending: PendingApprovals,
+}
+
+impl ApprovalToolAgent {
+    fn tool_parameters(&self) -> Result<Option<serde_json::Value>, AgentError> {
+        let Some(parameters) = self
+            .configs()?
+            .get(CONFIG_TOOL_PARAMETERS)
+            .ok()
+            .and_then(|v| serde_json::to_value(v).ok())
+        else {
+            return Ok(None);
+        };
+        validate_tool_parameters(&parameters)?;
+        Ok(Some(parameters))
+    }
+}
+
+#[async_trait]
+impl AsAgent for ApprovalToolAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        Ok(Self {
+            data: AgentData::new(askit, id, spec),
+            name: String::new(),
+            pending: PendingApprovals::default(),
+        })
+    }
+
+    // Report invalid parameters while they are edited, not when the tool is called
+    fn configs_changed(&mut self) -> Result<(), AgentError> {
+        self.tool_parameters().map(|_| ())
+    }
+
+    async fn start(&mut self) -> Result<(), AgentError> {
+        let name = self.configs()?.get_string_or_default(CONFIG_TOOL_NAME);
+        if name.is_empty() {
+            return Err(AgentError::InvalidConfig(
+                "Approval tool name is not set".to_string(),
+            ));
+        }
+        let tool = ApprovalTool {
+            info: ToolInfo {
+                name: name.clone(),
+                description: self
+                    .configs()?
+                    .get_string_or_default(CONFIG_TOOL_DESCRIPTION),
+                parameters: self.tool_parameters()?,
+            },
+            askit: self.askit().clone(),
+            agent_id: self.id().to_string(),
+            timeout: Duration::from_secs(
+                self.configs()?
+                    .get_integer_or(CONFIG_TIMEOUT_SECS, DEFAULT_TIMEOUT_SECS)
+                    .max(1) as u64,
+            ),
+            pending: self.pending.clone(),
+        };
+        register_tool(tool);
+        self.name = name;
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), AgentError> {
+        unregister_tool(&self.name);
+        self.pending.clear();
+        Ok(())
+    }
+
+    async fn process(
+        &mut self,
+        _ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        self.pending.resolve(&value)
+    }
+}
+
+/// Keep the tool calls whose tool matches the regex patterns of the tools config.
+///
+/// An empty config keeps every call, as in the upstream Call Tool Message agent.
+fn allowed_tool_calls(
+    config_tools: &str,
+    tool_calls: Vector<ToolCall>,
+) -> Result<Vector<ToolCall>, AgentError> {
+    if config_tools.trim().is_empty() {
+        return Ok(tool_calls);
+    }
+    let allowed: HashSet<String> = configured_tool_infos(config_tools)?
+        .into_iter()
+        .map(|info| info.name)
+        .collect();
+    Ok(tool_calls
+        .into_iter()
+        .filter(|call| allowed.contains(&call.function.name))
+        .collect())
+}
+
+// Call Tool Message (Concurrent) Agent
+/// Call the tools of a message concurrently and output the results in call order.
+///
+/// Unlike the upstream Call Tool Message agent, which calls the tools one by one,
+/// up to `concurrency` calls run at a time, and each result message has the id
+/// of its call.
+#[askit_agent(
+    title="Call Tool Message (Concurrent)",
+    category=CATEGORY,
+    inputs=[PIN_MESSAGE],
+    outputs=[PIN_MESSAGE],
+    text_config(name=CONFIG_TOOLS),
+    integer_config(name=CONFIG_CONCURRENCY, default=DEFAULT_TOOL_CONCURRENCY, title="Concurrency"),
+)]
+pub struct ConcurrentCallToolMessageAgent {
+    data: AgentData,
+}
+
+#[async_trait]
+impl AsAgent for ConcurrentCallToolMessageAgent {
+    fn new(askit: ASKit, id: String, spec: AgentSpec) -> Result<Self, AgentError> {
+        Ok(Self {
+            data: AgentData::new(askit, id, spec),
+        })
+    }
+
+    async fn process(
+        &mut self,
+        ctx: AgentContext,
+        _pin: String,
+        value: AgentValue,
+    ) -> Result<(), AgentError> {
+        let Some(tool_calls) = value.as_message().and_then(|m| m.tool_calls.clone()) else {
+            return Ok(());
+        };
+        let configs = self.configs()?;
+        let tool_calls =
+            allowed_tool_calls(&configs.get_string_or_default(CONFIG_TOOLS), tool_calls)?;
+        let concurrency = configs
+            .get_integer_or(CONFIG_CONCURRENCY, DEFAULT_TOOL_CONCURRENCY)
+            .max(1);
+
+        let messages = call_tools_concurrently(&ctx, &tool_calls, concurrency as usize).await?;
+        for message in messages {
+            self.output(ctx.clone(), PIN_MESSAGE, AgentValue::message(message))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+struct ApprovalTool {
+    info: ToolInfo,
+    askit: ASKit,
+    agent_id: String,
+    timeout: Duration,
+    pending: PendingApprovals,
+}
+
+#[async_trait]
+impl Tool for ApprovalTool {
+    fn info(&self) -> &ToolInfo {
+        &self.info
+    }
+
+    async fn call(&self, ctx: AgentContext, args: AgentValue) -> Result<AgentValue, AgentError> {
+        let (id, rx) = self.pending.start(args.clone());
+        let request = AgentValue::object(hashmap! {
+            "id".into() => AgentValue::string(id.clone()),
+            "name".into() => AgentValue::string(self.info.name.clone()),
+            "args".into() => args,
+        });
+        if let Err(e) = self
+            .askit
+            .send_agent_out(
+                self.agent_id.clone(),
+                ctx,
+                PIN_APPROVAL_REQUEST.to_string(),
+                request,
+            )
+            .await
+        {
+            self.pending.cancel(&id);
+            return Err(e);
+        }
+
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) => Err(AgentError::Other("Approval request dropped".to_string())),
+            Err(_) => {
+                self.pending.cancel(&id);
+                Err(AgentError::Other("Approval request timed out".to_string()))
+            }
+        }
+    }
+}
+
+/// Register the built-in tools enabled by crate features.
+///
+/// Currently this is `fetch_url` with the `fetch` feature.
+pub fn register_builtin_tools() -> Result<(), AgentError> {
+    #[cfg(feature = "fetch")]
+    register_tool(crate::fetch::FetchUrlTool::new()?);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn proposed_args() -> AgentValue {
+        AgentValue::object(hashmap! {
+            "path".into() => AgentValue::string("/tmp/a.txt"),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_approval_approve() {
+        let pending = PendingApprovals::default();
+        let (id, rx) = pending.start(proposed_args());
+
+        // approve with edited arguments
+        let response = AgentValue::object(hashmap! {
+            "id".into() => AgentValue::string(id),
+            "approved".into() => AgentValue::boolean(true),
+            "args".into() => AgentValue::object(hashmap! {
+                "path".into() => AgentValue::string("/tmp/b.txt"),
+            }),
+        });
+        pending.resolve(&response).unwrap();
+
+        let result = rx.await.unwrap();
+        assert_eq!(result.get_bool("approved"), Some(true));
+        assert_eq!(
+            result.get("args").unwrap().get_str("path"),
+            Some("/tmp/b.txt")
+        );
+
+        // approve without id or edits keeps the proposed arguments
+        let (_, rx) = pending.start(proposed_args());
+        pending.resolve(&AgentValue::boolean(true)).unwrap();
+        let result = rx.await.unwrap();
+        assert_eq!(
+            result.get("args").unwrap().get_str("path"),
+            Some("/tmp/a.txt")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_approval_deny() {
+        let pending = PendingApprovals::default();
+        let (id1, rx1) = pending.start(proposed_args());
+        let (id2, rx2) = pending.start(proposed_args());
+        assert_ne!(id1, id2);
+
+        // an id is required when several calls are waiting
+        assert!(pending.resolve(&AgentValue::boolean(false)).is_err());
+
+        let response = AgentValue::object(hashmap! {
+            "id".into() => AgentValue::string(id2),
+            "approved".into() => AgentValue::boolean(false),
+            "reason".into() => AgentValue::string("not allowed"),
+        });
+        pending.resolve(&response).unwrap();
+
+        let result = rx2.await.unwrap();
+        assert_eq!(result.get_bool("approved"), Some(false));
+        assert_eq!(result.get_str("reason"), Some("not allowed"));
+        assert!(result.get("args").is_none());
+
+        // the other call is still waiting
+        pending.cancel(&id1);
+        assert!(rx1.await.is_err());
+    }
+
+    struct SleepTool {
+        info: ToolInfo,
+        delay: Duration,
+        completed: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Tool for SleepTool {
+        fn info(&self) -> &ToolInfo {
+            &self.info
+        }
+
+        async fn call(
+            &self,
+            _ctx: AgentContext,
+            _args: AgentValue,
+        ) -> Result<AgentValue, AgentError> {
+            tokio::time::sleep(self.delay).await;
+            self.completed.lock().unwrap().push(self.info.name.clone());
+            Ok(AgentValue::string(self.info.name.clone()))
+        }
+    }
+
+    /// Register a tool that does nothing, for tests of tool names.
+    pub(crate) fn register_test_tool(name: &str) {
+        register_tool(SleepTool {
+            info: ToolInfo {
+                name: name.to_string(),
+                description: String::new(),
+                parameters: None,
+            },
+            delay: Duration::ZERO,
+            completed: Arc::new(Mutex::new(Vec::new())),
+        });
+    }
+
+    fn tool_call(name: &str) -> ToolCall {
+        ToolCall {
+            function: agent_stream_kit::ToolCallFunction {
+                id: None,
+                name: name.to_string(),
+                parameters: serde_json::json!({}),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_call_tools_concurrently_order() {
+        let completed = Arc::new(Mutex::new(Vec::new()));
+        for (name, delay) in [("test_order_slow", 50), ("test_order_fast", 0)] {
+            register_tool(SleepTool {
+                info: ToolInfo {
+                    name: name.to_string(),
+                    description: String::new(),
+                    parameters: None,
+                },
+                delay: Duration::from_millis(delay),
+                completed: completed.clone(),
+            });
+        }
+
+        let calls = im::vector![tool_call("test_order_slow"), tool_call("test_order_fast")];
+        let messages = call_tools_concurrently(&AgentContext::new(), &calls, 0)
+            .await
+            .unwrap();
+
+        // completed out of order
+        assert_eq!(
+            *completed.lock().unwrap(),
+            vec!["test_order_fast", "test_order_slow"]
+        );
+        // but the history follows the call order
+        let names: Vec<_> = messages
+            .iter()
+            .map(|m| m.tool_name.clone().unwrap())
+            .collect();
+        assert_eq!(names, vec!["test_order_slow", "test_order_fast"]);
+
+        unregister_tool("test_order_slow");
+        unregister_tool("test_order_fast");
+    }
+
+    #[test]
+    fn test_configured_tool_infos() {
+        // a local tool and an MCP tool, which is registered as <server>::<tool>
+        for name in ["test_union_lookup", "test_union_server::lookup"] {
+            register_tool(SleepTool {
+                info: ToolInfo {
+                    name: name.to_string(),
+                    description: String::new(),
+                    parameters: None,
+                },
+                delay: Duration::ZERO,
+                completed: Arc::new(Mutex::new(Vec::new())),
+            });
+        }
+
+        let names = |config: &str| {
+            let mut names: Vec<_> = configured_tool_infos(config)
+                .unwrap()
+                .into_iter()
+                .map(|info| info.name)
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            names("^test_union_"),
+            vec!["test_union_lookup", "test_union_server::lookup"]
+        );
+        assert_eq!(names("^test_union_server::"), vec!["test_union_server::lookup"]);
+        assert!(names("").is_empty());
+        assert!(configured_tool_infos("(").is_err());
+
+        unregister_tool("test_union_lookup");
+        unregister_tool("test_union_server::lookup");
+    }
+
+    #[test]
+    fn test_provider_tool_name() {
+        let valid = regex::Regex::new("^[a-zA-Z0-9_-]{1,64}$").unwrap();
+        for name in ["get_weather", "server::search", "my.server::look up", &"x".repeat(80)] {
+            assert!(valid.is_match(&provider_tool_name(name)), "{}", name);
+        }
+        assert_eq!(provider_tool_name("get_weather"), "get_weather");
+        assert_eq!(provider_tool_name("server::search"), "server__search");
+
+        register_test_tool("test_alias_server::search");
+        register_test_tool("test_alias_plain");
+        assert_eq!(
+            registry_tool_name("test_alias_server__search"),
+            "test_alias_server::search"
+        );
+        assert_eq!(registry_tool_name("test_alias_plain"), "test_alias_plain");
+        assert_eq!(registry_tool_name("test_alias_unknown"), "test_alias_unknown");
+        unregister_tool("test_alias_server::search");
+        unregister_tool("test_alias_plain");
+    }
+
+    #[test]
+    fn test_provider_tool_name_collisions() {
+        register_test_tool("test_collide::search");
+        register_test_tool("test_collide_unique::search");
+        let long = format!("test_collide_{}", "x".repeat(60));
+        register_test_tool(&format!("{}_a", long));
+        register_test_tool(&format!("{}_b", long));
+
+        let unique = configured_tool_infos("^test_collide_unique::").unwrap();
+        assert!(check_provider_tool_names(&unique).is_ok());
+
+        // a registered tool with the same provider name, even if not offered
+        register_test_tool("test_collide__search");
+        let tools = configured_tool_infos("^test_collide::").unwrap();
+        let err = check_provider_tool_names(&tools).unwrap_err();
+        assert!(
+            matches!(err, AgentError::InvalidConfig(msg) if msg.contains("test_collide__search"))
+        );
+
+        // names that differ only after 64 characters
+        let tools = configured_tool_infos(&format!("^{}_a$", long)).unwrap();
+        assert!(check_provider_tool_names(&tools).is_err());
+
+        unregister_tool("test_collide::search");
+        unregister_tool("test_collide_unique::search");
+        unregister_tool("test_collide__search");
+        unregister_tool(&format!("{}_a", long));
+        unregister_tool(&format!("{}_b", long));
+    }
+
+    #[tokio::test]
+    async fn test_call_tools_concurrently_ids() {
+        register_tool(SleepTool {
+            info: ToolInfo {
+                name: "test_ids".to_string(),
+                description: String::new(),
+                parameters: None,
+            },
+            delay: Duration::ZERO,
+            completed: Arc::new(Mutex::new(Vec::new())),
+        });
+
+        // two calls to the same tool can't be told apart by name
+        let mut calls = im::vector![tool_call("test_ids"), tool_call("test_ids")];
+        calls[0].function.id = Some("call_a".to_string());
+        calls[1].function.id = Some("call_b".to_string());
+        let messages = call_tools_concurrently(&AgentContext::new(), &calls, 0)
+            .await
+            .unwrap();
+        let ids: Vec<_> = messages.iter().map(|m| m.id.clone().unwrap()).collect();
+        assert_eq!(ids, vec!["call_a", "call_b"]);
+
+        // the ids match the results to their calls even when reordered
+        let mut assistant = Message::assistant("".to_string());
+        assistant.tool_calls = Some(calls);
+        let history = [assistant, messages[1].clone(), messages[0].clone()];
+        assert_eq!(
+            crate::common::tool_result_ids(&history),
+            vec![None, Some("call_b".to_string()), Some("call_a".to_string())]
+        );
+
+        unregister_tool("test_ids");
+    }
+
+    struct FailTool {
+        info: ToolInfo,
+    }
+
+    #[async_trait]
+    impl Tool for FailTool {
+        fn info(&self) -> &ToolInfo {
+            &self.info
+        }
+
+        async fn call(
+            &self,
+            _ctx: AgentContext,
+            _args: AgentValue,
+        ) -> Result<AgentValue, AgentError> {
+            Err(AgentError::Other("boom".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_call_tools_concurrently_bounded() {
+        let completed = Arc::new(Mutex::new(Vec::new()));
+        for (name, delay) in [("test_bounded_slow", 50), ("test_bounded_fast", 0)] {
+            register_tool(SleepTool {
+                info: ToolInfo {
+                    name: name.to_string(),
+                    description: String::new(),
+                    parameters: None,
+                },
+                delay: Duration::from_millis(delay),
+                completed: completed.clone(),
+            });
+        }
+        register_tool(FailTool {
+            info: ToolInfo {
+                name: "test_bounded_fail".to_string(),
+                description: String::new(),
+                parameters: None,
+            },
+        });
+
+        // one at a time completes in call order
+        let calls = im::vector![
+            tool_call("test_bounded_slow"),
+            tool_call("test_bounded_fast")
+        ];
+        let messages = call_tools_concurrently(&AgentContext::new(), &calls, 1)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            *completed.lock().unwrap(),
+            vec!["test_bounded_slow", "test_bounded_fast"]
+        );
+
+        // a failing call cancels the slower ones still running
+        completed.lock().unwrap().clear();
+        let calls = im::vector![
+            tool_call("test_bounded_fail"),
+            tool_call("test_bounded_slow")
+        ];
+        let err = call_tools_concurrently(&AgentContext::new(), &calls, 2)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("boom"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(completed.lock().unwrap().is_empty());
+
+        unregister_tool("test_bounded_slow");
+        unregister_tool("test_bounded_fast");
+        unregister_tool("test_bounded_fail");
+    }
+
+    #[test]
+    fn test_allowed_tool_calls() {
+        register_test_tool("test_allowed_search");
+        register_test_tool("test_allowed_fetch");
+        let calls = im::vector![
+            tool_call("test_allowed_search"),
+            tool_call("test_allowed_fetch"),
+            tool_call("test_allowed_search"),
+        ];
+
+        // an empty config keeps every call
+        assert_eq!(allowed_tool_calls("", calls.clone()).unwrap().len(), 3);
+
+        let allowed = allowed_tool_calls("^test_allowed_search$", calls.clone()).unwrap();
+        let names: Vec<_> = allowed.iter().map(|c| c.function.name.as_str()).collect();
+        assert_eq!(names, ["test_allowed_search", "test_allowed_search"]);
+        assert!(allowed_tool_calls("(", calls).is_err());
+
+        // the agent is registered with the default concurrency
+        let askit = ASKit::init().unwrap();
+        let spec = askit
+            .new_agent_spec(ConcurrentCallToolMessageAgent::DEF_NAME)
+            .unwrap();
+        assert_eq!(
+            spec.configs.unwrap().get_integer_or_default(CONFIG_CONCURRENCY),
+            DEFAULT_TOOL_CONCURRENCY
+        );
+
+        unregister_tool("test_allowed_search");
+        unregister_tool("test_allowed_fetch");
+    }
+
+    #[test]
+    fn test_validate_tool_parameters() {
+        let valid = serde_json::json!({
+            "type": "object",
+            "properties": { "city": { "type": "string" } },
+            "required": ["city"]
+        });
+        assert!(validate_tool_parameters(&valid).is_ok());
+        assert!(validate_tool_parameters(&serde_json::json!({})).is_ok());
+
+        for invalid in [
+            serde_json::json!("object"),
+            serde_json::json!({ "type": "string" }),
+            serde_json::json!({ "type": "object", "properties": ["city"] }),
+            serde_json::json!({ "type": "object", "properties": { "city": "string" } }),
+            serde_json::json!({ "type": "object", "required": "city" }),
+            serde_json::json!({
+                "type": "object",
+                "properties": { "city": { "type": "string" } },
+                "required": ["country"]
+            }),
+        ] {
+            assert!(
+                validate_tool_parameters(&invalid).is_err(),
+                "{} should be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_tool_arguments() {
+        let parameters = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "unit": {"type": "string", "enum": ["c", "f"]},
+                "days": {"type": "array", "items": {"type": "integer"}}
+            },
+            "required": ["unit"]
+        });
+        let check = |args: serde_json::Value| check_tool_arguments(&parameters, &args);
+        assert!(check(serde_json::json!({"unit": "c", "days": [1, 2]})).is_ok());
+        assert!(check(serde_json::json!({"days": [1]})).is_err());
+        assert!(check(serde_json::json!({"unit": "k"})).is_err());
+        let err = check(serde_json::json!({"unit": "c", "days": [1, 2.5]})).unwrap_err();
+        assert!(matches!(err, AgentError::InvalidValue(msg) if msg.contains("arguments.days[1]")));
+        assert!(check(serde_json::json!("c")).is_err());
+    }
+
+    #[test]
+    fn test_tool_message_from_serialize() {
+        #[derive(Serialize)]
+        struct Weather {
+            city: String,
+            celsius: f64,
+            sunny: bool,
+        }
+
+        let weather = Weather {
+            city: "Tokyo".to_string(),
+            celsius: 21.5,
+            sunny: true,
+        };
+        let message = tool_message_from_serialize("weather", &weather).unwrap();
+        assert_eq!(message.role, "tool");
+        assert_eq!(message.tool_name.as_deref(), Some("weather"));
+        let content: serde_json::Value = serde_json::from_str(&message.content).unwrap();
+        assert_eq!(
+            content,
+            serde_json::json!({ "city": "Tokyo", "celsius": 21.5, "sunny": true })
+        );
+    }
+}